@@ -0,0 +1,568 @@
+//! 块的分配
+//!
+//! 空闲块记录在每个 AG 的 bno 树和 cnt 树中，分配时先按策略选出 AG，再由 AG 内的分配器
+//! 查找空闲 extent。
+use std::mem;
+use std::sync::atomic::Ordering;
+
+use libc::{EINVAL, EIO, ENOSPC};
+
+use crate::{
+    btree::{AllocCntRec, AllocRec, Btree, BtreeAlloc, UndoLog},
+    dstruct::{AbsInoNo, AgfBtBno, AgfBtCnt, NullAgBlock},
+    mstruct::{AgfCtx, AgflCtx},
+    pound_fs::{FsResult, MountPoint},
+};
+
+// 每个 AG 留出的块数，足够填满 AGFL 并为 bmbt 分裂提供块
+pub const ALLOC_SET_ASIDE_PER_AG: u64 = 8;
+
+/// 一次块分配的参数 xfs_bmalloca
+pub struct AllocArgs {
+    pub ino: AbsInoNo,     // 为哪个 inode 分配，优先使用它所在的 AG
+    pub hint: Option<u64>, // 希望靠近的文件系统块号，为空时靠近 inode 所在的块
+    pub minlen: u64,
+    pub maxlen: u64,
+}
+
+impl<'a> MountPoint<'a> {
+    /// 每个 AG 开头被 AG 头部（SB、AGF、AGI、AGFL 各占一个扇区）占用的块数
+    pub fn ag_header_blocks(&self) -> u64 {
+        let sb = &self.superblock;
+        (4 * sb.sectsize as u64).div_ceil(sb.blocksize as u64)
+    }
+
+    /// bno 树根节点的 AG 内块号，紧跟在 AG 头部之后
+    pub fn bno_root_block(&self) -> u32 {
+        self.ag_header_blocks() as u32
+    }
+
+    /// cnt 树根节点的 AG 内块号
+    pub fn cnt_root_block(&self) -> u32 {
+        self.bno_root_block() + 1
+    }
+
+    /// inobt 根节点的 AG 内块号
+    pub fn ino_root_block(&self) -> u32 {
+        self.cnt_root_block() + 1
+    }
+
+    /// finobt 根节点的 AG 内块号
+    pub fn fino_root_block(&self) -> u32 {
+        self.ino_root_block() + 1
+    }
+
+    /// 每个 AG 开头被头部和 B+树根节点占用的块数 XFS_PREALLOC_BLOCKS
+    pub fn ag_prealloc_blocks(&self) -> u64 {
+        self.fino_root_block() as u64 + 1
+    }
+
+    /// 预留空间时不能动用的块数 xfs_alloc_set_aside
+    ///
+    /// fdblocks 中包含 AGFL 中的块，分配时 bmbt 也需要块，每个 AG 都留出一部分
+    pub fn alloc_set_aside(&self) -> u64 {
+        self.perag.len() as u64 * ALLOC_SET_ASIDE_PER_AG
+    }
+
+    /// 修改 fdblocks，用于预留和归还还没有分配的块 xfs_mod_fdblocks
+    ///
+    /// 预留后剩余的块数少于 alloc_set_aside 时返回 ENOSPC，rsvd 为真时可以用到 0
+    pub fn mod_fdblocks(&self, delta: i64, rsvd: bool) -> FsResult<()> {
+        let floor = if rsvd { 0 } else { self.alloc_set_aside() };
+        self.fdblocks
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |free| {
+                let new = free.checked_add_signed(delta)?;
+                (delta >= 0 || new >= floor).then_some(new)
+            })
+            .map(|_| ())
+            .map_err(|_| ENOSPC)
+    }
+
+    /// 从 fdblocks 中预留 len 个块执行 f，f 分配的块从 resv 中扣除，没有用完的预留最后归还
+    ///
+    /// 空间不足时在分配之前就返回 ENOSPC，不会用掉延迟分配已经预留的块 xfs_trans_reserve
+    pub fn with_reserved<T>(
+        &self,
+        len: u64,
+        f: impl FnOnce(&mut u64) -> FsResult<T>,
+    ) -> FsResult<T> {
+        self.mod_fdblocks(-(len as i64), false)?;
+        let mut resv = len;
+        let ret = f(&mut resv);
+        self.mod_fdblocks(resv as i64, true)?;
+        ret
+    }
+
+    /// 新目录的 inode 所在的 AG，依次轮换，使不同目录下的文件分散到各个 AG
+    pub fn rotor_ag(&self) -> u32 {
+        self.agrotor.fetch_add(1, Ordering::Relaxed) % self.superblock.agcount.max(1)
+    }
+
+    /// 按分配策略分配 [minlen, maxlen] 个连续块。返回 (起始块号, 块数)
+    ///
+    /// 依次尝试 hint 附近和 inode 所在的 AG，都没有空间时按最长空闲 extent 从大到小尝试其余 AG。
+    /// 分到的块从调用者的预留 resv 中扣除
+    pub fn alloc_blocks(&self, args: &AllocArgs, resv: &mut u64) -> FsResult<(u64, u64)> {
+        if args.minlen == 0 || args.minlen > args.maxlen {
+            return Err(EINVAL);
+        }
+        let agblocks = self.superblock.agblocks as u64;
+        if args.minlen > agblocks {
+            return Err(ENOSPC);
+        }
+        let (minlen, maxlen) = (args.minlen as u32, args.maxlen.min(agblocks) as u32);
+        let ino_fsbno = args.ino >> self.superblock.inpblock_bits;
+        let mut tried = vec![false; self.perag.len()];
+        for fsbno in [args.hint.unwrap_or(ino_fsbno), ino_fsbno] {
+            let (agno, agbno) = self.fsbno_to_agbno(fsbno);
+            match tried.get_mut(agno as usize) {
+                Some(tried) if !*tried => *tried = true,
+                _ => continue,
+            }
+            if let Some(got) = self.alloc_in_ag(agno, minlen, maxlen, Some(agbno), resv)? {
+                return Ok(got);
+            }
+        }
+        // longest 只是参考，分配时会在 AG 内重新检查
+        let mut rest: Vec<(u32, u32)> = self
+            .perag
+            .iter()
+            .filter(|pag| !tried[pag.agno as usize])
+            .map(|pag| (pag.agf.lock().unwrap().longest, pag.agno))
+            .collect();
+        rest.sort_by(|a, b| b.cmp(a));
+        for (longest, agno) in rest {
+            if longest < minlen {
+                break;
+            }
+            if let Some(got) = self.alloc_in_ag(agno, minlen, maxlen, None, resv)? {
+                return Ok(got);
+            }
+        }
+        Err(ENOSPC)
+    }
+
+    /// 在第 agno 个 AG 中分配，空间不足时返回 None
+    fn alloc_in_ag(
+        &self,
+        agno: u32,
+        minlen: u32,
+        maxlen: u32,
+        near: Option<u32>,
+        resv: &mut u64,
+    ) -> FsResult<Option<(u64, u64)>> {
+        match self.with_agf_resv(agno, resv, |ctx| ctx.alloc_extent(minlen, maxlen, near)) {
+            Ok((agbno, len)) => Ok(Some((self.agbno_to_fsbno(agno, agbno), len as u64))),
+            Err(ENOSPC) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 释放 [start, start + len)，这些块必须位于同一个 AG 中
+    pub fn free_blocks(&self, start: u64, len: u64) -> FsResult<()> {
+        let (agno, agbno) = self.fsbno_to_agbno(start);
+        let len = u32::try_from(len).map_err(|_| EINVAL)?;
+        self.with_agf(agno, |ctx| ctx.free_extent(agbno, len))?;
+        self.discard_blocks(start, len as u64);
+        Ok(())
+    }
+}
+
+// 按块号查找附近的空闲 extent 时，向两边各查看的记录数
+const NEAR_SCAN_RECS: usize = 16;
+
+/// 从 AGFL 中为 bno/cnt 树的分裂提供块，避免分配空闲空间时递归地修改正在修改的树
+struct FreelistAlloc<'f> {
+    fl: AgflCtx<'f>,
+}
+
+impl<'f> BtreeAlloc for FreelistAlloc<'f> {
+    fn alloc_block(&mut self) -> FsResult<u64> {
+        let agbno = self.fl.get().ok_or(ENOSPC)?;
+        self.fl.agf.btreeblks += 1;
+        Ok(self.fl.mp.agbno_to_fsbno(self.fl.agf.seqno, agbno))
+    }
+
+    fn free_block(&mut self, fsbno: u64) -> FsResult<()> {
+        self.fl.put(self.fl.mp.fsbno_to_agbno(fsbno).1)?;
+        self.fl.agf.btreeblks -= 1;
+        Ok(())
+    }
+}
+
+/// AGFL 是一个环形数组，从 flfirst 处取出，放入到 fllast 之后
+impl<'a> AgflCtx<'a> {
+    pub fn size(&self) -> u32 {
+        self.agfl.bno.len() as u32
+    }
+
+    /// 取出一个预留块 xfs_alloc_get_freelist
+    pub fn get(&mut self) -> Option<u32> {
+        if self.agf.flcount == 0 {
+            return None;
+        }
+        let agbno = mem::replace(&mut self.agfl.bno[self.agf.flfirst as usize], NullAgBlock);
+        self.agf.flfirst = (self.agf.flfirst + 1) % self.size();
+        self.agf.flcount -= 1;
+        Some(agbno)
+    }
+
+    /// 放入一个预留块 xfs_alloc_put_freelist
+    pub fn put(&mut self, agbno: u32) -> FsResult<()> {
+        if self.agf.flcount >= self.size() {
+            return Err(ENOSPC);
+        }
+        self.agf.fllast = (self.agf.fllast + 1) % self.size();
+        self.agfl.bno[self.agf.fllast as usize] = agbno;
+        self.agf.flcount += 1;
+        Ok(())
+    }
+}
+
+impl<'a> MountPoint<'a> {
+    /// 锁住第 agno 个 AG 的 AGF 和 AGFL 执行 f，f 不分配块时使用
+    pub fn with_agf<T>(
+        &self,
+        agno: u32,
+        f: impl FnOnce(&mut AgfCtx) -> FsResult<T>,
+    ) -> FsResult<T> {
+        self.with_agf_resv(agno, &mut 0, f)
+    }
+
+    /// 锁住第 agno 个 AG 的 AGF 和 AGFL 执行 f，成功后把空闲块数的变化同步到 fdblocks 并写回两者
+    ///
+    /// 分配出去的 extent 从 resv 中扣除，bno/cnt 树分裂用掉的 AGFL 块等其他变化直接计入 fdblocks，
+    /// 可以用到 alloc_set_aside 留出的块，fdblocks 不够时返回 ENOSPC。
+    /// f 或扣除出错时恢复执行前的 AGF、AGFL 和 bno/cnt 树，不写回磁盘。与 XFS 一样，fdblocks 中包含 AGFL 中的块
+    pub fn with_agf_resv<T>(
+        &self,
+        agno: u32,
+        resv: &mut u64,
+        f: impl FnOnce(&mut AgfCtx) -> FsResult<T>,
+    ) -> FsResult<T> {
+        let pag = self.perag.get(agno as usize).ok_or(EINVAL)?;
+        let mut agf = pag.agf.lock().unwrap();
+        let mut agfl = pag.agfl.lock().unwrap();
+        let (saved_agf, saved_agfl) = (agf.clone(), agfl.clone());
+        let undo = UndoLog::default();
+        let mut ctx = AgfCtx::new(self, &mut agf, &mut agfl, &undo);
+        let ret = f(&mut ctx).and_then(|ret| {
+            let from_resv = ctx.allocated.min(*resv);
+            let old = (saved_agf.freeblks + saved_agf.flcount) as i64;
+            let new = (ctx.agf.freeblks + ctx.agf.flcount) as i64;
+            self.mod_fdblocks(new - old + from_resv as i64, true)?;
+            *resv -= from_resv;
+            Ok(ret)
+        });
+        let ret = match ret {
+            Ok(ret) => ret,
+            Err(e) => {
+                *agf = saved_agf;
+                *agfl = saved_agfl;
+                // 返回 f 的错误而不是回滚的错误，alloc_in_ag 靠 ENOSPC 换到下一个 AG
+                let _ = undo.rollback(self);
+                return Err(e);
+            }
+        };
+        self.write_agf(&agf)?;
+        self.write_agfl(&agfl)?;
+        Ok(ret)
+    }
+}
+
+/// AG 内的空闲空间，同时记录在 bno 树和 cnt 树中。块号都是 AG 内块号
+impl<'a> AgfCtx<'a> {
+    fn freelist(&mut self) -> AgflCtx<'_> {
+        AgflCtx::new(self.mp, self.agf, self.agfl)
+    }
+
+    fn bno_tree(&self) -> Btree<'a, AllocRec> {
+        Btree::from_agf(self.mp, self.agf, AgfBtBno).with_undo(self.undo)
+    }
+
+    fn cnt_tree(&self) -> Btree<'a, AllocCntRec> {
+        Btree::from_agf(self.mp, self.agf, AgfBtCnt).with_undo(self.undo)
+    }
+
+    /// 对 bno 树和 cnt 树执行 f，之后把新的根节点和层数写回 AGF
+    fn modify_trees(
+        &mut self,
+        f: impl FnOnce(
+            &mut Btree<'a, AllocRec>,
+            &mut Btree<'a, AllocCntRec>,
+            &mut FreelistAlloc,
+        ) -> FsResult<()>,
+    ) -> FsResult<()> {
+        let mut bno = self.bno_tree();
+        let mut cnt = self.cnt_tree();
+        let ret = f(
+            &mut bno,
+            &mut cnt,
+            &mut FreelistAlloc {
+                fl: self.freelist(),
+            },
+        );
+        for (btnum, root, levels) in [
+            (AgfBtBno, bno.root, bno.levels),
+            (AgfBtCnt, cnt.root, cnt.levels),
+        ] {
+            self.agf.roots[btnum] = self.mp.fsbno_to_agbno(root).1;
+            self.agf.levels[btnum] = levels;
+        }
+        ret
+    }
+
+    /// 从两棵树中删除空闲 extent
+    fn remove_free(&mut self, start: u32, len: u32) -> FsResult<()> {
+        self.modify_trees(|bno, cnt, alloc| {
+            bno.delete(&start, alloc)?;
+            cnt.delete(&(len, start), alloc)
+        })?;
+        self.agf.freeblks -= len;
+        Ok(())
+    }
+
+    /// 向两棵树中插入空闲 extent，调用者保证它不与已有的 extent 相邻或重叠
+    fn insert_free(&mut self, start: u32, len: u32) -> FsResult<()> {
+        let rec = AllocRec {
+            startblock: start,
+            blockcount: len,
+        };
+        self.modify_trees(|bno, cnt, alloc| {
+            bno.insert(&rec, alloc)?;
+            cnt.insert(&AllocCntRec(rec), alloc)
+        })?;
+        self.agf.freeblks += len;
+        Ok(())
+    }
+
+    fn update_longest(&mut self) -> FsResult<()> {
+        self.agf.longest = self
+            .cnt_tree()
+            .cursor()
+            .last()?
+            .map_or(0, |rec| rec.0.blockcount);
+        Ok(())
+    }
+
+    /// 一次分配或释放最多向每棵树插入两条记录，最坏情况下每次插入都会让每一层分裂并长高一层
+    fn freelist_need(&self) -> u32 {
+        2 * (self.agf.levels[AgfBtBno] + 1) + 2 * (self.agf.levels[AgfBtCnt] + 1)
+    }
+
+    /// 保证 AGFL 中的块足够一次操作使用，不够时从最长的空闲 extent 末尾取 xfs_alloc_fix_freelist
+    fn fix_freelist(&mut self) -> FsResult<()> {
+        let need = self.freelist_need();
+        let count = self.agf.flcount;
+        if count >= need {
+            return Ok(());
+        }
+        let want = (2 * need).min(self.freelist().size()) - count;
+        let longest = self.cnt_tree().cursor().last()?.ok_or(ENOSPC)?.0;
+        let take = want.min(longest.blockcount);
+        let end = longest.startblock + longest.blockcount;
+        // 先放入 AGFL，删除和插入记录时的分裂就可以使用它们
+        for agbno in end - take..end {
+            self.freelist().put(agbno)?;
+        }
+        self.remove_free(longest.startblock, longest.blockcount)?;
+        if take < longest.blockcount {
+            self.insert_free(longest.startblock, longest.blockcount - take)?;
+        }
+        if self.agf.flcount < need {
+            return Err(ENOSPC);
+        }
+        Ok(())
+    }
+
+    /// AGFL 中的块过多时，归还到空闲空间
+    fn drain_freelist(&mut self) -> FsResult<()> {
+        let keep = 2 * self.freelist_need();
+        if self.agf.flcount <= 2 * keep {
+            return Ok(());
+        }
+        while self.agf.flcount > keep {
+            let agbno = self.freelist().get().unwrap();
+            self.free_range(agbno, 1)?;
+        }
+        Ok(())
+    }
+
+    /// 分配 [min_len, max_len] 个连续块，返回 (AG 内起始块号, 块数)
+    ///
+    /// 给出 near_hint 时优先分配包含或靠近它的块，否则选择能满足 max_len 的最小 extent
+    pub fn alloc_extent(
+        &mut self,
+        min_len: u32,
+        max_len: u32,
+        near_hint: Option<u32>,
+    ) -> FsResult<(u32, u32)> {
+        if min_len == 0 || min_len > max_len {
+            return Err(EINVAL);
+        }
+        if self.agf.longest < min_len {
+            return Err(ENOSPC);
+        }
+        self.fix_freelist()?;
+        let found = match near_hint {
+            Some(hint) => self.find_near(min_len, max_len, hint)?,
+            None => None,
+        };
+        let (fstart, flen, start, len) = match found {
+            Some(found) => found,
+            None => self.find_by_size(min_len, max_len)?,
+        };
+        self.take_free(fstart, flen, start, len)?;
+        self.drain_freelist()?;
+        self.update_longest()?;
+        Ok((start, len))
+    }
+
+    /// 分配 len 个连续块，起始块号是 align 的整数倍，返回 AG 内起始块号
+    ///
+    /// 从能放下 len 的最小 extent 开始依次查找，对齐后仍能放下的才使用
+    pub fn alloc_aligned(&mut self, len: u32, align: u32) -> FsResult<u32> {
+        if len == 0 || align == 0 {
+            return Err(EINVAL);
+        }
+        if self.agf.longest < len {
+            return Err(ENOSPC);
+        }
+        self.fix_freelist()?;
+        let cnt = self.cnt_tree();
+        let mut cur = cnt.cursor();
+        let mut next = cur.seek_ge(&(len, 0))?;
+        let (fstart, flen, start) = loop {
+            let Some(rec) = next else {
+                return Err(ENOSPC);
+            };
+            let rec = rec.0;
+            let start = rec.startblock.next_multiple_of(align);
+            if start as u64 + len as u64 <= rec.startblock as u64 + rec.blockcount as u64 {
+                break (rec.startblock, rec.blockcount, start);
+            }
+            next = cur.advance()?;
+        };
+        self.take_free(fstart, flen, start, len)?;
+        self.drain_freelist()?;
+        self.update_longest()?;
+        Ok(start)
+    }
+
+    /// 从空闲 extent [fstart, fstart + flen) 中取出 [start, start + len)，剩余部分放回
+    fn take_free(&mut self, fstart: u32, flen: u32, start: u32, len: u32) -> FsResult<()> {
+        self.allocated += len as u64;
+        self.remove_free(fstart, flen)?;
+        if start > fstart {
+            self.insert_free(fstart, start - fstart)?;
+        }
+        if start + len < fstart + flen {
+            self.insert_free(start + len, fstart + flen - start - len)?;
+        }
+        Ok(())
+    }
+
+    /// 在 hint 附近查找，返回 (空闲 extent 起点, 长度, 分配起点, 分配长度)
+    fn find_near(
+        &self,
+        min_len: u32,
+        max_len: u32,
+        hint: u32,
+    ) -> FsResult<Option<(u32, u32, u32, u32)>> {
+        let bno = self.bno_tree();
+        let mut cur = bno.cursor();
+        // 包含 hint 的空闲 extent，从 hint 开始分配
+        let mut left = cur.seek_le(&hint)?;
+        if let Some(rec) = left {
+            let end = rec.startblock + rec.blockcount;
+            if end > hint && end - hint >= min_len {
+                return Ok(Some((
+                    rec.startblock,
+                    rec.blockcount,
+                    hint,
+                    max_len.min(end - hint),
+                )));
+            }
+        }
+        // hint 左侧的 extent 从末尾分配，右侧的从开头分配，取离 hint 最近的
+        let mut best: Option<(u32, (u32, u32, u32, u32))> = None;
+        for _ in 0..NEAR_SCAN_RECS {
+            let Some(rec) = left else { break };
+            if rec.blockcount >= min_len {
+                let len = max_len.min(rec.blockcount);
+                let end = rec.startblock + rec.blockcount;
+                best = Some((
+                    hint.saturating_sub(end),
+                    (rec.startblock, rec.blockcount, end - len, len),
+                ));
+                break;
+            }
+            left = cur.prev()?;
+        }
+        let mut right = cur.seek_ge(&hint.saturating_add(1))?;
+        for _ in 0..NEAR_SCAN_RECS {
+            let Some(rec) = right else { break };
+            let dist = rec.startblock - hint;
+            if best.is_some_and(|(d, _)| d <= dist) {
+                break;
+            }
+            if rec.blockcount >= min_len {
+                let len = max_len.min(rec.blockcount);
+                best = Some((dist, (rec.startblock, rec.blockcount, rec.startblock, len)));
+                break;
+            }
+            right = cur.advance()?;
+        }
+        Ok(best.map(|(_, found)| found))
+    }
+
+    /// 从 cnt 树中找能放下 max_len 的最小 extent，没有时用最长的 extent
+    fn find_by_size(&self, min_len: u32, max_len: u32) -> FsResult<(u32, u32, u32, u32)> {
+        let cnt = self.cnt_tree();
+        let mut cur = cnt.cursor();
+        let rec = match cur.seek_ge(&(max_len, 0))? {
+            Some(rec) => rec.0,
+            None => cur.last()?.ok_or(ENOSPC)?.0,
+        };
+        if rec.blockcount < min_len {
+            return Err(ENOSPC);
+        }
+        let len = max_len.min(rec.blockcount);
+        Ok((rec.startblock, rec.blockcount, rec.startblock, len))
+    }
+
+    /// 释放 [start, start + len)，与相邻的空闲 extent 合并
+    pub fn free_extent(&mut self, start: u32, len: u32) -> FsResult<()> {
+        if len == 0 || start as u64 + len as u64 > self.agf.length as u64 {
+            return Err(EINVAL);
+        }
+        self.fix_freelist()?;
+        self.free_range(start, len)?;
+        self.drain_freelist()?;
+        self.update_longest()
+    }
+
+    fn free_range(&mut self, start: u32, len: u32) -> FsResult<()> {
+        let bno = self.bno_tree();
+        let mut cur = bno.cursor();
+        let left = cur.seek_le(&start)?;
+        let right = cur.seek_ge(&start)?;
+        let (mut start, mut len) = (start, len);
+        // 与已有的空闲 extent 重叠说明重复释放
+        if left.is_some_and(|rec| rec.startblock + rec.blockcount > start)
+            || right.is_some_and(|rec| rec.startblock < start + len)
+        {
+            return Err(EIO);
+        }
+        if let Some(rec) = left.filter(|rec| rec.startblock + rec.blockcount == start) {
+            self.remove_free(rec.startblock, rec.blockcount)?;
+            start = rec.startblock;
+            len += rec.blockcount;
+        }
+        if let Some(rec) = right.filter(|rec| rec.startblock == start + len) {
+            self.remove_free(rec.startblock, rec.blockcount)?;
+            len += rec.blockcount;
+        }
+        self.insert_free(start, len)
+    }
+}
@@ -0,0 +1,266 @@
+#[cfg(test)]
+use std::sync::atomic::Ordering;
+
+#[cfg(test)]
+use libc::{EINVAL, EIO, ENOSPC, S_IFDIR, S_IFREG};
+
+#[cfg(test)]
+use crate::{
+    alloc::AllocArgs,
+    btree::{AllocCntRec, AllocRec, Btree},
+    btree_test::{mkfs_and_mount, remount, test_mkfs_option},
+    dstruct::{Agf, AgfBtBno, AgfBtCnt, Agfl},
+    mstruct::AgflCtx,
+    pound_fs::{MkfsOption, MountPoint},
+};
+
+/// 两棵树记录相同的 extent，互不相邻，且与 AGF 中的计数一致
+#[cfg(test)]
+fn check_ag(mp: &MountPoint, agf: &Agf) {
+    let bno: Vec<AllocRec> = Btree::<AllocRec>::from_agf(mp, agf, AgfBtBno)
+        .range(..)
+        .map(|r| r.unwrap())
+        .collect();
+    for w in bno.windows(2) {
+        assert!(w[0].startblock + w[0].blockcount < w[1].startblock);
+    }
+    let mut cnt: Vec<AllocRec> = Btree::<AllocCntRec>::from_agf(mp, agf, AgfBtCnt)
+        .range(..)
+        .map(|r| r.unwrap().0)
+        .collect();
+    assert_eq!(cnt.last().map_or(0, |r| r.blockcount), agf.longest);
+    cnt.sort_by_key(|r| r.startblock);
+    assert_eq!(bno, cnt);
+    assert_eq!(bno.iter().map(|r| r.blockcount).sum::<u32>(), agf.freeblks);
+}
+
+#[test]
+fn test_alloc_extent() {
+    let path = "test_alloc_extent.bin";
+    let mp = mkfs_and_mount(path, test_mkfs_option());
+    let prealloc = mp.ag_prealloc_blocks() as u32;
+    let freeblks = mp.perag[1].agf.lock().unwrap().freeblks;
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+
+    mp.with_agf(1, |ctx| {
+        assert_eq!(ctx.alloc_extent(2, 1, None).err(), Some(EINVAL));
+        assert_eq!(ctx.alloc_extent(5000, 5000, None).err(), Some(ENOSPC));
+        // 从 hint 处开始分配
+        assert_eq!(
+            ctx.alloc_extent(1, 8, Some(prealloc + 100))?,
+            (prealloc + 100, 8)
+        );
+
+        // 逐块分配后隔一块释放一块，bno 树需要分裂
+        let blocks: Vec<u32> = (0..2000)
+            .map(|i| ctx.alloc_extent(1, 1, Some(prealloc + 200 + i)).unwrap().0)
+            .collect();
+        assert_eq!(blocks[1999], prealloc + 2199);
+        for &b in blocks.iter().step_by(2) {
+            ctx.free_extent(b, 1)?;
+        }
+        assert_eq!(ctx.agf.levels[AgfBtBno], 2);
+        assert_eq!(ctx.agf.levels[AgfBtCnt], 2);
+        check_ag(ctx.mp, ctx.agf);
+        assert_eq!(ctx.free_extent(blocks[0], 1).err(), Some(EIO));
+
+        // hint 已被占用时，选择离它最近的空闲块
+        assert_eq!(ctx.alloc_extent(1, 1, Some(blocks[1]))?, (blocks[0], 1));
+        ctx.free_extent(blocks[0], 1)?;
+        // 没有 hint 时选择能放下的最小 extent
+        let (start, len) = ctx.alloc_extent(1, 1, None)?;
+        assert_eq!(len, 1);
+        ctx.free_extent(start, 1)?;
+
+        for &b in blocks.iter().skip(1).step_by(2) {
+            ctx.free_extent(b, 1)?;
+        }
+        ctx.free_extent(prealloc + 100, 8)?;
+        check_ag(ctx.mp, ctx.agf);
+        assert_eq!(ctx.agf.levels[AgfBtBno], 1);
+        // 除了预留块和树占用的块，其余空间全部回收
+        assert_eq!(
+            ctx.agf.freeblks + ctx.agf.flcount + ctx.agf.btreeblks,
+            freeblks
+        );
+        Ok(())
+    })
+    .unwrap();
+
+    let agf = mp.perag[1].agf.lock().unwrap().clone();
+    // 树占用的块不再计入空闲块
+    assert_eq!(
+        mp.fdblocks.load(Ordering::Relaxed),
+        fdblocks - agf.btreeblks as u64
+    );
+    // 出错时 AGF 恢复原样，空闲块数不变
+    let ret = mp.with_agf(1, |ctx| {
+        ctx.alloc_extent(1, 8, Some(prealloc + 100))?;
+        ctx.free_extent(prealloc + 100, 4)?;
+        Err::<(), _>(EIO)
+    });
+    assert_eq!(ret.err(), Some(EIO));
+    let restored = mp.perag[1].agf.lock().unwrap().clone();
+    assert_eq!(restored.freeblks, agf.freeblks);
+    assert_eq!(restored.longest, agf.longest);
+    assert_eq!(restored.flcount, agf.flcount);
+    assert_eq!(
+        mp.fdblocks.load(Ordering::Relaxed),
+        fdblocks - agf.btreeblks as u64
+    );
+    // AGF 已经写回磁盘
+    drop(mp);
+    let mp = remount(path);
+    let on_disk = mp.perag[1].agf.lock().unwrap().clone();
+    assert_eq!(on_disk.freeblks, agf.freeblks);
+    assert_eq!(on_disk.roots, agf.roots);
+    check_ag(&mp, &on_disk);
+}
+
+#[test]
+fn test_alloc_policy() {
+    let path = "test_alloc_policy.bin";
+    let mp = mkfs_and_mount(
+        path,
+        MkfsOption {
+            agblocks: 2048,
+            ..test_mkfs_option()
+        },
+    );
+    let agcount = mp.superblock.agcount;
+    assert_eq!(agcount, 7);
+    let rootino = mp.superblock.rootino;
+    let ag_of = |fsbno: u64| mp.fsbno_to_agbno(fsbno).0;
+    let ino_ag = |ino: u64| ag_of(ino >> mp.superblock.inpblock_bits);
+
+    // 新目录轮流放到各个 AG 中，普通文件跟随父目录
+    let dirs: Vec<u64> = (0..agcount)
+        .map(|i| {
+            let name = format!("d{}", i);
+            mp.create(rootino, name.as_bytes(), S_IFDIR as u16 | 0o755, 0, 0)
+                .unwrap()
+                .ino
+        })
+        .collect();
+    assert_eq!(
+        dirs.iter().map(|&ino| ino_ag(ino)).collect::<Vec<_>>(),
+        (0..agcount).collect::<Vec<_>>()
+    );
+    let file = mp
+        .create(dirs[3], b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    assert_eq!(ino_ag(file.ino), 3);
+
+    let args = |hint: Option<u64>, minlen: u64, maxlen: u64| AllocArgs {
+        ino: file.ino,
+        hint,
+        minlen,
+        maxlen,
+    };
+    assert_eq!(
+        mp.alloc_blocks(&args(None, 0, 1), &mut 0).err(),
+        Some(EINVAL)
+    );
+    assert_eq!(
+        mp.alloc_blocks(&args(None, 4096, 4096), &mut 0).err(),
+        Some(ENOSPC)
+    );
+    // 没有 hint 时在 inode 所在的 AG 中分配，有 hint 时紧接着 hint 分配
+    let (start, len) = mp.alloc_blocks(&args(None, 1, 16), &mut 0).unwrap();
+    assert_eq!((ag_of(start), len), (3, 16));
+    let (next, _) = mp
+        .alloc_blocks(&args(Some(start + 16), 1, 1), &mut 0)
+        .unwrap();
+    assert_eq!(next, start + 16);
+    mp.free_blocks(start, 17).unwrap();
+
+    // AG 3 用完之后，选择最长空闲 extent 最大的 AG
+    let mut spilled = None;
+    for _ in 0..16 {
+        let longest: Vec<u32> = mp
+            .perag
+            .iter()
+            .map(|pag| pag.agf.lock().unwrap().longest)
+            .collect();
+        let (start, _) = mp.alloc_blocks(&args(None, 1, 2048), &mut 0).unwrap();
+        let agno = ag_of(start);
+        if agno != 3 {
+            let best = (0..agcount)
+                .filter(|&i| i != 3)
+                .map(|i| longest[i as usize]);
+            assert_eq!(longest[agno as usize], best.max().unwrap());
+            spilled = Some(agno);
+            break;
+        }
+    }
+    assert!(spilled.is_some());
+    let agf = mp.perag[3].agf.lock().unwrap().clone();
+    assert_eq!(agf.longest, 0);
+    check_ag(&mp, &agf);
+}
+
+#[test]
+fn test_agfl() {
+    let path = "test_agfl.bin";
+    let mp = mkfs_and_mount(path, test_mkfs_option());
+    let length = mp.ag_block_count(1);
+    let agfl = mp.perag[1].agfl.lock().unwrap().clone();
+    assert_eq!(agfl.encode().len(), mp.superblock.sectsize as usize);
+    assert_eq!(Agfl::decode(&agfl.encode()).unwrap().bno, agfl.bno);
+    let ring = |agf: &Agf, agfl: &Agfl| -> Vec<u32> {
+        let size = agfl.bno.len() as u32;
+        (0..agf.flcount)
+            .map(|i| agfl.bno[((agf.flfirst + i) % size) as usize])
+            .collect()
+    };
+
+    let blocks = mp
+        .with_agf(1, |ctx| {
+            assert_eq!(ctx.agf.flcount, 0);
+            // 第一次分配前从最长 extent 的末尾补充 AGFL
+            ctx.alloc_extent(1, 1, None)?;
+            assert_eq!(ctx.agf.flcount, 16);
+            let blocks = ring(ctx.agf, ctx.agfl);
+            let mut sorted = blocks.clone();
+            sorted.sort();
+            assert_eq!(sorted, (length - 16..length).collect::<Vec<_>>());
+
+            // 反复取出再放回，绕过数组末尾
+            let mut fl = AgflCtx::new(ctx.mp, ctx.agf, ctx.agfl);
+            let size = fl.size();
+            let (first, last) = (fl.agf.flfirst, fl.agf.fllast);
+            for _ in 0..size {
+                let agbno = fl.get().unwrap();
+                fl.put(agbno)?;
+            }
+            assert_eq!((fl.agf.flfirst, fl.agf.fllast), (first, last));
+            // 每次取出再放回，队首的块移到队尾
+            let mut rotated = blocks.clone();
+            rotated.rotate_left((size % 16) as usize);
+            assert_eq!(ring(fl.agf, fl.agfl), rotated);
+            while fl.agf.flcount < size {
+                fl.put(0)?;
+            }
+            assert_eq!(fl.put(0).err(), Some(ENOSPC));
+            let kept: Vec<u32> = (0..16).map(|_| fl.get().unwrap()).collect();
+            while fl.get().is_some() {}
+            for &agbno in kept.iter() {
+                fl.put(agbno)?;
+            }
+            Ok(kept)
+        })
+        .unwrap();
+
+    // AGFL 写回了磁盘，重新挂载后继续使用其中的块
+    drop(mp);
+    let mp = remount(path);
+    let freeblks = {
+        let agf = mp.perag[1].agf.lock().unwrap();
+        assert_eq!(ring(&agf, &mp.perag[1].agfl.lock().unwrap()), blocks);
+        agf.freeblks
+    };
+    mp.with_agf(1, |ctx| ctx.alloc_extent(1, 1, None)).unwrap();
+    let agf = mp.perag[1].agf.lock().unwrap();
+    assert_eq!(agf.flcount, 16);
+    assert_eq!(agf.freeblks, freeblks - 1);
+}
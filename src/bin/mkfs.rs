@@ -0,0 +1,247 @@
+//! mkfs.poundfs：在镜像文件或块设备上创建 PoundFS
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::Path,
+    process::exit,
+};
+
+use clap::{crate_version, Arg, ArgMatches, Command};
+use pound_fs::{
+    block_dev::BlockDevice,
+    dstruct::SuperBlockMagicNum,
+    file_blk::{FileBlockDevice, PHY_BLOCKSIZE},
+    pound_fs::{
+        check_blocksize, make_fs, print_geometry, MkfsError, MkfsOption, DEFAULT_INODESIZE,
+        MAX_AG_BLOCKS,
+    },
+    util::{parse_size, parse_uuid, uuid_str},
+};
+
+// 未指定 AG 大小时默认划分的 AG 数
+const DEFAULT_AGCOUNT: u32 = 4;
+
+fn fail(msg: &str) -> ! {
+    eprintln!("mkfs.poundfs: {}", msg);
+    exit(1);
+}
+
+fn parse_num<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|v| match v.parse() {
+        Ok(n) => n,
+        Err(_) => fail(&format!("invalid value '{}' for --{}", v, name)),
+    })
+}
+
+/// 已有文件或块设备的字节大小
+fn target_size(path: &str) -> Option<usize> {
+    let mut file = File::open(path).ok()?;
+    file.seek(SeekFrom::End(0)).ok().map(|len| len as usize)
+}
+
+/// 目标上是否已经有 PoundFS
+fn has_poundfs(path: &str) -> bool {
+    match target_size(path) {
+        Some(len) if len >= 512 => {}
+        _ => return false,
+    }
+    let mut magic = [0u8; 4];
+    match FileBlockDevice::new(path) {
+        Ok(dev) => {
+            dev.read_at(0, &mut magic).is_ok() && u32::from_le_bytes(magic) == SuperBlockMagicNum
+        }
+        Err(_) => false,
+    }
+}
+
+fn main() {
+    let matches = Command::new("mkfs.poundfs")
+        .version(crate_version!())
+        .about("Create a PoundFS filesystem")
+        .arg(
+            Arg::new("TARGET")
+                .required(true)
+                .index(1)
+                .help("Image file or block device to format"),
+        )
+        .arg(
+            Arg::new("size")
+                .short('s')
+                .long("size")
+                .takes_value(true)
+                .help("Filesystem size, e.g. 64M or 1G. Defaults to the size of TARGET"),
+        )
+        .arg(
+            Arg::new("block-size")
+                .short('b')
+                .long("block-size")
+                .takes_value(true)
+                .default_value("4096")
+                .help("Block size in bytes"),
+        )
+        .arg(
+            Arg::new("agblocks")
+                .short('a')
+                .long("agblocks")
+                .takes_value(true)
+                .conflicts_with("agcount")
+                .help("Blocks per allocation group"),
+        )
+        .arg(
+            Arg::new("agcount")
+                .short('c')
+                .long("agcount")
+                .takes_value(true)
+                .help("Number of allocation groups"),
+        )
+        .arg(
+            Arg::new("inode-size")
+                .short('i')
+                .long("inode-size")
+                .takes_value(true)
+                .help("Inode size in bytes"),
+        )
+        .arg(
+            Arg::new("label")
+                .short('L')
+                .long("label")
+                .takes_value(true)
+                .help("Filesystem label, at most 16 bytes"),
+        )
+        .arg(
+            Arg::new("log-size")
+                .short('l')
+                .long("log-size")
+                .takes_value(true)
+                .help("Size of the log placed in AG 0, e.g. 4M. No log by default"),
+        )
+        .arg(
+            Arg::new("uuid")
+                .short('U')
+                .long("uuid")
+                .takes_value(true)
+                .help("Filesystem UUID. Generated randomly if not given"),
+        )
+        .arg(
+            Arg::new("sparse-inodes")
+                .long("sparse-inodes")
+                .help("Allow partial inode chunks when free space is fragmented"),
+        )
+        .arg(
+            Arg::new("force")
+                .short('f')
+                .long("force")
+                .help("Overwrite an existing filesystem"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .short('n')
+                .long("dry-run")
+                .help("Print the geometry without writing anything"),
+        )
+        .get_matches();
+
+    let target = matches.value_of("TARGET").unwrap();
+    let exists = Path::new(target).exists();
+    let size = match matches.value_of("size") {
+        Some(s) => parse_size(s).unwrap_or_else(|| fail(&format!("invalid size '{}'", s))),
+        None => target_size(target)
+            .filter(|&len| len > 0)
+            .unwrap_or_else(|| fail("--size is required when TARGET does not exist")),
+    };
+    let blocksize: u32 = parse_num(&matches, "block-size").unwrap();
+    // 由块大小推算 AG 大小之前先检查它，其余参数由 validate 检查
+    if let Err(e) = check_blocksize(blocksize, PHY_BLOCKSIZE) {
+        fail(&e.to_string());
+    }
+    let dblocks = u32::try_from(size / blocksize as usize)
+        .unwrap_or_else(|_| fail(&MkfsError::TooLarge(size).to_string()));
+    // AG 大小必须是 2 的幂，实际的 AG 数可能比指定的少
+    let ag_for_count = |agcount: u32| {
+        let per_ag = dblocks.div_ceil(agcount);
+        per_ag
+            .checked_next_power_of_two()
+            .filter(|&agblocks| agblocks <= MAX_AG_BLOCKS)
+            .unwrap_or_else(|| {
+                let e = MkfsError::AgTooLarge {
+                    agblocks: per_ag,
+                    max: MAX_AG_BLOCKS,
+                };
+                fail(&e.to_string())
+            })
+    };
+    let agblocks = match (
+        parse_num::<u32>(&matches, "agblocks"),
+        parse_num::<u32>(&matches, "agcount"),
+    ) {
+        (Some(agblocks), _) => agblocks,
+        (None, Some(agcount)) if agcount > 0 => ag_for_count(agcount),
+        (None, Some(_)) => fail("--agcount must be positive"),
+        (None, None) => ag_for_count(DEFAULT_AGCOUNT),
+    };
+    let logblocks = match matches.value_of("log-size") {
+        Some(s) => match parse_size(s) {
+            // 超出 u32 的块数由 validate 报错
+            Some(size) => u32::try_from(size / blocksize as usize).unwrap_or(u32::MAX),
+            None => fail(&format!("invalid log size '{}'", s)),
+        },
+        None => 0,
+    };
+    let label = matches.value_of("label").unwrap_or("").to_string();
+    let uuid = matches.value_of("uuid").map(|s| {
+        parse_uuid(s).unwrap_or_else(|| fail(&format!("invalid uuid '{}'", s)))
+    });
+    let opt = MkfsOption {
+        size,
+        blocksize,
+        agblocks,
+        inodesize: parse_num(&matches, "inode-size").unwrap_or(DEFAULT_INODESIZE),
+        label,
+        uuid,
+        logblocks,
+        sparse_inodes: matches.is_present("sparse-inodes"),
+    };
+    let geo = match opt.validate(PHY_BLOCKSIZE) {
+        Ok(geo) => geo,
+        Err(e) => fail(&e.to_string()),
+    };
+
+    if matches.is_present("dry-run") {
+        print_geometry(&opt, &geo);
+        println!(
+            "blocksize={}, agblocks={}, inodesize={}, logblocks={}, sparse_inodes={}, label={:?}, uuid={}",
+            opt.blocksize,
+            opt.agblocks,
+            opt.inodesize,
+            opt.logblocks,
+            opt.sparse_inodes,
+            opt.label,
+            opt.uuid.map(|u| uuid_str(&u)).unwrap_or_else(|| "random".to_string())
+        );
+        return;
+    }
+    if exists && !matches.is_present("force") && has_poundfs(target) {
+        fail(&format!(
+            "{} contains an existing PoundFS, use -f to overwrite it",
+            target
+        ));
+    }
+
+    let dev = if exists {
+        // 镜像文件比指定的大小小时需要扩展
+        if target_size(target).unwrap_or(0) < size {
+            let file = File::options().write(true).open(target);
+            if file.and_then(|f| f.set_len(size as u64)).is_err() {
+                fail(&format!("{} is smaller than {} bytes", target, size));
+            }
+        }
+        FileBlockDevice::new(target)
+    } else {
+        FileBlockDevice::create(target, size)
+    };
+    let dev = dev.unwrap_or_else(|e| fail(&format!("{}: {}", target, e)));
+    print_geometry(&opt, &geo);
+    if let Err(e) = make_fs(Box::new(dev), opt) {
+        fail(&e.to_string());
+    }
+}
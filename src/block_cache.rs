@@ -1,126 +1,256 @@
-//! A naive LRU cache layer for `BlockDevice`
-use super::*;
-use alloc::{vec, vec::Vec};
-use spin::{Mutex, MutexGuard};
+//! A sharded, hash-indexed LRU cache layer for `BlockDevice`
+use std::{
+    collections::HashMap,
+    io,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+};
 
-pub struct BlockCache<T: BlockDevice> {
-    device: T,
+use crate::block_dev::BlockDevice;
+
+// MountPoint 默认缓存的物理块数
+pub const DEFAULT_CACHE_BLOCKS: usize = 8192;
+// 默认分片数，分片越多并发访问时锁竞争越少
+pub const DEFAULT_CACHE_SHARDS: usize = 16;
+
+const NO_BLOCK: usize = usize::MAX;
+
+pub struct BlockCache<'a> {
+    device: Box<dyn BlockDevice + 'a>,
+    shards: Vec<Shard>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    writebacks: AtomicU64,
+}
+
+/// 缓存的命中、未命中和写回次数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub writebacks: u64,
+}
+
+/// 块按 `block_id % shards` 分到各个分片，每个分片有自己的索引和 LRU
+struct Shard {
+    state: Mutex<ShardState>,
     bufs: Vec<Mutex<Buf>>,
-    lru: Mutex<LRU>,
+}
+
+struct ShardState {
+    /// block_id -> 分片内缓冲区下标，一个块最多只在一个缓冲区中
+    map: HashMap<usize, usize>,
+    lru: LRU,
+    free: Vec<usize>,
 }
 
 struct Buf {
+    /// 缓冲区当前对应的块，空闲时为 `NO_BLOCK`
+    block_id: usize,
     status: BufStatus,
     data: Vec<u8>,
 }
 
 enum BufStatus {
-    /// buffer is unused
+    /// buffer has not been read from disk
     Unused,
     /// buffer has been read from disk
-    Valid(BlockId),
+    Valid,
     /// buffer needs to be written to disk
-    Dirty(BlockId),
+    Dirty,
 }
 
-impl<T: BlockDevice> BlockCache<T> {
-    pub fn new(device: T, capacity: usize) -> Self {
-        let mut bufs = Vec::new();
-        bufs.resize_with(capacity, || {
-            Mutex::new(Buf {
-                status: BufStatus::Unused,
-                data: vec![0; 1 << T::BLOCK_SIZE_LOG2 as usize],
+impl<'a> BlockCache<'a> {
+    pub fn new(device: Box<dyn BlockDevice + 'a>, capacity: usize) -> Self {
+        Self::with_shards(device, capacity, DEFAULT_CACHE_SHARDS)
+    }
+
+    pub fn with_shards(device: Box<dyn BlockDevice + 'a>, capacity: usize, shards: usize) -> Self {
+        let block_size = device.get_phy_block_size() as usize;
+        let shards = shards.clamp(1, capacity.max(1));
+        let per_shard = capacity.div_ceil(shards).max(1);
+        let shards = (0..shards)
+            .map(|_| {
+                let mut bufs = Vec::new();
+                bufs.resize_with(per_shard, || {
+                    Mutex::new(Buf {
+                        block_id: NO_BLOCK,
+                        status: BufStatus::Unused,
+                        data: vec![0; block_size],
+                    })
+                });
+                Shard {
+                    state: Mutex::new(ShardState {
+                        map: HashMap::with_capacity(per_shard),
+                        lru: LRU::new(per_shard),
+                        free: (0..per_shard).rev().collect(),
+                    }),
+                    bufs,
+                }
             })
-        });
-        let lru = Mutex::new(LRU::new(capacity));
-        BlockCache { device, bufs, lru }
+            .collect();
+        BlockCache {
+            device,
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            writebacks: AtomicU64::new(0),
+        }
     }
 
-    /// Get a buffer for `block_id` with any status
-    fn get_buf(&self, block_id: BlockId) -> MutexGuard<Buf> {
-        let (i, buf) = self._get_buf(block_id);
-        self.lru.lock().visit(i);
-        buf
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            writebacks: self.writebacks.load(Ordering::Relaxed),
+        }
     }
 
-    fn _get_buf(&self, block_id: BlockId) -> (usize, MutexGuard<Buf>) {
-        for (i, buf) in self.bufs.iter().enumerate() {
-            if let Some(lock) = buf.try_lock() {
-                match lock.status {
-                    BufStatus::Valid(id) if id == block_id => return (i, lock),
-                    BufStatus::Dirty(id) if id == block_id => return (i, lock),
-                    _ => {}
+    /// Get the buffer of `block_id`, its status is `Unused` if it is newly allocated
+    fn get_buf(&self, block_id: usize) -> io::Result<MutexGuard<'_, Buf>> {
+        if block_id >= self.device.num_blocks() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("block {} is out of range", block_id),
+            ));
+        }
+        let shard = &self.shards[block_id % self.shards.len()];
+        loop {
+            let mut state = shard.state.lock().unwrap();
+            if let Some(&i) = state.map.get(&block_id) {
+                state.lru.visit(i);
+                drop(state);
+                let buf = shard.bufs[i].lock().unwrap();
+                // 在拿到缓冲区锁之前，它可能已经被淘汰并分给了别的块
+                if buf.block_id == block_id {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(buf);
                 }
+                continue;
             }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            let (i, mut buf) = self.get_unused(shard, &mut state)?;
+            state.map.insert(block_id, i);
+            state.lru.visit(i);
+            buf.block_id = block_id;
+            buf.status = BufStatus::Unused;
+            return Ok(buf);
         }
-        self.get_unused()
     }
 
-    /// Get an unused buffer
-    fn get_unused(&self) -> (usize, MutexGuard<Buf>) {
-        for (i, buf) in self.bufs.iter().enumerate() {
-            if let Some(lock) = buf.try_lock() {
-                if let BufStatus::Unused = lock.status {
-                    return (i, lock);
-                }
-            }
+    /// Get an unused buffer in `shard`, evicting the least recently used one if necessary
+    fn get_unused<'s>(
+        &self,
+        shard: &'s Shard,
+        state: &mut ShardState,
+    ) -> io::Result<(usize, MutexGuard<'s, Buf>)> {
+        if let Some(i) = state.free.pop() {
+            return Ok((i, shard.bufs[i].lock().unwrap()));
         }
-        let victim_id = self.lru.lock().victim();
-        let mut victim = self.bufs[victim_id].lock();
-        self.write_back(&mut victim).expect("failed to write back");
-        victim.status = BufStatus::Unused;
-        (victim_id, victim)
+        // 跳过正在被使用的缓冲区，都在使用时等待最久未使用的那个
+        let victim = state
+            .lru
+            .iter_from_tail()
+            .find_map(|i| shard.bufs[i].try_lock().ok().map(|buf| (i, buf)));
+        let (i, mut buf) = match victim {
+            Some(victim) => victim,
+            None => {
+                let i = state.lru.victim();
+                (i, shard.bufs[i].lock().unwrap())
+            }
+        };
+        self.write_back(&mut buf)?;
+        state.map.remove(&buf.block_id);
+        Ok((i, buf))
     }
 
     /// Write back data if buffer is dirty
-    fn write_back(&self, buf: &mut Buf) -> Result<()> {
-        if let BufStatus::Dirty(block_id) = buf.status {
-            self.device.write_at(block_id, &buf.data)?;
-            buf.status = BufStatus::Valid(block_id);
+    fn write_back(&self, buf: &mut Buf) -> io::Result<()> {
+        if let BufStatus::Dirty = buf.status {
+            self.device.write_block(buf.block_id, &buf.data)?;
+            self.writebacks.fetch_add(1, Ordering::Relaxed);
+            buf.status = BufStatus::Valid;
         }
         Ok(())
     }
 }
 
-impl<T: BlockDevice> Drop for BlockCache<T> {
+impl<'a> Drop for BlockCache<'a> {
     fn drop(&mut self) {
-        BlockDevice::sync(self).expect("failed to sync");
+        if let Err(e) = self.flush() {
+            eprintln!("failed to write back block cache: {}", e);
+        }
     }
 }
 
-impl<T: BlockDevice> BlockDevice for BlockCache<T> {
-    const BLOCK_SIZE_LOG2: u8 = T::BLOCK_SIZE_LOG2;
-
-    fn read_at(&self, block_id: BlockId, buffer: &mut [u8]) -> Result<()> {
-        let mut buf = self.get_buf(block_id);
+impl<'a> BlockDevice for BlockCache<'a> {
+    fn read_block(&self, block_id: usize, buffer: &mut [u8]) -> io::Result<()> {
+        let mut buf = self.get_buf(block_id)?;
         if let BufStatus::Unused = buf.status {
             // read from device
-            self.device.read_at(block_id, &mut buf.data)?;
-            buf.status = BufStatus::Valid(block_id);
+            self.device.read_block(block_id, &mut buf.data)?;
+            buf.status = BufStatus::Valid;
         }
-        let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
-        buffer[..len].copy_from_slice(&buf.data);
+        let len = buffer.len().min(buf.data.len());
+        buffer[..len].copy_from_slice(&buf.data[..len]);
         Ok(())
     }
 
-    fn write_at(&self, block_id: BlockId, buffer: &[u8]) -> Result<()> {
-        let mut buf = self.get_buf(block_id);
-        buf.status = BufStatus::Dirty(block_id);
-        let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
-        buf.data.copy_from_slice(&buffer[..len]);
+    fn write_block(&self, block_id: usize, buffer: &[u8]) -> io::Result<()> {
+        let mut buf = self.get_buf(block_id)?;
+        let len = buffer.len().min(buf.data.len());
+        // 只写入块的一部分时，其余部分需要先从设备读出
+        if let (BufStatus::Unused, true) = (&buf.status, len < buf.data.len()) {
+            self.device.read_block(block_id, &mut buf.data)?;
+        }
+        buf.status = BufStatus::Dirty;
+        buf.data[..len].copy_from_slice(&buffer[..len]);
         Ok(())
     }
 
-    fn sync(&self) -> Result<()> {
-        for buf in self.bufs.iter() {
-            self.write_back(&mut buf.lock())?;
+    fn get_phy_block_size(&self) -> u16 {
+        self.device.get_phy_block_size()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.device.num_blocks()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for shard in self.shards.iter() {
+            for buf in shard.bufs.iter() {
+                self.write_back(&mut buf.lock().unwrap())?;
+            }
         }
-        self.device.sync()?;
-        Ok(())
+        self.device.flush()
+    }
+
+    fn discard(&self, blocks: Range<usize>) -> io::Result<()> {
+        // 被丢弃的块即使是脏的也不必写回
+        for shard in self.shards.iter() {
+            let mut state = shard.state.lock().unwrap();
+            let ShardState { map, free, .. } = &mut *state;
+            map.retain(|block_id, &mut i| {
+                if !blocks.contains(block_id) {
+                    return true;
+                }
+                let mut buf = shard.bufs[i].lock().unwrap();
+                buf.block_id = NO_BLOCK;
+                buf.status = BufStatus::Unused;
+                free.push(i);
+                false
+            });
+        }
+        self.device.discard(blocks)
     }
 }
 
 /// Doubly circular linked list LRU manager
+///
+/// 节点 0 是哨兵，第 i 个缓冲区对应节点 i + 1
 #[allow(clippy::upper_case_acronyms)]
 struct LRU {
     prev: Vec<usize>,
@@ -129,6 +259,7 @@ struct LRU {
 
 impl LRU {
     fn new(size: usize) -> Self {
+        let size = size + 1;
         LRU {
             prev: (size - 1..size).chain(0..size - 1).collect(),
             next: (1..size).chain(0..1).collect(),
@@ -136,7 +267,8 @@ impl LRU {
     }
     /// Visit element `id`, move it to head.
     fn visit(&mut self, id: usize) {
-        if id == 0 || id >= self.prev.len() {
+        let id = id + 1;
+        if id >= self.prev.len() {
             return;
         }
         self._list_remove(id);
@@ -144,7 +276,19 @@ impl LRU {
     }
     /// Get a victim at tail.
     fn victim(&self) -> usize {
-        self.prev[0]
+        self.prev[0] - 1
+    }
+    /// Iterate elements from tail to head.
+    fn iter_from_tail(&self) -> impl Iterator<Item = usize> + '_ {
+        let mut cur = self.prev[0];
+        std::iter::from_fn(move || {
+            if cur == 0 {
+                return None;
+            }
+            let id = cur - 1;
+            cur = self.prev[cur];
+            Some(id)
+        })
     }
     fn _list_remove(&mut self, id: usize) {
         let prev = self.prev[id];
@@ -159,4 +303,4 @@ impl LRU {
         self.next[0] = id;
         self.prev[head] = id;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,94 @@
+#[cfg(test)]
+use std::thread;
+
+#[cfg(test)]
+use crate::{
+    block_cache::{BlockCache, CacheStats},
+    block_dev::BlockDevice,
+    file_blk::FileBlockDevice,
+};
+
+#[test]
+fn test_block_cache() {
+    let path = "test_block_cache.bin";
+    let dev = FileBlockDevice::create(path, 16 * 512).unwrap();
+    // 只用一个分片，淘汰顺序就是全局的 LRU 顺序
+    let cache = BlockCache::with_shards(Box::new(dev), 4, 1);
+    assert_eq!(cache.num_blocks(), 16);
+    let mut buf = [0u8; 512];
+    cache.write_block(1, &[1u8; 512]).unwrap();
+    cache.read_block(1, &mut buf).unwrap();
+    assert_eq!(buf, [1u8; 512]);
+    assert_eq!(
+        cache.stats(),
+        CacheStats {
+            hits: 1,
+            misses: 1,
+            writebacks: 0
+        }
+    );
+    // 写入的块在刷回之前不落盘
+    let dev = FileBlockDevice::new(path).unwrap();
+    dev.read_block(1, &mut buf).unwrap();
+    assert_eq!(buf, [0u8; 512]);
+
+    // 超出容量时淘汰最久未使用的块，脏块先写回
+    for i in 2..6 {
+        cache.write_block(i, &[i as u8; 512]).unwrap();
+    }
+    assert_eq!(cache.stats().writebacks, 1);
+    dev.read_block(1, &mut buf).unwrap();
+    assert_eq!(buf, [1u8; 512]);
+    cache.read_block(1, &mut buf).unwrap();
+    assert_eq!(buf, [1u8; 512]);
+
+    cache.flush().unwrap();
+    for i in 2..6 {
+        dev.read_block(i, &mut buf).unwrap();
+        assert_eq!(buf, [i as u8; 512]);
+    }
+    assert!(cache.read_block(16, &mut buf).is_err());
+    assert!(cache.write_block(16, &buf).is_err());
+}
+
+#[test]
+fn test_block_cache_discard() {
+    let path = "test_block_cache_discard.bin";
+    let cache = BlockCache::new(
+        Box::new(FileBlockDevice::create(path, 16 * 512).unwrap()),
+        4,
+    );
+    cache.write_block(3, &[3u8; 512]).unwrap();
+    cache.discard(0..8).unwrap();
+    drop(cache);
+    // 被丢弃的脏块不会再写回
+    let dev = FileBlockDevice::new(path).unwrap();
+    let mut buf = [0u8; 512];
+    dev.read_block(3, &mut buf).unwrap();
+    assert_eq!(buf, [0u8; 512]);
+}
+
+#[test]
+fn test_block_cache_concurrent() {
+    let path = "test_block_cache_concurrent.bin";
+    let dev = FileBlockDevice::create(path, 256 * 512).unwrap();
+    for i in 0..256 {
+        dev.write_block(i, &[i as u8; 512]).unwrap();
+    }
+    let cache = BlockCache::new(Box::new(dev), 1024);
+    thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                let mut buf = [0u8; 512];
+                for i in 0..256 {
+                    cache.read_block(i, &mut buf).unwrap();
+                    assert_eq!(buf, [i as u8; 512]);
+                }
+            });
+        }
+    });
+    // 每个块只会被读入一次
+    let stats = cache.stats();
+    assert_eq!(stats.misses, 256);
+    assert_eq!(stats.hits, 7 * 256);
+}
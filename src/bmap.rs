@@ -0,0 +1,492 @@
+//! 文件的 data fork：文件逻辑块到文件系统块的映射
+//!
+//! 内存中的 `Inode::extents` 总是保存完整的 extent 列表（相当于 XFS 的 iext 树）。
+//! extent 能放进 inode 时以 FMT_EXTENTS 格式内联保存；放不下时转换为 FMT_BTREE，
+//! extent 保存在 bmbt 中，inode 里只保存 `BmdrBlock` 形式的根。
+//! 每次修改映射后，只同步 bmbt 中受影响的那一段记录。
+use std::{ops::Range, sync::atomic::Ordering};
+
+use libc::{EFBIG, EIO};
+
+use crate::{
+    alloc::AllocArgs,
+    btree::{Btree, BtreeAlloc, BtreeRecord},
+    dstruct::{
+        AbsInoNo, BmbtRecord, BmdrBlock, Dinode, ExtentState, DINODE_FMT_BTREE, DINODE_FMT_EXTENTS,
+        MAX_BMBT_EXTLEN,
+    },
+    inode::Inode,
+    pound_fs::{FsResult, MountPoint},
+};
+
+// BmbtRecord 编码后的大小
+pub const BMBT_REC_SIZE: usize = <BmbtRecord as BtreeRecord>::SIZE;
+// 小于这个大小的文件追加写时不做推测性预分配
+pub const EOF_PREALLOC_MIN_SIZE: u64 = 64 << 10;
+
+/// a 之后紧接着 b，在逻辑上和物理上都连续且状态相同，合并后的长度不超过 MAX_BMBT_EXTLEN
+fn contiguous(a: &BmbtRecord, b: &BmbtRecord) -> bool {
+    a.startoff + a.blockcount == b.startoff
+        && a.startblock + a.blockcount == b.startblock
+        && a.state == b.state
+        && a.blockcount + b.blockcount <= MAX_BMBT_EXTLEN
+}
+
+impl Inode {
+    /// 将文件逻辑块号映射为文件系统块号。空洞返回 None
+    pub fn bmap(&self, lblk: u64) -> Option<(u64, ExtentState)> {
+        let idx = self.extents.partition_point(|e| e.startoff <= lblk);
+        let e = self.extents[..idx].last()?;
+        (lblk < e.startoff + e.blockcount).then(|| (e.startblock + (lblk - e.startoff), e.state))
+    }
+
+    /// 下一个从 lblk 之后开始的 extent 的起始逻辑块号
+    pub fn next_mapped(&self, lblk: u64) -> Option<u64> {
+        let idx = self.extents.partition_point(|e| e.startoff <= lblk);
+        self.extents.get(idx).map(|e| e.startoff)
+    }
+
+    /// 包含 lblk 的空洞 [lo, hi)，lblk 必须没有映射
+    pub fn hole_around(&self, lblk: u64) -> (u64, u64) {
+        let idx = self.extents.partition_point(|e| e.startoff <= lblk);
+        let lo = self.extents[..idx]
+            .last()
+            .map_or(0, |e| e.startoff + e.blockcount);
+        let hi = self.extents.get(idx).map_or(u64::MAX, |e| e.startoff);
+        (lo, hi)
+    }
+
+    /// 插入一个新映射的 extent，与前后相邻的 extent 在逻辑上和物理上都连续时合并
+    fn insert_extent(&mut self, rec: BmbtRecord) {
+        let idx = self.extents.partition_point(|e| e.startoff < rec.startoff);
+        let merge_prev = idx > 0 && contiguous(&self.extents[idx - 1], &rec);
+        let merge_next = idx < self.extents.len()
+            && contiguous(&rec, &self.extents[idx])
+            && !(merge_prev
+                && self.extents[idx - 1].blockcount
+                    + rec.blockcount
+                    + self.extents[idx].blockcount
+                    > MAX_BMBT_EXTLEN);
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                let next = self.extents.remove(idx);
+                self.extents[idx - 1].blockcount += rec.blockcount + next.blockcount;
+            }
+            (true, false) => self.extents[idx - 1].blockcount += rec.blockcount,
+            (false, true) => {
+                self.extents[idx].startoff = rec.startoff;
+                self.extents[idx].startblock = rec.startblock;
+                self.extents[idx].blockcount += rec.blockcount;
+            }
+            (false, false) => self.extents.insert(idx, rec),
+        }
+        self.core.nblocks += rec.blockcount;
+    }
+
+    /// 将 [lblk, lblk + len) 中已映射部分的状态改为 state，返回是否有 extent 被修改
+    ///
+    /// 跨越边界的 extent 被拆分，修改后与相邻的 extent 合并
+    fn convert_range(&mut self, lblk: u64, len: u64, state: ExtentState) -> bool {
+        let end = lblk.saturating_add(len);
+        let overlaps =
+            |rec: &BmbtRecord| rec.startoff < end && rec.startoff + rec.blockcount > lblk;
+        if !self
+            .extents
+            .iter()
+            .any(|rec| rec.state != state && overlaps(rec))
+        {
+            return false;
+        }
+        let mut split = Vec::with_capacity(self.extents.len() + 2);
+        for rec in self.extents.drain(..) {
+            if rec.state == state || !overlaps(&rec) {
+                split.push(rec);
+                continue;
+            }
+            let rec_end = rec.startoff + rec.blockcount;
+            let (start, stop) = (rec.startoff.max(lblk), rec_end.min(end));
+            for (from, to, st) in [
+                (rec.startoff, start, rec.state),
+                (start, stop, state),
+                (stop, rec_end, rec.state),
+            ] {
+                if from < to {
+                    split.push(BmbtRecord {
+                        startoff: from,
+                        startblock: rec.startblock + (from - rec.startoff),
+                        blockcount: to - from,
+                        state: st,
+                    });
+                }
+            }
+        }
+        // 只合并边界落在 [lblk, end] 中的 extent，范围之外的记录保持不变
+        let mut merged: Vec<BmbtRecord> = Vec::with_capacity(split.len());
+        for rec in split {
+            match merged.last_mut() {
+                Some(prev) if (lblk..=end).contains(&rec.startoff) && contiguous(prev, &rec) => {
+                    prev.blockcount += rec.blockcount
+                }
+                _ => merged.push(rec),
+            }
+        }
+        self.extents = merged;
+        true
+    }
+
+    /// 将 startoff 不小于 lblk 的 extent 前移 shift 块，[lblk - shift, lblk) 必须是空洞
+    ///
+    /// 移动后与前面相邻的 extent 合并
+    fn shift_extents(&mut self, lblk: u64, shift: u64) {
+        let idx = self.extents.partition_point(|e| e.startoff < lblk);
+        for rec in self.extents[idx..].iter_mut() {
+            rec.startoff -= shift;
+        }
+        if idx > 0
+            && idx < self.extents.len()
+            && contiguous(&self.extents[idx - 1], &self.extents[idx])
+        {
+            let next = self.extents.remove(idx);
+            self.extents[idx - 1].blockcount += next.blockcount;
+        }
+    }
+
+    /// 解除 [lblk, lblk + len) 的映射，返回被解除映射的物理范围 (起始块号, 块数)
+    ///
+    /// 跨越边界的 extent 被拆分，只保留范围之外的部分
+    fn unmap_range(&mut self, lblk: u64, len: u64) -> Vec<(u64, u64)> {
+        let end = lblk.saturating_add(len);
+        let mut freed = Vec::new();
+        let mut kept = Vec::with_capacity(self.extents.len());
+        for rec in self.extents.drain(..) {
+            let rec_end = rec.startoff + rec.blockcount;
+            if rec_end <= lblk || rec.startoff >= end {
+                kept.push(rec);
+                continue;
+            }
+            if rec.startoff < lblk {
+                kept.push(BmbtRecord {
+                    blockcount: lblk - rec.startoff,
+                    ..rec
+                });
+            }
+            let start = rec.startoff.max(lblk);
+            freed.push((
+                rec.startblock + (start - rec.startoff),
+                rec_end.min(end) - start,
+            ));
+            if rec_end > end {
+                kept.push(BmbtRecord {
+                    startoff: end,
+                    startblock: rec.startblock + (end - rec.startoff),
+                    blockcount: rec_end - end,
+                    ..rec
+                });
+            }
+        }
+        self.extents = kept;
+        self.core.nblocks -= freed.iter().map(|&(_, len)| len).sum::<u64>();
+        freed
+    }
+}
+
+/// 为 bmbt 分配块，尽量靠近 inode，块数计入 inode 的 nblocks
+struct BmbtAlloc<'m> {
+    mp: &'m MountPoint<'m>,
+    ino: AbsInoNo,
+    hint: u64,
+    blocks: i64, // 树占用的块数的变化
+}
+
+impl<'m> BtreeAlloc for BmbtAlloc<'m> {
+    fn alloc_block(&mut self) -> FsResult<u64> {
+        let args = AllocArgs {
+            ino: self.ino,
+            hint: Some(self.hint),
+            minlen: 1,
+            maxlen: 1,
+        };
+        let (fsbno, _) = self
+            .mp
+            .with_reserved(1, |resv| self.mp.alloc_blocks(&args, resv))?;
+        self.hint = fsbno + 1;
+        self.blocks += 1;
+        Ok(fsbno)
+    }
+
+    fn free_block(&mut self, fsbno: u64) -> FsResult<()> {
+        self.mp.free_blocks(fsbno, 1)?;
+        self.blocks -= 1;
+        Ok(())
+    }
+}
+
+impl<'a> MountPoint<'a> {
+    /// data fork 中最多能内联多少个 extent
+    pub fn max_inline_extents(&self, core: &Dinode) -> usize {
+        core.data_fork_size(self.superblock.inodesize) / BMBT_REC_SIZE
+    }
+
+    fn bmbt(&self, broot: &BmdrBlock, ino: AbsInoNo) -> Btree<'_, BmbtRecord> {
+        Btree::new(
+            self,
+            broot.ptrs[0],
+            broot.level as u32,
+            self.ino_to_agno(ino),
+        )
+    }
+
+    fn bmbt_alloc(&self, ino: AbsInoNo) -> BmbtAlloc<'_> {
+        BmbtAlloc {
+            mp: self,
+            ino,
+            hint: ino >> self.superblock.inpblock_bits,
+            blocks: 0,
+        }
+    }
+
+    /// 读入 btree 格式的 data fork 中所有的 extent xfs_iread_extents
+    ///
+    /// 目前 inode 中的根只有一个指针，指向磁盘上 bmbt 的根节点
+    pub fn bmap_read_btree(
+        &self,
+        core: &Dinode,
+        fork: &[u8],
+    ) -> FsResult<(BmdrBlock, Vec<BmbtRecord>)> {
+        let broot = BmdrBlock::decode(fork)
+            .filter(|broot| broot.ptrs.len() == 1 && broot.level > 0)
+            .ok_or(EIO)?;
+        let extents = self
+            .bmbt(&broot, core.ino)
+            .range(..)
+            .collect::<FsResult<Vec<_>>>()?;
+        if extents.len() != core.nextents as usize {
+            return Err(EIO);
+        }
+        Ok((broot, extents))
+    }
+
+    /// 按 extsize 提示把要分配的 [start, end) 向外扩展到 extsize 的整数倍，不超出 [lo, hi)
+    /// xfs_bmap_extsize_align
+    pub fn extsize_align(&self, ip: &Inode, start: u64, end: u64, lo: u64, hi: u64) -> (u64, u64) {
+        let extsize = ip.core.extsize_hint();
+        if extsize <= 1 {
+            return (start, end);
+        }
+        let astart = (start - start % extsize).max(lo);
+        let aend = end
+            .checked_next_multiple_of(extsize)
+            .unwrap_or(u64::MAX)
+            .min(hi);
+        (astart, aend)
+    }
+
+    /// 追加写时在 EOF 之后推测性预分配的块数 xfs_iomap_prealloc_size
+    ///
+    /// 与文件当前的大小相当，取 2 的幂，不超过 inode 所在 AG 中最长的空闲 extent。
+    /// 小文件和有 extsize 提示的文件不预分配，空闲空间少于 5% 时每少 1% 减半
+    pub fn eof_prealloc_blocks(&self, ip: &Inode) -> u64 {
+        if ip.core.size < EOF_PREALLOC_MIN_SIZE || ip.core.extsize_hint() != 0 {
+            return 0;
+        }
+        let size_blocks = ip.core.size / self.superblock.blocksize as u64;
+        let agno = self.ino_to_agno(ip.ino) as usize;
+        let longest = self.perag[agno].agf.lock().unwrap().longest as u64;
+        let mut blocks = (1 << size_blocks.ilog2()).min(MAX_BMBT_EXTLEN).min(longest);
+        let free = self
+            .fdblocks
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.alloc_set_aside());
+        let dblocks = self.superblock.dblocks as u64;
+        for pct in 1..=5 {
+            if free < dblocks * pct / 100 {
+                blocks >>= 1;
+            }
+        }
+        // 预分配的块没有预留，不能占用别人预留的空间
+        blocks.min(free)
+    }
+
+    /// 映射刚分配的 [startoff, startoff + len)，data 中的部分状态为 state，其余部分为 unwritten
+    ///
+    /// 失败时释放所有的块，映射保持不变
+    pub fn map_alloc(
+        &self,
+        ip: &mut Inode,
+        startoff: u64,
+        fsbno: u64,
+        len: u64,
+        data: Range<u64>,
+        state: ExtentState,
+    ) -> FsResult<()> {
+        let end = startoff + len;
+        let (lo, hi) = (
+            data.start.clamp(startoff, end),
+            data.end.clamp(startoff, end),
+        );
+        let pieces: Vec<BmbtRecord> = [
+            (startoff, lo, ExtentState::ExtUnwritten),
+            (lo, hi, state),
+            (hi, end, ExtentState::ExtUnwritten),
+        ]
+        .into_iter()
+        .filter(|&(from, to, _)| from < to)
+        .map(|(from, to, state)| BmbtRecord {
+            startoff: from,
+            startblock: fsbno + (from - startoff),
+            blockcount: to - from,
+            state,
+        })
+        .collect();
+        for (i, rec) in pieces.iter().enumerate() {
+            if let Err(e) = self.map_extent(ip, *rec) {
+                for rec in pieces[..i].iter() {
+                    let _ = self.bunmap(ip, rec.startoff, rec.blockcount);
+                }
+                for rec in pieces[i..].iter() {
+                    let _ = self.free_blocks(rec.startblock, rec.blockcount);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// 将一个新分配的 extent 映射到 inode 的 data fork 中 xfs_bmapi_write
+    ///
+    /// 失败时映射保持不变，rec 中的块仍由调用者负责
+    pub fn map_extent(&self, ip: &mut Inode, rec: BmbtRecord) -> FsResult<()> {
+        let end = rec.startoff + rec.blockcount;
+        ip.insert_extent(rec);
+        if let Err(e) = self.bmap_sync(ip, rec.startoff, end) {
+            ip.unmap_range(rec.startoff, rec.blockcount);
+            let _ = self.bmap_sync(ip, rec.startoff, end);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// 解除 [lblk, lblk + len) 的映射并释放对应的块 xfs_bunmapi
+    pub fn bunmap(&self, ip: &mut Inode, lblk: u64, len: u64) -> FsResult<()> {
+        let freed = ip.unmap_range(lblk, len);
+        self.bmap_sync(ip, lblk, lblk.saturating_add(len))?;
+        for (fsbno, len) in freed {
+            self.free_blocks(fsbno, len)?;
+        }
+        Ok(())
+    }
+
+    /// 将 [lblk, lblk + len) 中已映射的 extent 转换为 state xfs_bmapi_convert_unwritten
+    pub fn bmap_convert(
+        &self,
+        ip: &mut Inode,
+        lblk: u64,
+        len: u64,
+        state: ExtentState,
+    ) -> FsResult<()> {
+        if !ip.convert_range(lblk, len, state) {
+            return Ok(());
+        }
+        self.bmap_sync(ip, lblk, lblk.saturating_add(len))
+    }
+
+    /// 将 lblk 及之后的映射前移 shift 块 xfs_bmap_collapse_extents
+    pub fn bmap_collapse(&self, ip: &mut Inode, lblk: u64, shift: u64) -> FsResult<()> {
+        ip.shift_extents(lblk, shift);
+        self.bmap_sync(ip, lblk - shift, u64::MAX)
+    }
+
+    /// 内存中 [lo, hi) 的映射被修改后，同步 data fork 的格式和 bmbt 中的记录
+    fn bmap_sync(&self, ip: &mut Inode, lo: u64, hi: u64) -> FsResult<()> {
+        let fits = ip.extents.len() <= self.max_inline_extents(&ip.core);
+        match (ip.broot.is_some(), fits) {
+            (false, true) => Ok(()),
+            (false, false) => self.bmap_extents_to_btree(ip),
+            (true, true) => self.bmap_btree_to_extents(ip),
+            (true, false) => self.bmap_update_btree(ip, lo, hi),
+        }
+    }
+
+    /// inline 的 extent 放不下时，把所有 extent 移到新建的 bmbt 中 xfs_bmap_extents_to_btree
+    fn bmap_extents_to_btree(&self, ip: &mut Inode) -> FsResult<()> {
+        if ip.core.data_fork_size(self.superblock.inodesize) < BmdrBlock::HDR_SIZE + 16 {
+            return Err(EFBIG);
+        }
+        let mut alloc = self.bmbt_alloc(ip.ino);
+        let mut tree = Btree::create(self, self.ino_to_agno(ip.ino), &mut alloc)?;
+        if let Err(e) = ip
+            .extents
+            .iter()
+            .try_for_each(|rec| tree.insert(rec, &mut alloc))
+        {
+            let _ = tree.destroy(&mut alloc);
+            return Err(e);
+        }
+        ip.broot = Some(BmdrBlock {
+            level: tree.levels as u16,
+            keys: vec![ip.extents[0].startoff],
+            ptrs: vec![tree.root],
+        });
+        ip.core.format = DINODE_FMT_BTREE;
+        ip.core.nblocks = (ip.core.nblocks as i64 + alloc.blocks) as u64;
+        Ok(())
+    }
+
+    /// extent 又能放进 inode 时释放整棵 bmbt xfs_bmap_btree_to_extents
+    fn bmap_btree_to_extents(&self, ip: &mut Inode) -> FsResult<()> {
+        let broot = ip.broot.take().unwrap();
+        ip.core.format = DINODE_FMT_EXTENTS;
+        let mut alloc = self.bmbt_alloc(ip.ino);
+        let ret = self.bmbt(&broot, ip.ino).destroy(&mut alloc);
+        ip.core.nblocks = (ip.core.nblocks as i64 + alloc.blocks) as u64;
+        ret
+    }
+
+    /// 用内存中的 extent 替换 bmbt 中 key 在 [lo, hi] 之间的记录
+    ///
+    /// lo 之前相邻的那条记录可能被合并或截断，也一起比较
+    fn bmap_update_btree(&self, ip: &mut Inode, lo: u64, hi: u64) -> FsResult<()> {
+        let broot = ip.broot.as_mut().unwrap();
+        let mut tree = Btree::<BmbtRecord>::new(
+            self,
+            broot.ptrs[0],
+            broot.level as u32,
+            self.ino_to_agno(ip.ino),
+        );
+        let start = match lo.checked_sub(1) {
+            Some(prev) => tree.cursor().seek_le(&prev)?.map_or(0, |rec| rec.startoff),
+            None => 0,
+        };
+        let old = tree
+            .range(start..=hi)
+            .collect::<FsResult<Vec<BmbtRecord>>>()?;
+        let new: Vec<BmbtRecord> = ip
+            .extents
+            .iter()
+            .filter(|rec| (start..=hi).contains(&rec.startoff))
+            .copied()
+            .collect();
+
+        let mut alloc = self.bmbt_alloc(ip.ino);
+        let ret = (|| {
+            for rec in old.iter() {
+                if !new.iter().any(|r| r.startoff == rec.startoff) {
+                    tree.delete(&rec.startoff, &mut alloc)?;
+                }
+            }
+            for rec in new.iter() {
+                match old.iter().find(|r| r.startoff == rec.startoff) {
+                    Some(r) if r == rec => {}
+                    Some(_) => tree.update(rec)?,
+                    None => tree.insert(rec, &mut alloc)?,
+                }
+            }
+            Ok(())
+        })();
+        broot.level = tree.levels as u16;
+        broot.keys[0] = ip.extents[0].startoff;
+        broot.ptrs[0] = tree.root;
+        ip.core.nblocks = (ip.core.nblocks as i64 + alloc.blocks) as u64;
+        ret
+    }
+}
@@ -0,0 +1,228 @@
+#[cfg(test)]
+use std::sync::atomic::Ordering;
+
+#[cfg(test)]
+use libc::S_IFREG;
+#[cfg(test)]
+use proptest::prelude::*;
+
+#[cfg(test)]
+use crate::{
+    btree::BtreeRecord,
+    btree_test::{mkfs_and_mount, remount, small_mkfs_option},
+    dstruct::{
+        BmbtRec, BmbtRecord, ExtentState, BMBT_BLOCKCOUNT_BITS, BMBT_STARTBLOCK_BITS,
+        BMBT_STARTOFF_BITS, DINODE_FMT_BTREE, DINODE_FMT_EXTENTS, MAX_BMBT_EXTLEN,
+    },
+    inode::Inode,
+    pound_fs::MountPoint,
+};
+
+/// extent 有序且不重叠，写回后从磁盘读出的映射与内存中一致
+#[cfg(test)]
+fn check_fork(mp: &MountPoint, ip: &mut Inode) {
+    for w in ip.extents.windows(2) {
+        assert!(w[0].startoff + w[0].blockcount <= w[1].startoff);
+    }
+    mp.write_inode(ip).unwrap();
+    mp.flush_inodes().unwrap();
+    let disk = mp.read_inode(ip.ino).unwrap();
+    assert_eq!(disk.extents, ip.extents);
+    assert_eq!(disk.broot, ip.broot);
+    assert_eq!(disk.core.nblocks, ip.core.nblocks);
+}
+
+#[cfg(test)]
+fn write_block(mp: &MountPoint, ip: &mut Inode, lblk: u64) {
+    mp.write_file(ip, lblk * 512, &[lblk as u8; 512]).unwrap();
+}
+
+#[test]
+fn test_bmap_inline() {
+    let mp = mkfs_and_mount("test_bmap_inline.bin", small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut ip = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    mp.write_file(&mut ip, 0, &[1u8; 8 * 512]).unwrap();
+    assert_eq!(ip.extents.len(), 1);
+    assert_eq!(ip.bmap(8), None);
+    assert_eq!(ip.next_mapped(0), None);
+
+    // 在 extent 中间打洞，extent 被拆成两段
+    let (start, _) = ip.bmap(0).unwrap();
+    mp.bunmap(&mut ip, 3, 2).unwrap();
+    assert_eq!(ip.extents.len(), 2);
+    assert_eq!(ip.bmap(3), None);
+    assert_eq!(ip.bmap(5).unwrap().0, start + 5);
+    assert_eq!(ip.next_mapped(3), Some(5));
+    assert_eq!(ip.core.nblocks, 6);
+    assert_eq!(ip.core.format, DINODE_FMT_EXTENTS);
+    check_fork(&mp, &mut ip);
+
+    // 重新写入后与两侧合并
+    write_block(&mp, &mut ip, 3);
+    write_block(&mp, &mut ip, 4);
+    if ip.bmap(3).unwrap().0 == start + 3 {
+        assert_eq!(ip.extents.len(), 1);
+    }
+    check_fork(&mp, &mut ip);
+}
+
+#[test]
+fn test_bmap_btree() {
+    let path = "test_bmap_btree.bin";
+    let mp = mkfs_and_mount(path, small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut ip = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+
+    // 隔一块写一块，每块都是单独的 extent
+    for i in 0..100 {
+        write_block(&mp, &mut ip, i * 2);
+        if i == 4 {
+            assert_eq!(ip.core.format, DINODE_FMT_EXTENTS);
+        }
+    }
+    assert_eq!(ip.extents.len(), 100);
+    assert_eq!(ip.core.format, DINODE_FMT_BTREE);
+    let broot = ip.broot.clone().unwrap();
+    assert_eq!(broot.level, 2);
+    assert_eq!(broot.keys, vec![0]);
+    // bmbt 的块也计入 nblocks
+    assert!(ip.core.nblocks > 100);
+    for i in 0..100 {
+        assert!(ip.bmap(i * 2).is_some());
+        assert!(ip.bmap(i * 2 + 1).is_none());
+    }
+    check_fork(&mp, &mut ip);
+
+    // 填满空洞，再在中间打洞
+    for i in 0..100 {
+        write_block(&mp, &mut ip, i * 2 + 1);
+    }
+    check_fork(&mp, &mut ip);
+    mp.bunmap(&mut ip, 50, 20).unwrap();
+    assert!((50..70).all(|lblk| ip.bmap(lblk).is_none()));
+    assert!(ip.bmap(49).is_some() && ip.bmap(70).is_some());
+    check_fork(&mp, &mut ip);
+    let data = mp.read_file(&ip, 0, 200 * 512).unwrap();
+    for lblk in 0..200 {
+        let expect = if (50..70).contains(&lblk) {
+            0
+        } else {
+            lblk as u8
+        };
+        assert!(data[lblk * 512..(lblk + 1) * 512]
+            .iter()
+            .all(|&b| b == expect));
+    }
+
+    // 重新挂载后从 bmbt 读出所有 extent
+    let extents = ip.extents.clone();
+    drop(mp);
+    let mp = remount(path);
+    let mut ip = mp.iget(ip.ino).unwrap();
+    assert_eq!(ip.extents, extents);
+
+    // 截断后 extent 又能放进 inode，bmbt 的块全部释放
+    mp.truncate(&mut ip, 512).unwrap();
+    assert_eq!(ip.core.format, DINODE_FMT_EXTENTS);
+    assert!(ip.broot.is_none());
+    assert_eq!(ip.core.nblocks, 1);
+    check_fork(&mp, &mut ip);
+    mp.truncate(&mut ip, 0).unwrap();
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks);
+}
+
+/// 字段的取值，边界附近的值占一半
+#[cfg(test)]
+fn field(bits: u32) -> impl Strategy<Value = u64> {
+    let max = (1u64 << bits) - 1;
+    prop_oneof![
+        prop_oneof![Just(0), Just(1), Just(max - 1), Just(max)],
+        0..=max,
+    ]
+}
+
+#[cfg(test)]
+fn bmbt_record() -> impl Strategy<Value = BmbtRecord> {
+    (
+        field(BMBT_STARTOFF_BITS),
+        field(BMBT_STARTBLOCK_BITS),
+        field(BMBT_BLOCKCOUNT_BITS),
+        any::<bool>(),
+    )
+        .prop_map(|(startoff, startblock, blockcount, unwritten)| BmbtRecord {
+            startoff,
+            startblock,
+            blockcount,
+            state: match unwritten {
+                true => ExtentState::ExtUnwritten,
+                false => ExtentState::ExtNorm,
+            },
+        })
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn test_bmbt_rec_roundtrip(rec in bmbt_record()) {
+        let packed = BmbtRec::from(&rec);
+        prop_assert_eq!(BmbtRecord::from(packed), rec);
+        // 最高位是 unwritten 标记
+        prop_assert_eq!(packed.l0 >> 63 == 1, rec.state == ExtentState::ExtUnwritten);
+        prop_assert_eq!(packed.l1 & MAX_BMBT_EXTLEN, rec.blockcount);
+
+        let mut buf = [0xffu8; BmbtRecord::SIZE];
+        rec.encode(&mut buf);
+        prop_assert_eq!(&buf[..8], &packed.l0.to_be_bytes());
+        prop_assert_eq!(&buf[8..], &packed.l1.to_be_bytes());
+        prop_assert_eq!(BmbtRecord::decode(&buf), rec);
+    }
+
+    #[test]
+    fn test_bmbt_rec_fields(startblock in field(BMBT_STARTBLOCK_BITS)) {
+        // startblock 跨越 l0 和 l1，其余字段全 0 时只有 startblock 的位被置上
+        let rec = BmbtRecord {
+            startoff: 0,
+            startblock,
+            blockcount: 0,
+            state: ExtentState::ExtNorm,
+        };
+        let packed = BmbtRec::from(&rec);
+        prop_assert_eq!(packed.l0, startblock >> 43);
+        prop_assert_eq!(packed.l1, startblock << 21);
+    }
+}
+
+#[test]
+fn test_bmbt_rec_layout() {
+    let rec = BmbtRecord {
+        startoff: (1 << BMBT_STARTOFF_BITS) - 1,
+        startblock: 0,
+        blockcount: 0,
+        state: ExtentState::ExtNorm,
+    };
+    assert_eq!(
+        BmbtRec::from(&rec),
+        BmbtRec {
+            l0: !0 >> 1 & !0x1ff,
+            l1: 0
+        }
+    );
+    let rec = BmbtRecord {
+        state: ExtentState::ExtUnwritten,
+        blockcount: MAX_BMBT_EXTLEN,
+        ..rec
+    };
+    assert_eq!(
+        BmbtRec::from(&rec),
+        BmbtRec {
+            l0: !0x1ff,
+            l1: 0x1f_ffff
+        }
+    );
+}
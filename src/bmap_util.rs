@@ -0,0 +1,163 @@
+//! 文件空间的预分配、打洞、清零和折叠，对应 fallocate 的各个模式
+//!
+//! 预分配的块以 unwritten extent 保存，读出为 0，第一次写入时转换为正常的 extent。
+use libc::{
+    EFBIG, EINVAL, EISDIR, ENODEV, EOPNOTSUPP, EPERM, FALLOC_FL_COLLAPSE_RANGE,
+    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE, S_IFREG,
+};
+
+use crate::{
+    dstruct::{timestamp, ExtentState, InodeFlag, MAX_BMBT_EXTLEN},
+    inode::Inode,
+    pound_fs::{FsResult, MountPoint},
+};
+
+// 支持的 fallocate 模式
+const FALLOC_MODES: i32 =
+    FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE | FALLOC_FL_COLLAPSE_RANGE;
+
+impl<'a> MountPoint<'a> {
+    /// 按 mode 处理文件 [offset, offset + len) 的空间 xfs_file_fallocate
+    pub fn fallocate(&self, ip: &mut Inode, mode: i32, offset: u64, len: u64) -> FsResult<()> {
+        if mode & !FALLOC_MODES != 0 {
+            return Err(EOPNOTSUPP);
+        }
+        // 打洞不能改变文件大小
+        if mode & FALLOC_FL_PUNCH_HOLE != 0 && mode & FALLOC_FL_KEEP_SIZE == 0 {
+            return Err(EOPNOTSUPP);
+        }
+        if mode & FALLOC_FL_PUNCH_HOLE != 0 && mode & FALLOC_FL_ZERO_RANGE != 0 {
+            return Err(EINVAL);
+        }
+        if mode & FALLOC_FL_COLLAPSE_RANGE != 0 && mode != FALLOC_FL_COLLAPSE_RANGE {
+            return Err(EINVAL);
+        }
+        if len == 0 {
+            return Err(EINVAL);
+        }
+        let end = offset.checked_add(len).ok_or(EFBIG)?;
+        if ip.is_dir() {
+            return Err(EISDIR);
+        }
+        if ip.core.file_type() != S_IFREG {
+            return Err(ENODEV);
+        }
+        if ip.core.has_flag(InodeFlag::XfsDiflagImmutable) {
+            return Err(EPERM);
+        }
+        // 只能追加的文件不能修改已有的数据
+        if ip.core.has_flag(InodeFlag::XfsDiflagAppend) && mode & !FALLOC_FL_KEEP_SIZE != 0 {
+            return Err(EPERM);
+        }
+
+        // 延迟分配的数据先写回，之后只需要处理已经映射的块
+        self.flush_delalloc(ip)?;
+        match mode & !FALLOC_FL_KEEP_SIZE {
+            0 => self.alloc_file_space(ip, offset, len)?,
+            FALLOC_FL_PUNCH_HOLE => self.free_file_space(ip, offset, len)?,
+            FALLOC_FL_ZERO_RANGE => self.zero_file_space(ip, offset, len)?,
+            _ => self.collapse_file_space(ip, offset, len)?,
+        }
+        if mode & (FALLOC_FL_KEEP_SIZE | FALLOC_FL_COLLAPSE_RANGE) == 0 && end > ip.core.size {
+            ip.core.size = end;
+        }
+        ip.touch();
+        self.write_inode(ip)
+    }
+
+    /// 为 [offset, offset + len) 中的空洞预分配 unwritten extent xfs_alloc_file_space
+    pub fn alloc_file_space(&self, ip: &mut Inode, offset: u64, len: u64) -> FsResult<()> {
+        let blocksize = self.superblock.blocksize as u64;
+        let first = offset / blocksize;
+        let last = (offset + len).div_ceil(blocksize);
+        let new_ranges = self.alloc_range(ip, first, last - first, ExtentState::ExtUnwritten)?;
+        if !new_ranges.is_empty() {
+            ip.core.set_flag(InodeFlag::XfsDiflagPrealloc, true);
+        }
+        Ok(())
+    }
+
+    /// 释放 [offset, offset + len) 中完整的块，两端不完整的块清零 xfs_free_file_space
+    pub fn free_file_space(&self, ip: &mut Inode, offset: u64, len: u64) -> FsResult<()> {
+        let blocksize = self.superblock.blocksize as u64;
+        let end = offset + len;
+        let first = offset.div_ceil(blocksize);
+        let last = end / blocksize;
+        if first >= last {
+            return self.zero_range(ip, offset, len);
+        }
+        self.zero_range(ip, offset, first * blocksize - offset)?;
+        self.zero_range(ip, last * blocksize, end - last * blocksize)?;
+        self.bunmap(ip, first, last - first)
+    }
+
+    /// 将 [offset, offset + len) 清零，整个范围之后都有块可用
+    ///
+    /// 完整的块先释放再预分配，不需要写入数据
+    pub fn zero_file_space(&self, ip: &mut Inode, offset: u64, len: u64) -> FsResult<()> {
+        self.free_file_space(ip, offset, len)?;
+        self.alloc_file_space(ip, offset, len)
+    }
+
+    /// 删除 [offset, offset + len)，之后的数据前移，文件大小相应减小 xfs_collapse_file_space
+    ///
+    /// offset 和 len 必须按块对齐，范围不能到达 EOF
+    pub fn collapse_file_space(&self, ip: &mut Inode, offset: u64, len: u64) -> FsResult<()> {
+        let blocksize = self.superblock.blocksize as u64;
+        if !offset.is_multiple_of(blocksize) || !len.is_multiple_of(blocksize) {
+            return Err(EINVAL);
+        }
+        if offset + len >= ip.core.size {
+            return Err(EINVAL);
+        }
+        let (lblk, shift) = (offset / blocksize, len / blocksize);
+        self.bunmap(ip, lblk, shift)?;
+        self.bmap_collapse(ip, lblk + shift, shift)?;
+        ip.core.size -= len;
+        Ok(())
+    }
+
+    /// 释放 EOF 之后多分配的块，返回是否释放了 xfs_free_eofblocks
+    ///
+    /// fallocate 预分配的和只能追加的文件保留，有 extsize 提示时保留到对齐的位置
+    pub fn free_eofblocks(&self, ip: &mut Inode) -> FsResult<bool> {
+        if ip.core.has_flag(InodeFlag::XfsDiflagPrealloc)
+            || ip.core.has_flag(InodeFlag::XfsDiflagAppend)
+        {
+            return Ok(false);
+        }
+        let blocksize = self.superblock.blocksize as u64;
+        let mut first = ip.core.size.div_ceil(blocksize);
+        let extsize = ip.core.extsize_hint();
+        if extsize > 1 {
+            first = first.next_multiple_of(extsize);
+        }
+        match ip.extents.last() {
+            Some(e) if e.startoff + e.blockcount > first => {
+                let last = e.startoff + e.blockcount;
+                self.bunmap(ip, first, last - first)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// 设置文件的 extsize 提示，以块为单位，0 表示清除 xfs_ioctl_setattr_check_extsize
+    ///
+    /// 只能用于普通文件，已经有数据的文件不能修改，不能超过最大 extent 长度的一半
+    pub fn set_extsize(&self, ip: &mut Inode, extsize: u32) -> FsResult<()> {
+        if ip.core.file_type() != S_IFREG || extsize as u64 > MAX_BMBT_EXTLEN / 2 {
+            return Err(EINVAL);
+        }
+        if extsize as u64 == ip.core.extsize_hint() {
+            return Ok(());
+        }
+        if !ip.extents.is_empty() || self.delayed_blocks(ip.ino) != 0 {
+            return Err(EINVAL);
+        }
+        ip.core.extsize = extsize;
+        ip.core.set_flag(InodeFlag::XfsDiflagExtsize, extsize != 0);
+        ip.core.ctime = timestamp::now();
+        self.write_inode(ip)
+    }
+}
@@ -0,0 +1,245 @@
+#[cfg(test)]
+use std::sync::atomic::Ordering;
+
+#[cfg(test)]
+use libc::{
+    EINVAL, EOPNOTSUPP, FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE,
+    FALLOC_FL_ZERO_RANGE, S_IFREG,
+};
+
+#[cfg(test)]
+use crate::{
+    btree_test::{mkfs_and_mount, remount, small_mkfs_option},
+    dstruct::{ExtentState, InodeFlag, DINODE_FMT_BTREE, MAX_BMBT_EXTLEN},
+    inode::Inode,
+    pound_fs::MountPoint,
+};
+
+#[cfg(test)]
+const BS: u64 = 512;
+
+/// 文件内容与 expect 相同，写回后从磁盘读出的映射与内存中一致
+#[cfg(test)]
+fn check_file(mp: &MountPoint, ip: &mut Inode, expect: &[u8]) {
+    assert_eq!(ip.core.size, expect.len() as u64);
+    assert!(mp.read_file(ip, 0, expect.len()).unwrap() == expect);
+    mp.write_inode(ip).unwrap();
+    mp.flush_inodes().unwrap();
+    let disk = mp.read_inode(ip.ino).unwrap();
+    assert_eq!(disk.extents, ip.extents);
+    assert_eq!(disk.core.nblocks, ip.core.nblocks);
+    // bmbt 的块也计入 nblocks
+    let mapped: u64 = ip.extents.iter().map(|e| e.blockcount).sum();
+    assert_eq!(ip.core.nblocks > mapped, ip.broot.is_some());
+}
+
+#[test]
+fn test_fallocate_prealloc() {
+    let path = "test_fallocate_prealloc.bin";
+    let mp = mkfs_and_mount(path, small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut ip = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+
+    // 预分配的块读出为 0，文件大小随之扩展
+    mp.fallocate(&mut ip, 0, 0, 10 * BS).unwrap();
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks - 10);
+    assert_eq!(ip.core.nblocks, 10);
+    assert!(ip.core.has_flag(InodeFlag::XfsDiflagPrealloc));
+    assert!(ip
+        .extents
+        .iter()
+        .all(|e| e.state == ExtentState::ExtUnwritten));
+    let mut expect = vec![0u8; 10 * BS as usize];
+    check_file(&mp, &mut ip, &expect);
+
+    // 写入的块转换为正常状态，块中其余部分仍是 0
+    let (fsbno, _) = ip.bmap(1).unwrap();
+    mp.write_file(&mut ip, BS + 10, &[7; 100]).unwrap();
+    expect[BS as usize + 10..BS as usize + 110].fill(7);
+    assert_eq!(ip.bmap(1), Some((fsbno, ExtentState::ExtNorm)));
+    assert_eq!(ip.bmap(0).unwrap().1, ExtentState::ExtUnwritten);
+    assert_eq!(ip.bmap(2).unwrap().1, ExtentState::ExtUnwritten);
+    assert_eq!(ip.extents.len(), 3);
+    check_file(&mp, &mut ip, &expect);
+
+    // KEEP_SIZE 在 EOF 之后预分配，已分配的块不受影响
+    mp.fallocate(&mut ip, FALLOC_FL_KEEP_SIZE, 5 * BS, 15 * BS)
+        .unwrap();
+    assert_eq!(ip.core.nblocks, 20);
+    assert_eq!(ip.bmap(1), Some((fsbno, ExtentState::ExtNorm)));
+    check_file(&mp, &mut ip, &expect);
+
+    // 全部写入后与相邻的 extent 重新合并
+    expect = (0..20 * BS).map(|i| (i / BS) as u8).collect();
+    mp.write_file(&mut ip, 0, &expect).unwrap();
+    assert!(ip.extents.iter().all(|e| e.state == ExtentState::ExtNorm));
+    assert!(ip.extents.len() <= 2);
+    assert_eq!(ip.core.nblocks, 20);
+    check_file(&mp, &mut ip, &expect);
+
+    drop(mp);
+    let mp = remount(path);
+    let mut ip = mp.iget(ip.ino).unwrap();
+    check_file(&mp, &mut ip, &expect);
+    mp.truncate(&mut ip, 0).unwrap();
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks);
+}
+
+#[test]
+fn test_fallocate_modes() {
+    let mp = mkfs_and_mount("test_fallocate_modes.bin", small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut ip = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let mut expect: Vec<u8> = (0..20 * BS).map(|i| (i / BS) as u8 + 1).collect();
+    mp.write_file(&mut ip, 0, &expect).unwrap();
+
+    let punch = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+    assert_eq!(
+        mp.fallocate(&mut ip, FALLOC_FL_PUNCH_HOLE, 0, BS).err(),
+        Some(EOPNOTSUPP)
+    );
+    assert_eq!(mp.fallocate(&mut ip, 0x20, 0, BS).err(), Some(EOPNOTSUPP));
+    assert_eq!(
+        mp.fallocate(
+            &mut ip,
+            FALLOC_FL_COLLAPSE_RANGE | FALLOC_FL_KEEP_SIZE,
+            0,
+            BS
+        )
+        .err(),
+        Some(EINVAL)
+    );
+    assert_eq!(mp.fallocate(&mut ip, punch, 0, 0).err(), Some(EINVAL));
+
+    // 打洞只释放完整的块，两端的块中只清零范围内的部分
+    mp.fallocate(&mut ip, punch, 2 * BS + 100, 4 * BS).unwrap();
+    expect[2 * BS as usize + 100..6 * BS as usize + 100].fill(0);
+    assert!((3..6).all(|lblk| ip.bmap(lblk).is_none()));
+    assert!(ip.bmap(2).is_some() && ip.bmap(6).is_some());
+    assert_eq!(ip.core.nblocks, 17);
+    check_file(&mp, &mut ip, &expect);
+    // 块内的打洞
+    mp.fallocate(&mut ip, punch, 7 * BS + 1, 10).unwrap();
+    expect[7 * BS as usize + 1..7 * BS as usize + 11].fill(0);
+    check_file(&mp, &mut ip, &expect);
+
+    // 清零后的范围以预分配的块保留，EOF 之后的部分扩展文件
+    mp.fallocate(&mut ip, FALLOC_FL_ZERO_RANGE, 8 * BS, 2 * BS)
+        .unwrap();
+    expect[8 * BS as usize..10 * BS as usize].fill(0);
+    assert_eq!(ip.bmap(8).unwrap().1, ExtentState::ExtUnwritten);
+    assert_eq!(ip.bmap(9).unwrap().1, ExtentState::ExtUnwritten);
+    check_file(&mp, &mut ip, &expect);
+    mp.fallocate(&mut ip, FALLOC_FL_ZERO_RANGE, 19 * BS + 10, 2 * BS)
+        .unwrap();
+    expect.truncate(19 * BS as usize + 10);
+    expect.resize(21 * BS as usize + 10, 0);
+    assert_eq!(ip.bmap(21).unwrap().1, ExtentState::ExtUnwritten);
+    check_file(&mp, &mut ip, &expect);
+
+    // 折叠删除一段数据，之后的数据前移
+    assert_eq!(
+        mp.fallocate(&mut ip, FALLOC_FL_COLLAPSE_RANGE, BS, 100)
+            .err(),
+        Some(EINVAL)
+    );
+    assert_eq!(
+        mp.fallocate(&mut ip, FALLOC_FL_COLLAPSE_RANGE, 20 * BS, 2 * BS)
+            .err(),
+        Some(EINVAL)
+    );
+    let nblocks = ip.core.nblocks;
+    mp.fallocate(&mut ip, FALLOC_FL_COLLAPSE_RANGE, 10 * BS, 5 * BS)
+        .unwrap();
+    expect.drain(10 * BS as usize..15 * BS as usize);
+    assert_eq!(ip.core.nblocks, nblocks - 5);
+    check_file(&mp, &mut ip, &expect);
+}
+
+#[test]
+fn test_collapse_btree() {
+    // 隔一块写一块，data fork 转换为 bmbt 后再折叠
+    let mp = mkfs_and_mount("test_collapse_btree.bin", small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut ip = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+    let mut expect = vec![0u8; 199 * BS as usize];
+    for lblk in (0..200).step_by(2) {
+        let off = (lblk * BS) as usize;
+        expect[off..off + BS as usize].fill(lblk as u8 + 1);
+        mp.write_file(&mut ip, lblk * BS, &expect[off..off + BS as usize])
+            .unwrap();
+    }
+    assert_eq!(ip.core.format, DINODE_FMT_BTREE);
+
+    mp.fallocate(&mut ip, FALLOC_FL_COLLAPSE_RANGE, 21 * BS, 60 * BS)
+        .unwrap();
+    expect.drain(21 * BS as usize..81 * BS as usize);
+    assert_eq!(ip.extents.len(), 70);
+    check_file(&mp, &mut ip, &expect);
+    // 预分配整个文件后全部写入，空洞被填满
+    mp.fallocate(&mut ip, FALLOC_FL_KEEP_SIZE, 0, expect.len() as u64)
+        .unwrap();
+    check_file(&mp, &mut ip, &expect);
+    mp.write_file(&mut ip, 0, &expect).unwrap();
+    assert!(ip.extents.iter().all(|e| e.state == ExtentState::ExtNorm));
+    check_file(&mp, &mut ip, &expect);
+
+    mp.truncate(&mut ip, 0).unwrap();
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks);
+}
+
+#[test]
+fn test_extsize_hint() {
+    let mp = mkfs_and_mount("test_extsize_hint.bin", small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut ip = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+    let mut dp = mp.iget(rootino).unwrap();
+    assert_eq!(mp.set_extsize(&mut dp, 16).err(), Some(EINVAL));
+    assert_eq!(
+        mp.set_extsize(&mut ip, MAX_BMBT_EXTLEN as u32).err(),
+        Some(EINVAL)
+    );
+    mp.set_extsize(&mut ip, 16).unwrap();
+    assert!(ip.core.has_flag(InodeFlag::XfsDiflagExtsize));
+    assert_eq!(mp.iget(ip.ino).unwrap().core.extsize_hint(), 16);
+
+    // 直接写入时按 16 块对齐分配，多分配的部分为 unwritten
+    mp.write_file(&mut ip, 5 * BS, &[5; BS as usize]).unwrap();
+    assert_eq!(ip.core.nblocks, 16);
+    assert_eq!(ip.bmap(5).unwrap().1, ExtentState::ExtNorm);
+    assert_eq!(ip.bmap(0).unwrap().1, ExtentState::ExtUnwritten);
+    assert_eq!(ip.bmap(15).unwrap().1, ExtentState::ExtUnwritten);
+    assert_eq!(ip.bmap(16), None);
+    let mut expect = vec![0u8; 6 * BS as usize];
+    expect[5 * BS as usize..].fill(5);
+    check_file(&mp, &mut ip, &expect);
+    // 已经有块的文件不能修改提示
+    assert_eq!(mp.set_extsize(&mut ip, 8).err(), Some(EINVAL));
+
+    // 延迟分配写回时同样对齐，对齐的块不会被当作 EOF 之后的块释放
+    mp.buffered_write(&mut ip, 20 * BS, &[7; 10]).unwrap();
+    mp.release(&mut ip).unwrap();
+    assert_eq!(ip.core.nblocks, 32);
+    assert_eq!(ip.bmap(20).unwrap().1, ExtentState::ExtNorm);
+    assert_eq!(ip.bmap(16).unwrap().1, ExtentState::ExtUnwritten);
+    assert_eq!(ip.bmap(31).unwrap().1, ExtentState::ExtUnwritten);
+    expect.resize(20 * BS as usize, 0);
+    expect.extend_from_slice(&[7; 10]);
+    check_file(&mp, &mut ip, &expect);
+
+    mp.truncate(&mut ip, 0).unwrap();
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks);
+    mp.set_extsize(&mut ip, 0).unwrap();
+    assert!(!ip.core.has_flag(InodeFlag::XfsDiflagExtsize));
+}
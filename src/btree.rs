@@ -1,10 +1,36 @@
+//! 磁盘上的 B+树，空闲空间、inode 和 extent 树共用
+//!
+//! 叶子节点在头部之后依次存放记录；中间节点在头部之后存放 key，
+//! 在 `头部 + maxrecs * key 长度` 处存放指向子节点的 fsbno。
+//! 中间节点的第 i 个 key 不大于第 i 个子树中的所有 key。
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt::Debug,
+    marker::PhantomData,
+    mem::size_of,
+    ops::{Bound, RangeBounds},
+};
+
+use libc::{EEXIST, EIO, ENOENT};
 use serde::{Deserialize, Serialize};
 
-use crate::{dstruct::UUID, util::uuid};
+use crate::{
+    block_dev::BlockDevice,
+    dstruct::{Agf, Agi, UUID},
+    pound_fs::{errno, FsResult, MountPoint},
+    util::uuid,
+};
 const BtreeBlockMagicNum: u32 = 0xB5B1A1A1;
+// 没有兄弟节点时 leftSibling/rightSibling 的值
+pub const NULL_BTREE_BLOCK: u64 = u64::MAX;
+// 节点头部的大小，记录和 key 从这里开始
+pub const BTREE_HDR_SIZE: usize = size_of::<BtreeBlock>();
+// 中间节点中指向子节点的指针大小
+const BTREE_PTR_SIZE: usize = size_of::<u64>();
 // https://stackoverflow.com/questions/32428153/how-can-i-align-a-struct-to-a-specified-byte-boundary
 #[repr(align(64))]
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct BtreeBlock {
     /* 8 bytes */
     pub magicnum: u32,    // B+树块的 Magic Number AB3B
@@ -29,9 +55,9 @@ impl BtreeBlock {
             magicnum: BtreeBlockMagicNum,
             level: 0,
             numrecs: 0,
-            leftSibling: 0,
-            rightSibling: 0,
-            blkno: blkno,
+            leftSibling: NULL_BTREE_BLOCK,
+            rightSibling: NULL_BTREE_BLOCK,
+            blkno,
             lsn: 0,
             uuid: uuid(),
             owner: 0,
@@ -43,8 +69,724 @@ impl BtreeBlock {
 // AllocRec 是一个数对。
 // 用来表示空闲空间时，AllocRec 表示每个空闲块的起始块号和长度。
 #[repr(align(8))]
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
 pub struct AllocRec {
     pub startblock: u32, // 当前记录的起始块号
     pub blockcount: u32, // 当前记录的块数
 }
+
+// bno 树以起始块号为 key
+impl BtreeRecord for AllocRec {
+    type Key = u32;
+    const SIZE: usize = 8;
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.startblock.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.blockcount.to_be_bytes());
+    }
+    fn decode(buf: &[u8]) -> Self {
+        AllocRec {
+            startblock: u32::decode(&buf[0..4]),
+            blockcount: u32::decode(&buf[4..8]),
+        }
+    }
+    fn key(&self) -> u32 {
+        self.startblock
+    }
+}
+
+/// cnt 树中的记录，磁盘格式与 AllocRec 相同，以 (块数, 起始块号) 为 key
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct AllocCntRec(pub AllocRec);
+
+impl BtreeRecord for AllocCntRec {
+    type Key = (u32, u32);
+    const SIZE: usize = AllocRec::SIZE;
+    fn encode(&self, buf: &mut [u8]) {
+        self.0.encode(buf)
+    }
+    fn decode(buf: &[u8]) -> Self {
+        AllocCntRec(AllocRec::decode(buf))
+    }
+    fn key(&self) -> (u32, u32) {
+        (self.0.blockcount, self.0.startblock)
+    }
+}
+
+/// B+树的 key，以定长大端序编码，编码后的字节序与 key 的大小顺序一致
+pub trait BtreeKey: Ord + Copy + Debug {
+    const SIZE: usize;
+    fn encode(&self, buf: &mut [u8]);
+    fn decode(buf: &[u8]) -> Self;
+}
+
+impl BtreeKey for u32 {
+    const SIZE: usize = 4;
+    fn encode(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&self.to_be_bytes());
+    }
+    fn decode(buf: &[u8]) -> Self {
+        u32::from_be_bytes(buf[..4].try_into().unwrap())
+    }
+}
+
+impl BtreeKey for u64 {
+    const SIZE: usize = 8;
+    fn encode(&self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.to_be_bytes());
+    }
+    fn decode(buf: &[u8]) -> Self {
+        u64::from_be_bytes(buf[..8].try_into().unwrap())
+    }
+}
+
+impl BtreeKey for (u32, u32) {
+    const SIZE: usize = 8;
+    fn encode(&self, buf: &mut [u8]) {
+        self.0.encode(&mut buf[0..4]);
+        self.1.encode(&mut buf[4..8]);
+    }
+    fn decode(buf: &[u8]) -> Self {
+        (u32::decode(&buf[0..4]), u32::decode(&buf[4..8]))
+    }
+}
+
+/// B+树中的一条记录，叶子节点中按 key 升序存放，key 不重复
+///
+/// 磁盘上的记录格式由 `encode`/`decode` 定义，与编译器和序列化库无关
+pub trait BtreeRecord: Clone {
+    type Key: BtreeKey;
+    /// 编码后的记录长度
+    const SIZE: usize;
+    /// 编码到 `buf[..SIZE]`
+    fn encode(&self, buf: &mut [u8]);
+    /// 从 `buf[..SIZE]` 解码
+    fn decode(buf: &[u8]) -> Self;
+    fn key(&self) -> Self::Key;
+}
+
+/// 节点分裂时申请新块，合并时释放块，块号都是 fsbno
+pub trait BtreeAlloc {
+    fn alloc_block(&mut self) -> FsResult<u64>;
+    fn free_block(&mut self, fsbno: u64) -> FsResult<()>;
+}
+
+enum Items<R: BtreeRecord> {
+    Leaf(Vec<R>),
+    Node(Vec<(R::Key, u64)>),
+}
+
+impl<R: BtreeRecord> Items<R> {
+    fn len(&self) -> usize {
+        match self {
+            Items::Leaf(recs) => recs.len(),
+            Items::Node(ents) => ents.len(),
+        }
+    }
+
+    fn low_key(&self) -> Option<R::Key> {
+        match self {
+            Items::Leaf(recs) => recs.first().map(|r| r.key()),
+            Items::Node(ents) => ents.first().map(|e| e.0),
+        }
+    }
+
+    fn split_off(&mut self, at: usize) -> Self {
+        match self {
+            Items::Leaf(recs) => Items::Leaf(recs.split_off(at)),
+            Items::Node(ents) => Items::Node(ents.split_off(at)),
+        }
+    }
+
+    fn append(&mut self, other: Self) {
+        match (self, other) {
+            (Items::Leaf(recs), Items::Leaf(mut other)) => recs.append(&mut other),
+            (Items::Node(ents), Items::Node(mut other)) => ents.append(&mut other),
+            _ => unreachable!("merging nodes of different levels"),
+        }
+    }
+}
+
+struct Node<R: BtreeRecord> {
+    bno: u64,
+    hdr: BtreeBlock,
+    items: Items<R>,
+}
+
+/// 中间节点中 key 所在的子节点下标
+fn child_index<K: Ord>(ents: &[(K, u64)], key: &K) -> usize {
+    ents.partition_point(|(k, _)| k <= key).saturating_sub(1)
+}
+
+/// 一棵 B+树，根节点和层数由调用者保存在 AGF、AGI 或 inode 中
+pub struct Btree<'a, R: BtreeRecord> {
+    mp: &'a MountPoint<'a>,
+    pub root: u64,   // 根节点的 fsbno
+    pub levels: u32, // 树的层数，只有一个叶子节点时为 1
+    owner: u32,
+    undo: Option<&'a UndoLog>, // 写节点前先保存原来的内容
+    _rec: PhantomData<R>,
+}
+
+/// 被修改的块在修改前的内容，操作失败时写回以撤销对 B+树的修改
+#[derive(Default)]
+pub struct UndoLog {
+    saved: RefCell<Vec<(u64, Vec<u8>)>>,
+    seen: RefCell<HashSet<u64>>,
+}
+
+impl UndoLog {
+    /// 第一次修改 fsbno 之前保存它的内容
+    fn save(&self, mp: &MountPoint, fsbno: u64) -> FsResult<()> {
+        if !self.seen.borrow_mut().insert(fsbno) {
+            return Ok(());
+        }
+        let blocksize = mp.superblock.blocksize as usize;
+        let mut buf = vec![0u8; blocksize];
+        mp.dev
+            .read_all_at(fsbno as usize * blocksize, &mut buf)
+            .map_err(errno)?;
+        self.saved.borrow_mut().push((fsbno, buf));
+        Ok(())
+    }
+
+    /// 把保存的块全部写回
+    pub fn rollback(&self, mp: &MountPoint) -> FsResult<()> {
+        let blocksize = mp.superblock.blocksize as usize;
+        for (fsbno, buf) in self.saved.take().into_iter().rev() {
+            mp.dev
+                .write_all_at(fsbno as usize * blocksize, &buf)
+                .map_err(errno)?;
+        }
+        self.seen.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+impl<'a, R: BtreeRecord> Btree<'a, R> {
+    pub fn new(mp: &'a MountPoint<'a>, root: u64, levels: u32, owner: u32) -> Self {
+        Btree {
+            mp,
+            root,
+            levels,
+            owner,
+            undo: None,
+            _rec: PhantomData,
+        }
+    }
+
+    /// 之后对节点的修改都先记录到 undo 中
+    pub fn with_undo(mut self, undo: &'a UndoLog) -> Self {
+        self.undo = Some(undo);
+        self
+    }
+
+    /// AGF 中的第 btnum 棵树，btnum 为 `AgfBtBno` 或 `AgfBtCnt`
+    pub fn from_agf(mp: &'a MountPoint<'a>, agf: &Agf, btnum: usize) -> Self {
+        let root = mp.agbno_to_fsbno(agf.seqno, agf.roots[btnum]);
+        Btree::new(mp, root, agf.levels[btnum], agf.seqno)
+    }
+
+    /// AGI 中的 inobt
+    pub fn from_agi(mp: &'a MountPoint<'a>, agi: &Agi) -> Self {
+        let root = mp.agbno_to_fsbno(agi.seqno, agi.root);
+        Btree::new(mp, root, agi.level, agi.seqno)
+    }
+
+    /// AGI 中的 finobt
+    pub fn from_agi_free(mp: &'a MountPoint<'a>, agi: &Agi) -> Self {
+        let root = mp.agbno_to_fsbno(agi.seqno, agi.freeRoot);
+        Btree::new(mp, root, agi.freeLevel, agi.seqno)
+    }
+
+    /// 新建一棵只有一个空叶子节点的树
+    pub fn create(
+        mp: &'a MountPoint<'a>,
+        owner: u32,
+        alloc: &mut dyn BtreeAlloc,
+    ) -> FsResult<Self> {
+        let root = alloc.alloc_block()?;
+        let tree = Btree::new(mp, root, 1, owner);
+        tree.write_node(&tree.new_node(root, 0))?;
+        Ok(tree)
+    }
+
+    fn blocksize(&self) -> usize {
+        self.mp.superblock.blocksize as usize
+    }
+
+    /// 第 level 层的节点最多能放多少条记录或 key
+    fn maxrecs(&self, level: u16) -> usize {
+        let space = self.blocksize() - BTREE_HDR_SIZE;
+        if level == 0 {
+            space / R::SIZE
+        } else {
+            space / (R::Key::SIZE + BTREE_PTR_SIZE)
+        }
+    }
+
+    fn minrecs(&self, level: u16) -> usize {
+        self.maxrecs(level) / 2
+    }
+
+    fn new_node(&self, bno: u64, level: u16) -> Node<R> {
+        let mut hdr = BtreeBlock::new(bno);
+        hdr.level = level;
+        hdr.owner = self.owner;
+        hdr.uuid = self.mp.superblock.uuid;
+        let items = if level == 0 {
+            Items::Leaf(Vec::new())
+        } else {
+            Items::Node(Vec::new())
+        };
+        Node { bno, hdr, items }
+    }
+
+    fn read_node(&self, bno: u64) -> FsResult<Node<R>> {
+        let blocksize = self.blocksize();
+        let mut buf = vec![0u8; blocksize];
+        self.mp
+            .dev
+            .read_all_at(bno as usize * blocksize, &mut buf)
+            .map_err(errno)?;
+        let hdr: BtreeBlock = bincode::deserialize(&buf).map_err(|_| EIO)?;
+        let n = hdr.numrecs as usize;
+        if hdr.magicnum != BtreeBlockMagicNum || n > self.maxrecs(hdr.level) {
+            return Err(EIO);
+        }
+        let items = if hdr.level == 0 {
+            let recs = (0..n)
+                .map(|i| R::decode(&buf[BTREE_HDR_SIZE + i * R::SIZE..]))
+                .collect();
+            Items::Leaf(recs)
+        } else {
+            let ptr_base = BTREE_HDR_SIZE + self.maxrecs(hdr.level) * R::Key::SIZE;
+            let ents = (0..n)
+                .map(|i| {
+                    let key = R::Key::decode(&buf[BTREE_HDR_SIZE + i * R::Key::SIZE..]);
+                    let ptr = u64::decode(&buf[ptr_base + i * BTREE_PTR_SIZE..]);
+                    (key, ptr)
+                })
+                .collect();
+            Items::Node(ents)
+        };
+        Ok(Node { bno, hdr, items })
+    }
+
+    fn write_node(&self, node: &Node<R>) -> FsResult<()> {
+        if let Some(undo) = self.undo {
+            undo.save(self.mp, node.bno)?;
+        }
+        let blocksize = self.blocksize();
+        let mut buf = vec![0u8; blocksize];
+        let mut hdr = node.hdr.clone();
+        hdr.blkno = node.bno;
+        hdr.numrecs = node.items.len() as u16;
+        let hdr_buf = bincode::serialize(&hdr).unwrap();
+        buf[..hdr_buf.len()].copy_from_slice(&hdr_buf);
+        match &node.items {
+            Items::Leaf(recs) => {
+                for (i, rec) in recs.iter().enumerate() {
+                    rec.encode(&mut buf[BTREE_HDR_SIZE + i * R::SIZE..]);
+                }
+            }
+            Items::Node(ents) => {
+                let ptr_base = BTREE_HDR_SIZE + self.maxrecs(hdr.level) * R::Key::SIZE;
+                for (i, (key, ptr)) in ents.iter().enumerate() {
+                    key.encode(&mut buf[BTREE_HDR_SIZE + i * R::Key::SIZE..]);
+                    ptr.encode(&mut buf[ptr_base + i * BTREE_PTR_SIZE..]);
+                }
+            }
+        }
+        self.mp
+            .dev
+            .write_all_at(node.bno as usize * blocksize, &buf)
+            .map_err(errno)
+    }
+
+    /// 找到 key 所在的叶子节点
+    fn find_leaf(&self, key: &R::Key) -> FsResult<Node<R>> {
+        self.descend(|ents| child_index(ents, key))
+    }
+
+    /// 从根节点向下走到叶子节点，pick 选择中间节点中的子节点下标
+    fn descend(&self, pick: impl Fn(&[(R::Key, u64)]) -> usize) -> FsResult<Node<R>> {
+        let mut node = self.read_node(self.root)?;
+        loop {
+            let child = match &node.items {
+                Items::Leaf(_) => return Ok(node),
+                Items::Node(ents) if ents.is_empty() => return Err(EIO),
+                Items::Node(ents) => ents[pick(ents)].1,
+            };
+            node = self.read_node(child)?;
+        }
+    }
+
+    pub fn cursor(&self) -> BtreeCursor<'_, 'a, R> {
+        BtreeCursor {
+            tree: self,
+            leaf: None,
+            pos: 0,
+        }
+    }
+
+    /// 按 key 顺序遍历 range 中的记录
+    pub fn range(&self, range: impl RangeBounds<R::Key>) -> BtreeRange<'_, 'a, R> {
+        let end = range.end_bound().cloned();
+        let mut cur = self.cursor();
+        let first = match range.start_bound() {
+            Bound::Included(key) => cur.seek_ge(key),
+            Bound::Excluded(key) => match cur.seek_ge(key) {
+                Ok(Some(rec)) if rec.key() == *key => cur.advance(),
+                other => other,
+            },
+            Bound::Unbounded => cur.first(),
+        };
+        BtreeRange {
+            cur,
+            next: Some(first),
+            end,
+        }
+    }
+
+    pub fn get(&self, key: &R::Key) -> FsResult<Option<R>> {
+        let leaf = self.find_leaf(key)?;
+        let Items::Leaf(recs) = leaf.items else {
+            unreachable!()
+        };
+        Ok(recs
+            .binary_search_by_key(key, |r| r.key())
+            .ok()
+            .map(|i| recs[i].clone()))
+    }
+
+    /// 原地替换 key 相同的记录
+    pub fn update(&self, rec: &R) -> FsResult<()> {
+        let key = rec.key();
+        let mut leaf = self.find_leaf(&key)?;
+        let Items::Leaf(recs) = &mut leaf.items else {
+            unreachable!()
+        };
+        let i = recs
+            .binary_search_by_key(&key, |r| r.key())
+            .map_err(|_| ENOENT)?;
+        recs[i] = rec.clone();
+        self.write_node(&leaf)
+    }
+
+    /// 插入一条新记录，key 已存在时返回 EEXIST
+    pub fn insert(&mut self, rec: &R, alloc: &mut dyn BtreeAlloc) -> FsResult<()> {
+        if let Some((key, bno)) = self.insert_rec(self.root, rec, alloc)? {
+            // 根节点分裂，树长高一层
+            let old_root = self.read_node(self.root)?;
+            let new_root = alloc.alloc_block()?;
+            let mut node = self.new_node(new_root, self.levels as u16);
+            node.items = Items::Node(vec![
+                (old_root.items.low_key().unwrap(), self.root),
+                (key, bno),
+            ]);
+            self.write_node(&node)?;
+            self.root = new_root;
+            self.levels += 1;
+        }
+        Ok(())
+    }
+
+    /// 插入到以 bno 为根的子树中，节点分裂时返回新的右半节点的最小 key 和块号
+    fn insert_rec(
+        &self,
+        bno: u64,
+        rec: &R,
+        alloc: &mut dyn BtreeAlloc,
+    ) -> FsResult<Option<(R::Key, u64)>> {
+        let mut node = self.read_node(bno)?;
+        let key = rec.key();
+        match &mut node.items {
+            Items::Leaf(recs) => match recs.binary_search_by_key(&key, |r| r.key()) {
+                Ok(_) => return Err(EEXIST),
+                Err(i) => recs.insert(i, rec.clone()),
+            },
+            Items::Node(ents) => {
+                if ents.is_empty() {
+                    return Err(EIO);
+                }
+                let i = child_index(ents, &key);
+                let mut dirty = false;
+                if key < ents[i].0 {
+                    ents[i].0 = key;
+                    dirty = true;
+                }
+                match self.insert_rec(ents[i].1, rec, alloc)? {
+                    Some(ent) => ents.insert(i + 1, ent),
+                    None if dirty => {}
+                    None => return Ok(None),
+                }
+            }
+        }
+        if node.items.len() <= self.maxrecs(node.hdr.level) {
+            self.write_node(&node)?;
+            return Ok(None);
+        }
+        self.split(&mut node, alloc).map(Some)
+    }
+
+    /// 把节点的后一半移到新的右兄弟节点中
+    fn split(&self, node: &mut Node<R>, alloc: &mut dyn BtreeAlloc) -> FsResult<(R::Key, u64)> {
+        let new_bno = alloc.alloc_block()?;
+        let mut right = self.new_node(new_bno, node.hdr.level);
+        right.items = node.items.split_off(node.items.len() / 2);
+        right.hdr.leftSibling = node.bno;
+        right.hdr.rightSibling = node.hdr.rightSibling;
+        self.set_left_sibling(node.hdr.rightSibling, new_bno)?;
+        node.hdr.rightSibling = new_bno;
+        self.write_node(node)?;
+        self.write_node(&right)?;
+        Ok((right.items.low_key().unwrap(), new_bno))
+    }
+
+    fn set_left_sibling(&self, bno: u64, left: u64) -> FsResult<()> {
+        if bno != NULL_BTREE_BLOCK {
+            let mut node = self.read_node(bno)?;
+            node.hdr.leftSibling = left;
+            self.write_node(&node)?;
+        }
+        Ok(())
+    }
+
+    /// 删除 key 对应的记录，不存在时返回 ENOENT
+    pub fn delete(&mut self, key: &R::Key, alloc: &mut dyn BtreeAlloc) -> FsResult<()> {
+        self.delete_rec(self.root, key, alloc)?;
+        // 根节点只剩一个子节点时，树降低一层
+        while self.levels > 1 {
+            let root = self.read_node(self.root)?;
+            let child = match &root.items {
+                Items::Node(ents) if ents.len() == 1 => ents[0].1,
+                _ => break,
+            };
+            alloc.free_block(self.root)?;
+            self.root = child;
+            self.levels -= 1;
+        }
+        Ok(())
+    }
+
+    /// 释放树占用的所有块，之后这棵树不能再使用
+    pub fn destroy(self, alloc: &mut dyn BtreeAlloc) -> FsResult<()> {
+        self.destroy_node(self.root, alloc)
+    }
+
+    fn destroy_node(&self, bno: u64, alloc: &mut dyn BtreeAlloc) -> FsResult<()> {
+        if let Items::Node(ents) = self.read_node(bno)?.items {
+            for (_, child) in ents {
+                self.destroy_node(child, alloc)?;
+            }
+        }
+        alloc.free_block(bno)
+    }
+
+    /// 从以 bno 为根的子树中删除，返回节点是否少于半满
+    fn delete_rec(&self, bno: u64, key: &R::Key, alloc: &mut dyn BtreeAlloc) -> FsResult<bool> {
+        let mut node = self.read_node(bno)?;
+        match &mut node.items {
+            Items::Leaf(recs) => {
+                let i = recs
+                    .binary_search_by_key(key, |r| r.key())
+                    .map_err(|_| ENOENT)?;
+                recs.remove(i);
+            }
+            Items::Node(ents) => {
+                if ents.is_empty() {
+                    return Err(EIO);
+                }
+                let i = child_index(ents, key);
+                if !self.delete_rec(ents[i].1, key, alloc)? {
+                    return Ok(false);
+                }
+                self.rebalance(ents, i, alloc)?;
+            }
+        }
+        self.write_node(&node)?;
+        Ok(node.items.len() < self.minrecs(node.hdr.level))
+    }
+
+    /// 第 i 个子节点少于半满，与相邻的兄弟节点合并或者从兄弟节点借一些过来
+    fn rebalance(
+        &self,
+        ents: &mut Vec<(R::Key, u64)>,
+        i: usize,
+        alloc: &mut dyn BtreeAlloc,
+    ) -> FsResult<()> {
+        let (li, ri) = if i + 1 < ents.len() {
+            (i, i + 1)
+        } else if i > 0 {
+            (i - 1, i)
+        } else {
+            return Ok(());
+        };
+        let mut left = self.read_node(ents[li].1)?;
+        let mut right = self.read_node(ents[ri].1)?;
+        let (nl, nr) = (left.items.len(), right.items.len());
+        if nl + nr <= self.maxrecs(left.hdr.level) {
+            // 合并到左节点，释放右节点
+            let items = right.items.split_off(0);
+            left.items.append(items);
+            left.hdr.rightSibling = right.hdr.rightSibling;
+            self.set_left_sibling(right.hdr.rightSibling, left.bno)?;
+            self.write_node(&left)?;
+            alloc.free_block(right.bno)?;
+            ents.remove(ri);
+            return Ok(());
+        }
+        // 两边各留一半
+        let half = (nl + nr) / 2;
+        if nl < half {
+            let rest = right.items.split_off(half - nl);
+            let moved = std::mem::replace(&mut right.items, rest);
+            left.items.append(moved);
+        } else {
+            let mut moved = left.items.split_off(half);
+            moved.append(right.items.split_off(0));
+            right.items = moved;
+        }
+        ents[ri].0 = right.items.low_key().unwrap();
+        self.write_node(&left)?;
+        self.write_node(&right)
+    }
+}
+
+/// 指向叶子节点中某条记录的游标，沿兄弟指针前后移动
+///
+/// 树被修改后游标失效，需要重新 seek
+pub struct BtreeCursor<'t, 'a, R: BtreeRecord> {
+    tree: &'t Btree<'a, R>,
+    leaf: Option<Node<R>>,
+    pos: usize,
+}
+
+impl<'t, 'a, R: BtreeRecord> BtreeCursor<'t, 'a, R> {
+    /// 游标当前指向的记录
+    pub fn get(&self) -> Option<R> {
+        match &self.leaf {
+            Some(Node {
+                items: Items::Leaf(recs),
+                ..
+            }) => recs.get(self.pos).cloned(),
+            _ => None,
+        }
+    }
+
+    fn leaf_len(&self) -> usize {
+        self.leaf.as_ref().map_or(0, |leaf| leaf.items.len())
+    }
+
+    /// 跳过空的叶子节点，直到游标指向一条记录
+    fn settle_forward(&mut self) -> FsResult<Option<R>> {
+        while let Some(leaf) = &self.leaf {
+            if self.pos < leaf.items.len() {
+                return Ok(self.get());
+            }
+            let right = leaf.hdr.rightSibling;
+            self.leaf = match right {
+                NULL_BTREE_BLOCK => None,
+                bno => Some(self.tree.read_node(bno)?),
+            };
+            self.pos = 0;
+        }
+        Ok(None)
+    }
+
+    /// pos 为 0 时表示指向当前叶子节点之前，向左移动到上一条记录
+    fn settle_backward(&mut self) -> FsResult<Option<R>> {
+        while let Some(leaf) = &self.leaf {
+            if self.pos > 0 {
+                self.pos -= 1;
+                return Ok(self.get());
+            }
+            let left = leaf.hdr.leftSibling;
+            self.leaf = match left {
+                NULL_BTREE_BLOCK => None,
+                bno => Some(self.tree.read_node(bno)?),
+            };
+            self.pos = self.leaf_len();
+        }
+        Ok(None)
+    }
+
+    /// 指向第一条 key 不小于 key 的记录
+    pub fn seek_ge(&mut self, key: &R::Key) -> FsResult<Option<R>> {
+        let leaf = self.tree.find_leaf(key)?;
+        self.pos = match &leaf.items {
+            Items::Leaf(recs) => recs.partition_point(|r| r.key() < *key),
+            Items::Node(_) => unreachable!(),
+        };
+        self.leaf = Some(leaf);
+        self.settle_forward()
+    }
+
+    /// 指向最后一条 key 不大于 key 的记录
+    pub fn seek_le(&mut self, key: &R::Key) -> FsResult<Option<R>> {
+        let leaf = self.tree.find_leaf(key)?;
+        self.pos = match &leaf.items {
+            Items::Leaf(recs) => recs.partition_point(|r| r.key() <= *key),
+            Items::Node(_) => unreachable!(),
+        };
+        self.leaf = Some(leaf);
+        self.settle_backward()
+    }
+
+    /// 指向 key 最小的记录
+    pub fn first(&mut self) -> FsResult<Option<R>> {
+        self.leaf = Some(self.tree.descend(|_| 0)?);
+        self.pos = 0;
+        self.settle_forward()
+    }
+
+    /// 指向 key 最大的记录
+    pub fn last(&mut self) -> FsResult<Option<R>> {
+        self.leaf = Some(self.tree.descend(|ents| ents.len() - 1)?);
+        self.pos = self.leaf_len();
+        self.settle_backward()
+    }
+
+    /// 移动到下一条记录，已经在末尾时返回 None
+    pub fn advance(&mut self) -> FsResult<Option<R>> {
+        if self.leaf.is_none() {
+            return Ok(None);
+        }
+        self.pos += 1;
+        self.settle_forward()
+    }
+
+    /// 移动到上一条记录，已经在开头时返回 None
+    pub fn prev(&mut self) -> FsResult<Option<R>> {
+        self.settle_backward()
+    }
+}
+
+/// `Btree::range` 返回的迭代器
+pub struct BtreeRange<'t, 'a, R: BtreeRecord> {
+    cur: BtreeCursor<'t, 'a, R>,
+    next: Option<FsResult<Option<R>>>,
+    end: Bound<R::Key>,
+}
+
+impl<'t, 'a, R: BtreeRecord> Iterator for BtreeRange<'t, 'a, R> {
+    type Item = FsResult<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rec = match self.next.take()? {
+            Ok(Some(rec)) => rec,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+        let in_range = match &self.end {
+            Bound::Included(end) => rec.key() <= *end,
+            Bound::Excluded(end) => rec.key() < *end,
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            return None;
+        }
+        self.next = Some(self.cur.advance());
+        Some(Ok(rec))
+    }
+}
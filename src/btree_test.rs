@@ -1,198 +1,274 @@
-use std::mem::size_of;
+#[cfg(test)]
+use std::collections::BTreeSet;
 
-use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
+#[cfg(test)]
+use libc::{EEXIST, ENOENT};
+#[cfg(test)]
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 
+#[cfg(test)]
 use crate::{
-    block_dev::BlockDevice,
-    btree::{AllocRec, BtreeBlock},
+    btree::{AllocRec, Btree, BtreeAlloc, BtreeKey, BtreeRecord},
+    dstruct::{AgfBtBno, AgfBtCnt, BmbtRecord, ExtentState, InodeBtreeRecord, SuperBlock},
     file_blk::FileBlockDevice,
+    pound_fs::{make_fs, mount, FsResult, MkfsOption, MountPoint},
 };
 
-pub struct BtreeOperator<TKey, TVal> {
-    dev: Box<dyn BlockDevice>,
-    btroot_block: u64,
-    __type_key: std::marker::PhantomData<TKey>,
-    __type_val: std::marker::PhantomData<TVal>,
+/// 从设备开头依次分配块，记录哪些块正在使用
+#[cfg(test)]
+#[derive(Default)]
+pub struct TestAlloc {
+    pub next: u64,
+    pub used: BTreeSet<u64>,
 }
-impl<'de, TKey, TVal> BtreeOperator<TKey, TVal>
-where
-    TKey: DeserializeOwned + PartialOrd,
-    TVal: DeserializeOwned + Serialize,
-{
-    pub fn new(dev: Box<dyn BlockDevice>, btroot_block: u64) -> Self {
-        BtreeOperator {
-            dev,
-            btroot_block,
-            __type_key: std::marker::PhantomData,
-            __type_val: std::marker::PhantomData,
-        }
-    }
-
-    pub fn create_btree(&self) -> () {
-        let new_node = BtreeBlock::new(0);
-        let buf_node = bincode::serialize(&new_node).unwrap();
-        self.dev.write_block(new_node.blkno as usize, &buf_node);
-    }
-
-    pub fn get<'a>(&self, key: &TKey) -> Option<TVal> {
-        let mut buf = [0u8; 512];
-        // 读一个 block
-        self.dev.read_block(self.btroot_block as usize, &mut buf);
-        // -- node 为 block 的头部
-        let node: BtreeBlock = bincode::deserialize(&buf).unwrap();
-        // -- recs 为 block 的剩余部分
-        let opt = self.find_rec_in_block(&node, &buf, key);
-        match opt {
-            Some(rec) => {
-                if rec.1 == *key {
-                    Some(rec.2)
-                } else {
-                    None
-                }
-            }
-            None => None,
-        }
-    }
-
-    pub fn set(&self, key: &TKey, value: &TVal) -> bool {
-        let mut buf = [0u8; 512];
-        // 读一个 block
-        self.dev.read_block(self.btroot_block as usize, &mut buf);
-        // -- node 为 block 的头部
-        let mut header: BtreeBlock = bincode::deserialize(&buf).unwrap();
-        // -- recs 为 block 的剩余部分
-        let ret = self.find_rec_in_block(&header, &buf, key);
-        // 无记录
-        if ret.is_none() {
-            // 追加到末尾
-            let off_new_rec_opt = self.get_rec_off(4096, header.numrecs as usize);
-            if off_new_rec_opt.is_none() {
-                // TODO: 更复杂的 b+tree 操作
-                return true;
-            }
-            let off_new_rec = off_new_rec_opt.unwrap();
-            let rec_buf = bincode::serialize(value).unwrap();
-            // update buf with new rec
-            let s_rec = size_of::<TVal>();
-            buf[off_new_rec as usize..off_new_rec + s_rec].copy_from_slice(&rec_buf);
-            // update header
-            header.numrecs += 1;
-            let buf_hdr = bincode::serialize(&header).unwrap();
-            buf[..size_of::<BtreeBlock>()].copy_from_slice(&buf_hdr);
-            self.dev.write_block(self.btroot_block as usize, &buf);
-            return true;
-        } else {
-            // 未找到
-            let last_rec = ret.unwrap();
-            if last_rec.1 == *key  {
-                println!("found record");
-                // TODO: update record with new value
-            }
-            // 可插入或追加
-            if last_rec.0 <= header.numrecs - 1 {
-                let rec_no = last_rec.0 + 1;
-                let rec_off = self.get_rec_off(4096, rec_no as usize).unwrap();
-                let rec_buf = bincode::serialize(value).unwrap();
-                // update buf with new rec
-                let s_rec = size_of::<TVal>();
-                let n_rec_after = header.numrecs - rec_no;
-                let s_rec_after = s_rec * n_rec_after as usize;
-                // -- move recs after new rec
-                // buf[rec_off as usize + s_rec..rec_off as usize + s_rec_after+ s_rec].copy_from_slice(&buf[rec_off as usize..rec_off as usize + s_rec_after]);
-                buf.copy_within(
-                    rec_off as usize..rec_off as usize + s_rec_after,
-                    rec_off as usize + s_rec,
-                );
-                buf[rec_off as usize..rec_off + s_rec].copy_from_slice(&rec_buf);
-                // update header
-                header.numrecs += 1;
-                let buf_hdr = bincode::serialize(&header).unwrap();
-                buf[..size_of::<BtreeBlock>()].copy_from_slice(&buf_hdr);
-                self.dev.write_block(self.btroot_block as usize, &buf);
-                return true;
-            } else {
-                assert_eq!("", "unreachable");
-            }
-
-            return true;
-        }
-    }
-    /// 获取第 rec_no 个记录的字节偏移
-    pub fn get_rec_off(&self, blocksize: usize, rec_no: usize) -> Option<usize> {
-        let sz_recs = size_of::<TVal>();
-        let sz_header = size_of::<BtreeBlock>();
-        let off_new_rec = sz_header + sz_recs * rec_no as usize;
-        if off_new_rec > blocksize {
-            return None;
-        }
-        return Some(off_new_rec);
-    }
-    /// 读取与 key 匹配的记录, 或者在 key 之前最近的记录. 如果未找到, 则返回 None
-    pub fn find_rec_in_block(
-        &self,
-        header: &BtreeBlock,
-        buf: &[u8],
-        key: &TKey,
-    ) -> Option<(u16, TKey, TVal)> {
-        let n_recs = header.numrecs;
-        if n_recs == 0 {
-            return None;
-        }
-        let off_buf = size_of::<BtreeBlock>();
-        let size_rec = size_of::<TVal>();
-        let mut rec_offset = off_buf;
-        let key_size = size_of::<TKey>();
-        let mut last_rec: Option<(u16, TKey, TVal)> = Option::None;
-        for i in 0..n_recs {
-            let rec: TVal = bincode::deserialize(&buf[rec_offset as usize..]).unwrap();
-            let rec_key: TKey =
-                bincode::deserialize(&buf[rec_offset as usize..rec_offset + key_size]).unwrap();
-            if rec_key > *key {
-                break;
-            }
-            // 如果找到了，就返回
-            if rec_key == *key {
-                return Option::Some((i, rec_key, rec));
-            }
-            // 如果当前记录的 key 小于搜索 key, 说明搜索 key 所对应的记录要么没有, 要么在之后
-            // 所以先把当前记录存起来, 这样万一后面没有, 就先返回当前记录
-            if rec_key < *key {
-                last_rec = Option::Some((i, rec_key, rec));
-            }
-            rec_offset += size_rec;
-        }
-        last_rec
-    }
-
-    // pub fn decode_recs<Tkey, TRec>(
-    //     &self,
-    //     node_hdr: &Option<BtreeBlock>,
-    //     buf: &[u8],
-    // ) -> Vec<(Tkey, TRec)> {
-    //     let mut recs = Vec::new();
-    //     if node_hdr.is_none() {
-    //         return recs;
-    //     }
-    //     let node = node_hdr;
-    //     let mut offset = 0;
-    //     for _ in 0..node.numrecs {}
-    //     recs
-    // }
+
+#[cfg(test)]
+impl BtreeAlloc for TestAlloc {
+    fn alloc_block(&mut self) -> FsResult<u64> {
+        self.next += 1;
+        self.used.insert(self.next);
+        Ok(self.next)
+    }
+
+    fn free_block(&mut self, fsbno: u64) -> FsResult<()> {
+        assert!(self.used.remove(&fsbno), "block {} freed twice", fsbno);
+        Ok(())
+    }
+}
+
+/// 块大小为 512 的空白文件系统，每个叶子节点只能放 56 条 AllocRec
+#[cfg(test)]
+pub fn make_test_mp(path: &str, blocks: usize) -> MountPoint<'static> {
+    let mut sb = SuperBlock::new();
+    sb.blocksize = 512;
+    sb.blocksize_bits = 9;
+    sb.dblocks = blocks as u32;
+    make_raw_mp(path, sb)
+}
+
+/// 不经过 mkfs，直接用 sb 在大小为 dblocks 块的空白镜像上构造 MountPoint
+#[cfg(test)]
+pub fn make_raw_mp(path: &str, sb: SuperBlock) -> MountPoint<'static> {
+    let size = sb.dblocks as usize * sb.blocksize as usize;
+    let dev = FileBlockDevice::create(path, size).unwrap();
+    MountPoint::new(Box::new(dev), sb)
+}
+
+/// 测试用的 mkfs 参数：50MB，AG 大小 8192 块，块大小 4096
+#[cfg(test)]
+pub fn test_mkfs_option() -> MkfsOption {
+    MkfsOption {
+        size: 50 << 20,
+        agblocks: 8192,
+        blocksize: 4096,
+        ..Default::default()
+    }
+}
+
+/// 块大小 512、inode 大小 256 的 16MB 文件系统
+///
+/// inode 中只能内联 5 个 extent，bmbt 的叶子节点放 28 条记录
+#[cfg(test)]
+pub fn small_mkfs_option() -> MkfsOption {
+    MkfsOption {
+        size: 16 << 20,
+        blocksize: 512,
+        inodesize: 256,
+        ..test_mkfs_option()
+    }
+}
+
+/// 按 opt 在 path 处创建 opt.size 大小的镜像，格式化后挂载
+#[cfg(test)]
+pub fn mkfs_and_mount(path: &str, opt: MkfsOption) -> MountPoint<'static> {
+    let dev = FileBlockDevice::create(path, opt.size).unwrap();
+    make_fs(Box::new(dev), opt).unwrap();
+    remount(path)
+}
+
+/// 重新挂载 path 处的镜像
+#[cfg(test)]
+pub fn remount(path: &str) -> MountPoint<'static> {
+    mount(Box::new(FileBlockDevice::new(path).unwrap())).unwrap()
+}
+
+#[cfg(test)]
+fn rec(i: u32) -> AllocRec {
+    AllocRec {
+        startblock: i * 2,
+        blockcount: i,
+    }
 }
 
 #[test]
 fn test_btree() {
-    let dev_size = 10 * 1024; // 10KB
-    let bto: BtreeOperator<[u8; 8], [u8; 16]> = BtreeOperator::new(
-        Box::new(FileBlockDevice::create("test_btree.bin", dev_size)),
-        0,
+    let mp = make_test_mp("test_btree.bin", 1024);
+    let mut alloc = TestAlloc::default();
+    let mut tree = Btree::<AllocRec>::create(&mp, 0, &mut alloc).unwrap();
+    let mut keys: Vec<u32> = (0..5000).collect();
+    keys.shuffle(&mut StdRng::seed_from_u64(1));
+    for &i in keys.iter() {
+        tree.insert(&rec(i), &mut alloc).unwrap();
+    }
+    assert_eq!(tree.levels, 3);
+    assert_eq!(tree.insert(&rec(7), &mut alloc).err(), Some(EEXIST));
+    for i in 0..5000 {
+        assert_eq!(tree.get(&(i * 2)).unwrap(), Some(rec(i)));
+        assert_eq!(tree.get(&(i * 2 + 1)).unwrap(), None);
+    }
+
+    tree.update(&AllocRec {
+        startblock: 20,
+        blockcount: 99,
+    })
+    .unwrap();
+    assert_eq!(tree.get(&20).unwrap().unwrap().blockcount, 99);
+    assert_eq!(
+        tree.update(&AllocRec {
+            startblock: 21,
+            blockcount: 1
+        })
+        .err(),
+        Some(ENOENT)
     );
-    bto.create_btree();
-    for i in 0..10 {
-        let v = (i..i + 16).collect::<Vec<_>>();
-        bto.set(
-            &v[..8].try_into().expect(""),
-            &v[..16].try_into().expect(""),
-        );
+
+    // 删除一半后剩下的记录仍然可以找到
+    keys.shuffle(&mut StdRng::seed_from_u64(2));
+    let (gone, kept) = keys.split_at(2500);
+    for &i in gone {
+        tree.delete(&(i * 2), &mut alloc).unwrap();
+    }
+    assert_eq!(tree.delete(&(gone[0] * 2), &mut alloc).err(), Some(ENOENT));
+    for &i in gone {
+        assert_eq!(tree.get(&(i * 2)).unwrap(), None);
+    }
+    for &i in kept.iter().filter(|&&i| i != 10) {
+        assert_eq!(tree.get(&(i * 2)).unwrap(), Some(rec(i)));
     }
+
+    // 全部删除后只剩一个空的根节点
+    for &i in kept {
+        tree.delete(&(i * 2), &mut alloc).unwrap();
+    }
+    assert_eq!(tree.levels, 1);
+    assert_eq!(alloc.used, BTreeSet::from([tree.root]));
+    assert_eq!(tree.get(&0).unwrap(), None);
+}
+
+#[test]
+fn test_btree_reopen() {
+    let mp = make_test_mp("test_btree_reopen.bin", 256);
+    let mut alloc = TestAlloc::default();
+    let mut tree = Btree::<AllocRec>::create(&mp, 0, &mut alloc).unwrap();
+    for i in 0..200 {
+        tree.insert(&rec(i), &mut alloc).unwrap();
+    }
+    // 只凭根节点和层数就能重新打开
+    let tree = Btree::<AllocRec>::new(&mp, tree.root, tree.levels, 0);
+    assert_eq!(tree.levels, 2);
+    for i in 0..200 {
+        assert_eq!(tree.get(&(i * 2)).unwrap(), Some(rec(i)));
+    }
+}
+
+#[test]
+fn test_btree_record_codec() {
+    // 大端序、定长编码
+    let mut buf = [0u8; 8];
+    rec(3).encode(&mut buf);
+    assert_eq!(buf, [0, 0, 0, 6, 0, 0, 0, 3]);
+    assert_eq!(AllocRec::decode(&buf), rec(3));
+
+    let irec = InodeBtreeRecord {
+        startino: 0x1234_5678,
+        holemask: 0x00ff,
+        count: 48,
+        freecount: 10,
+        free: 0x8000_0000_0000_0001,
+    };
+    let mut buf = [0u8; InodeBtreeRecord::SIZE];
+    irec.encode(&mut buf);
+    assert_eq!(&buf[..8], &[0x12, 0x34, 0x56, 0x78, 0x00, 0xff, 48, 10]);
+    assert_eq!(InodeBtreeRecord::decode(&buf), irec);
+    assert_eq!(irec.key(), 0x1234_5678);
+
+    let brec = BmbtRecord {
+        startoff: 1 << 40,
+        startblock: 77,
+        blockcount: 5,
+        state: ExtentState::ExtUnwritten,
+    };
+    let mut buf = [0xffu8; BmbtRecord::SIZE];
+    brec.encode(&mut buf);
+    assert_eq!(BmbtRecord::decode(&buf), brec);
+    assert_eq!(brec.key(), 1 << 40);
+
+    // key 的编码保持大小顺序
+    let (mut a, mut b) = ([0u8; 8], [0u8; 8]);
+    (1u32, 0xffu32).encode(&mut a);
+    (2u32, 0u32).encode(&mut b);
+    assert!(a < b);
+    assert_eq!(<(u32, u32)>::decode(&a), (1, 0xff));
+}
+
+#[test]
+fn test_btree_cursor() {
+    let mp = make_test_mp("test_btree_cursor.bin", 1024);
+    let mut alloc = TestAlloc::default();
+    let mut tree = Btree::<AllocRec>::create(&mp, 0, &mut alloc).unwrap();
+    let mut keys: Vec<u32> = (0..3000).collect();
+    keys.shuffle(&mut StdRng::seed_from_u64(3));
+    for &i in keys.iter() {
+        tree.insert(&rec(i), &mut alloc).unwrap();
+    }
+    // 删掉一部分，让节点发生合并
+    for i in (0..3000).filter(|i| i % 3 == 0) {
+        tree.delete(&(i * 2), &mut alloc).unwrap();
+    }
+    let expect: Vec<u32> = (0..3000).filter(|i| i % 3 != 0).map(|i| i * 2).collect();
+
+    let all: Vec<u32> = tree.range(..).map(|r| r.unwrap().startblock).collect();
+    assert_eq!(all, expect);
+    let some: Vec<u32> = tree.range(100..=200).map(|r| r.unwrap().startblock).collect();
+    let want: Vec<u32> = expect.iter().copied().filter(|k| (100..=200).contains(k)).collect();
+    assert_eq!(some, want);
+    assert_eq!(tree.range(4..4).count(), 0);
+
+    let mut cur = tree.cursor();
+    assert_eq!(cur.seek_ge(&7).unwrap().unwrap().startblock, 8);
+    assert_eq!(cur.advance().unwrap().unwrap().startblock, 10);
+    assert_eq!(cur.seek_le(&7).unwrap().unwrap().startblock, 4);
+    assert_eq!(cur.prev().unwrap().unwrap().startblock, 2);
+    assert_eq!(cur.prev().unwrap(), None);
+    assert_eq!(cur.seek_le(&1).unwrap(), None);
+    assert_eq!(cur.seek_ge(&6000).unwrap(), None);
+
+    // 从最后一条记录沿左兄弟指针走回开头
+    let mut back = vec![cur.last().unwrap().unwrap().startblock];
+    while let Some(r) = cur.prev().unwrap() {
+        back.push(r.startblock);
+    }
+    back.reverse();
+    assert_eq!(back, expect);
+}
+
+#[test]
+fn test_btree_from_agf() {
+    let mp = mkfs_and_mount("test_btree_from_agf.bin", test_mkfs_option());
+    let prealloc = mp.ag_prealloc_blocks() as u32;
+    let agf = mp.perag[1].agf.lock().unwrap().clone();
+    let bno = Btree::<AllocRec>::from_agf(&mp, &agf, AgfBtBno);
+    let recs: Vec<AllocRec> = bno.range(..).map(|r| r.unwrap()).collect();
+    assert_eq!(
+        recs,
+        vec![AllocRec {
+            startblock: prealloc,
+            blockcount: 4608 - prealloc
+        }]
+    );
+    let cnt = Btree::<AllocRec>::from_agf(&mp, &agf, AgfBtCnt);
+    assert_eq!(cnt.cursor().first().unwrap(), Some(recs[0]));
+    let agi = mp.perag[1].agi.lock().unwrap().clone();
+    let ino = Btree::<InodeBtreeRecord>::from_agi(&mp, &agi);
+    assert_eq!(ino.range(..).count(), 0);
 }
@@ -0,0 +1,316 @@
+//! 缓冲写的延迟分配
+//!
+//! 写入空洞时不立即分配块，只从 fdblocks 中预留空间，数据保存在内存中。
+//! 写回时把连续的一段尽量分配为一个 extent，小块追加写入的文件在磁盘上也是连续的。
+//! 追加到 EOF 时还会多分配一些 unwritten 块，关闭文件或空间不足时再释放。
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use libc::{EIO, ENOENT, ENOSPC};
+
+use crate::{
+    alloc::AllocArgs,
+    block_dev::BlockDevice,
+    dstruct::{AbsInoNo, ExtentState, MAX_BMBT_EXTLEN},
+    inode::Inode,
+    pound_fs::{errno, FsResult, MountPoint},
+};
+
+// 延迟分配的块超过这个数目时立即写回
+pub const DELALLOC_MAX_BLOCKS: u64 = 4096;
+
+/// 还没有分配块的文件数据，每个块都已在 fdblocks 中预留
+#[derive(Default)]
+pub struct DelallocCache {
+    files: HashMap<AbsInoNo, BTreeMap<u64, Vec<u8>>>, // ino -> 文件逻辑块号 -> 块的内容
+    blocks: u64,                                      // 所有文件延迟分配的块数 m_delalloc_blks
+    eofblocks: HashSet<AbsInoNo>,                     // EOF 之后有推测性预分配的块的文件
+}
+
+impl<'a> MountPoint<'a> {
+    /// 缓冲写：已分配的块直接写入，空洞中的数据先放在内存中，写回时再分配 xfs_file_buffered_write
+    pub fn buffered_write(&self, ip: &mut Inode, offset: u64, data: &[u8]) -> FsResult<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let blocksize = self.superblock.blocksize as u64;
+        let end = offset + data.len() as u64;
+        let (first, last) = (offset / blocksize, (end - 1) / blocksize);
+        let mut guard = self.delalloc.lock().unwrap();
+        let cache = &mut *guard;
+        // 先预留所有新的延迟分配块，空间不足时什么都不写
+        let count_new = |cache: &DelallocCache, ip: &Inode| {
+            let bufs = cache.files.get(&ip.ino);
+            (first..=last)
+                .filter(|lblk| {
+                    ip.bmap(*lblk).is_none() && !bufs.is_some_and(|b| b.contains_key(lblk))
+                })
+                .count() as u64
+        };
+        let mut new = count_new(cache, ip);
+        let mut reserved = self.mod_fdblocks(-(new as i64), false);
+        if reserved == Err(ENOSPC) && !cache.eofblocks.is_empty() {
+            // 空间不足时先回收 EOF 之后预分配的块再试一次，当前文件被回收的块需要重新预留
+            self.reclaim_eofblocks(cache, ip)?;
+            new = count_new(cache, ip);
+            reserved = self.mod_fdblocks(-(new as i64), false);
+        }
+        reserved?;
+        let bufs = cache.files.entry(ip.ino).or_default();
+        let mut ret = Ok(());
+        let mut pos = offset;
+        let mut inserted = 0;
+        while ret.is_ok() && pos < end {
+            let lblk = pos / blocksize;
+            let blk_off = pos % blocksize;
+            let len = (blocksize - blk_off).min(end - pos);
+            let src = &data[(pos - offset) as usize..(pos - offset + len) as usize];
+            if ip.bmap(lblk).is_some() {
+                ret = self.write_direct(ip, pos, src).map(|_| ());
+            } else {
+                let buf = bufs.entry(lblk).or_insert_with(|| {
+                    inserted += 1;
+                    vec![0u8; blocksize as usize]
+                });
+                buf[blk_off as usize..(blk_off + len) as usize].copy_from_slice(src);
+            }
+            pos += len;
+        }
+        cache.blocks += inserted;
+        if let Err(e) = ret {
+            // 没有用到的预留归还
+            let _ = self.mod_fdblocks((new - inserted) as i64, true);
+            if bufs.is_empty() {
+                cache.files.remove(&ip.ino);
+            }
+            return Err(e);
+        }
+        if bufs.is_empty() {
+            cache.files.remove(&ip.ino);
+        }
+        if end > ip.core.size {
+            ip.core.size = end;
+        }
+        ip.touch();
+        self.write_inode(ip)?;
+
+        if cache.blocks > DELALLOC_MAX_BLOCKS {
+            self.flush_delalloc_locked(cache)?;
+            *ip = self.iget(ip.ino)?;
+        }
+        Ok(data.len())
+    }
+
+    /// 文件中延迟分配的块数，stat 时计入文件占用的块
+    pub fn delayed_blocks(&self, ino: AbsInoNo) -> u64 {
+        let cache = self.delalloc.lock().unwrap();
+        cache.files.get(&ino).map_or(0, |bufs| bufs.len() as u64)
+    }
+
+    /// 用延迟分配的数据覆盖 data 中对应的部分，data 为文件 [offset, offset + data.len()) 的内容
+    pub fn delalloc_read(&self, ino: AbsInoNo, offset: u64, data: &mut [u8]) {
+        let cache = self.delalloc.lock().unwrap();
+        let Some(bufs) = cache.files.get(&ino) else {
+            return;
+        };
+        let blocksize = self.superblock.blocksize as u64;
+        let end = offset + data.len() as u64;
+        let range = offset / blocksize..end.div_ceil(blocksize);
+        for (&lblk, buf) in bufs.range(range) {
+            let from = (lblk * blocksize).max(offset);
+            let to = ((lblk + 1) * blocksize).min(end);
+            data[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                &buf[(from - lblk * blocksize) as usize..(to - lblk * blocksize) as usize],
+            );
+        }
+    }
+
+    /// 文件截断到 size 时丢弃 EOF 之后延迟分配的块并归还预留，最后一个块中 EOF 之后的部分清零
+    pub fn delalloc_truncate(&self, ino: AbsInoNo, size: u64) {
+        let mut cache = self.delalloc.lock().unwrap();
+        let Some(bufs) = cache.files.get_mut(&ino) else {
+            return;
+        };
+        let blocksize = self.superblock.blocksize as u64;
+        let dropped = bufs.split_off(&size.div_ceil(blocksize)).len() as u64;
+        if let Some(buf) = bufs.get_mut(&(size / blocksize)) {
+            buf[(size % blocksize) as usize..].fill(0);
+        }
+        if bufs.is_empty() {
+            cache.files.remove(&ino);
+        }
+        cache.blocks -= dropped;
+        let _ = self.mod_fdblocks(dropped as i64, true);
+    }
+
+    /// 关闭文件时写回延迟分配的数据，并释放 EOF 之后多分配的块 xfs_release
+    pub fn release(&self, ip: &mut Inode) -> FsResult<()> {
+        let mut cache = self.delalloc.lock().unwrap();
+        self.flush_delalloc_inode(&mut cache, ip)?;
+        cache.eofblocks.remove(&ip.ino);
+        if self.free_eofblocks(ip)? {
+            self.write_inode(ip)?;
+        }
+        Ok(())
+    }
+
+    /// 释放所有文件 EOF 之后推测性预分配的块，ip 为调用者持有的 inode xfs_blockgc_free_space
+    fn reclaim_eofblocks(&self, cache: &mut DelallocCache, ip: &mut Inode) -> FsResult<()> {
+        let mut inos: Vec<AbsInoNo> = cache.eofblocks.drain().collect();
+        inos.sort();
+        for ino in inos {
+            if ino == ip.ino {
+                if self.free_eofblocks(ip)? {
+                    self.write_inode(ip)?;
+                }
+                continue;
+            }
+            let mut other = match self.iget(ino) {
+                Ok(other) => other,
+                Err(ENOENT) => continue,
+                Err(e) => return Err(e),
+            };
+            if self.free_eofblocks(&mut other)? {
+                self.write_inode(&mut other)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 为 ip 中延迟分配的数据分配块并写入 xfs_bmapi_convert_delalloc
+    pub fn flush_delalloc(&self, ip: &mut Inode) -> FsResult<()> {
+        let mut cache = self.delalloc.lock().unwrap();
+        self.flush_delalloc_inode(&mut cache, ip)
+    }
+
+    /// 写回所有文件延迟分配的数据，用于 sync 和卸载
+    pub fn flush_delalloc_all(&self) -> FsResult<()> {
+        let mut cache = self.delalloc.lock().unwrap();
+        self.flush_delalloc_locked(&mut cache)
+    }
+
+    fn flush_delalloc_locked(&self, cache: &mut DelallocCache) -> FsResult<()> {
+        let mut inos: Vec<AbsInoNo> = cache.files.keys().copied().collect();
+        inos.sort();
+        for ino in inos {
+            let mut ip = self.iget(ino)?;
+            self.flush_delalloc_inode(cache, &mut ip)?;
+        }
+        Ok(())
+    }
+
+    /// 每次取出逻辑上连续的一段，尽量分配为一个 extent。出错时已经分配的部分仍然写回 inode
+    fn flush_delalloc_inode(&self, cache: &mut DelallocCache, ip: &mut Inode) -> FsResult<()> {
+        let Some(bufs) = cache.files.get_mut(&ip.ino) else {
+            return Ok(());
+        };
+        let mut ret = Ok(());
+        let mut past_eof = false;
+        while let Some((&start, _)) = bufs.first_key_value() {
+            let mut end = start + 1;
+            while end - start < MAX_BMBT_EXTLEN && bufs.contains_key(&end) {
+                end += 1;
+            }
+            match self.flush_delalloc_run(bufs, ip, start, end) {
+                Ok((got, eof)) => {
+                    cache.blocks -= got;
+                    past_eof |= eof;
+                }
+                Err(e) => {
+                    ret = Err(e);
+                    break;
+                }
+            }
+        }
+        if bufs.is_empty() {
+            cache.files.remove(&ip.ino);
+        }
+        if past_eof {
+            cache.eofblocks.insert(ip.ino);
+        }
+        self.write_inode(ip)?;
+        ret
+    }
+
+    /// 为 [start, end) 中延迟分配的块分配一个紧接在前一个块之后的 extent，
+    /// 返回写回的块数，以及是否在 EOF 之后多分配了块
+    ///
+    /// 分配的块从这一段的预留中扣除，多分配的块另外预留，预留不到时只分配这一段；
+    /// 没有分到的部分继续保留预留。有 extsize 提示时按提示对齐，否则追加到 EOF 时推测性地多分配一些，
+    /// 多出的块映射为 unwritten
+    fn flush_delalloc_run(
+        &self,
+        bufs: &mut BTreeMap<u64, Vec<u8>>,
+        ip: &mut Inode,
+        start: u64,
+        end: u64,
+    ) -> FsResult<(u64, bool)> {
+        if ip.bmap(start).is_some() {
+            return Err(EIO);
+        }
+        let blocksize = self.superblock.blocksize as u64;
+        let len = end - start;
+        let (lo, hi) = ip.hole_around(start);
+        // 多分配的块不能覆盖之后还在内存中的数据
+        let hi = hi.min(bufs.range(end..).next().map_or(u64::MAX, |(&k, _)| k));
+        let (mut astart, mut aend) = if ip.core.extsize_hint() != 0 {
+            self.extsize_align(ip, start, end, lo, hi)
+        } else if hi == u64::MAX && end * blocksize >= ip.core.size {
+            (start, end + self.eof_prealloc_blocks(ip))
+        } else {
+            (start, end)
+        };
+        // 多分配的块不一定都能分到，按最多能分到的非数据块预留
+        let mut extra = (aend - astart)
+            .min(MAX_BMBT_EXTLEN)
+            .saturating_sub(len)
+            .max(start - astart);
+        if extra > 0 && self.mod_fdblocks(-(extra as i64), false).is_err() {
+            (astart, aend, extra) = (start, end, 0);
+        }
+        let hint = match astart.checked_sub(1).and_then(|prev| ip.bmap(prev)) {
+            Some((fsbno, _)) => fsbno + 1,
+            None => ip.ino >> self.superblock.inpblock_bits,
+        };
+        let mut resv = len + extra;
+        let args = AllocArgs {
+            ino: ip.ino,
+            hint: Some(hint),
+            minlen: 1,
+            maxlen: (aend - astart).min(MAX_BMBT_EXTLEN),
+        };
+        let (fsbno, got) = match self.alloc_blocks(&args, &mut resv) {
+            Ok(got) => got,
+            Err(e) => {
+                self.mod_fdblocks(extra as i64, true)?;
+                return Err(e);
+            }
+        };
+        // 分到的块中属于数据的部分，astart 之后的对齐部分不一定能分到
+        let data_got = (astart + got).min(end).saturating_sub(start);
+
+        let ret = (start..start + data_got)
+            .try_for_each(|lblk| {
+                self.dev
+                    .write_all_at(((fsbno + lblk - astart) * blocksize) as usize, &bufs[&lblk])
+                    .map_err(errno)
+            })
+            .inspect_err(|_| {
+                let _ = self.free_blocks(fsbno, got);
+            })
+            .and_then(|()| {
+                self.map_alloc(ip, astart, fsbno, got, start..end, ExtentState::ExtNorm)
+            });
+        if let Err(e) = ret {
+            // 数据仍在内存中，恢复这一段的预留
+            self.mod_fdblocks(resv as i64 - len as i64, true)?;
+            return Err(e);
+        }
+        for lblk in start..start + data_got {
+            bufs.remove(&lblk);
+        }
+        // 没有写回的数据继续占用预留，其余归还
+        self.mod_fdblocks(resv as i64 - (len - data_got) as i64, true)?;
+        let size_blocks = ip.core.size.div_ceil(blocksize);
+        Ok((data_got, astart + got > size_blocks.max(end)))
+    }
+}
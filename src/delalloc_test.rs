@@ -0,0 +1,256 @@
+#[cfg(test)]
+use std::sync::atomic::Ordering;
+
+#[cfg(test)]
+use libc::{ENOSPC, S_IFREG};
+
+#[cfg(test)]
+use crate::{
+    btree_test::{mkfs_and_mount, remount, small_mkfs_option},
+    dstruct::ExtentState,
+};
+
+#[cfg(test)]
+const BS: u64 = 512;
+
+#[test]
+fn test_delalloc_append() {
+    let path = "test_delalloc_append.bin";
+    let mp = mkfs_and_mount(path, small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut f = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let mut g = mp
+        .create(rootino, b"g", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+
+    // 两个文件交替小块追加，写入时只预留空间
+    let expect: Vec<u8> = (0..40 * BS).map(|i| (i % 251) as u8).collect();
+    for chunk in expect.chunks(100) {
+        let off = f.core.size;
+        mp.buffered_write(&mut f, off, chunk).unwrap();
+        mp.buffered_write(&mut g, off, chunk).unwrap();
+    }
+    assert!(f.extents.is_empty() && g.extents.is_empty());
+    assert_eq!(f.core.size, 40 * BS);
+    assert_eq!(mp.delayed_blocks(f.ino), 40);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks - 80);
+    assert!(mp.read_file(&f, 0, expect.len()).unwrap() == expect);
+
+    // 写回时每个文件分配为一个连续的 extent，预留转为实际分配
+    mp.flush_delalloc_all().unwrap();
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks - 80);
+    for ip in [&mut f, &mut g] {
+        *ip = mp.iget(ip.ino).unwrap();
+        assert_eq!(mp.delayed_blocks(ip.ino), 0);
+        assert_eq!(ip.extents.len(), 1);
+        assert_eq!(ip.core.nblocks, 40);
+        assert!(mp.read_file(ip, 0, expect.len()).unwrap() == expect);
+    }
+
+    // 追加到已分配的块中直接写入，新的块仍然延迟分配
+    mp.buffered_write(&mut f, 40 * BS - 10, &[1; 20]).unwrap();
+    assert_eq!(mp.delayed_blocks(f.ino), 1);
+    // 卸载时写回
+    drop(mp);
+    let mp = remount(path);
+    let f = mp.iget(f.ino).unwrap();
+    assert_eq!(f.core.size, 40 * BS + 10);
+    assert_eq!(f.core.nblocks, 41);
+    let data = mp.read_file(&f, 0, f.core.size as usize).unwrap();
+    assert!(data[..expect.len() - 10] == expect[..expect.len() - 10]);
+    assert!(data[expect.len() - 10..].iter().all(|&b| b == 1));
+}
+
+#[test]
+fn test_delalloc_reserve() {
+    let mp = mkfs_and_mount("test_delalloc_reserve.bin", small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut ip = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+
+    // 截断时归还 EOF 之后的预留，最后一个块中 EOF 之后的部分清零
+    mp.buffered_write(&mut ip, 0, &[9; 10 * BS as usize])
+        .unwrap();
+    mp.truncate(&mut ip, 3 * BS + 10).unwrap();
+    assert_eq!(mp.delayed_blocks(ip.ino), 4);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks - 4);
+    mp.truncate(&mut ip, 4 * BS).unwrap();
+    let data = mp.read_file(&ip, 0, 4 * BS as usize).unwrap();
+    assert!(data[..3 * BS as usize + 10].iter().all(|&b| b == 9));
+    assert!(data[3 * BS as usize + 10..].iter().all(|&b| b == 0));
+
+    // 直接写入前先写回延迟分配的数据
+    mp.write_file(&mut ip, 8 * BS, &[3; 10]).unwrap();
+    assert_eq!(mp.delayed_blocks(ip.ino), 0);
+    assert_eq!(ip.core.nblocks, 5);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks - 5);
+
+    // 剩余的块不足时预留失败，什么都不写
+    let free = mp.fdblocks.load(Ordering::Relaxed) - mp.alloc_set_aside();
+    mp.mod_fdblocks(-(free as i64), false).unwrap();
+    assert_eq!(mp.mod_fdblocks(-1, false).err(), Some(ENOSPC));
+    let size = ip.core.size;
+    assert_eq!(
+        mp.buffered_write(&mut ip, 9 * BS, &[1; 2]).err(),
+        Some(ENOSPC)
+    );
+    assert_eq!(ip.core.size, size);
+    assert_eq!(mp.delayed_blocks(ip.ino), 0);
+    // 已分配的块不需要预留
+    mp.buffered_write(&mut ip, 0, &[5; 2]).unwrap();
+    mp.mod_fdblocks(free as i64, false).unwrap();
+
+    mp.truncate(&mut ip, 0).unwrap();
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks);
+}
+
+#[test]
+fn test_eof_prealloc() {
+    let mp = mkfs_and_mount("test_eof_prealloc.bin", small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut ip = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+
+    // 追加到 128KiB 后写回，EOF 之后按文件大小多分配 256 块
+    let expect: Vec<u8> = (0..356 * BS).map(|i| (i % 253) as u8).collect();
+    for chunk in expect[..256 * BS as usize].chunks(4096) {
+        let off = ip.core.size;
+        mp.buffered_write(&mut ip, off, chunk).unwrap();
+    }
+    mp.flush_delalloc(&mut ip).unwrap();
+    assert_eq!(ip.core.nblocks, 512);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks - 512);
+    let (fsbno, _) = ip.bmap(0).unwrap();
+    assert_eq!(ip.bmap(255), Some((fsbno + 255, ExtentState::ExtNorm)));
+    assert_eq!(ip.bmap(256), Some((fsbno + 256, ExtentState::ExtUnwritten)));
+    assert_eq!(ip.bmap(511).unwrap().1, ExtentState::ExtUnwritten);
+
+    // 继续追加直接写入预分配的块，不需要再预留
+    let off = ip.core.size;
+    mp.buffered_write(&mut ip, off, &expect[256 * BS as usize..])
+        .unwrap();
+    assert_eq!(mp.delayed_blocks(ip.ino), 0);
+    assert_eq!(ip.core.nblocks, 512);
+    assert_eq!(ip.bmap(355), Some((fsbno + 355, ExtentState::ExtNorm)));
+    assert!(mp.read_file(&ip, 0, expect.len()).unwrap() == expect);
+
+    // 关闭时释放 EOF 之后剩下的块
+    mp.release(&mut ip).unwrap();
+    assert_eq!(ip.core.nblocks, 356);
+    assert_eq!(ip.bmap(356), None);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks - 356);
+    let ip = mp.iget(ip.ino).unwrap();
+    assert_eq!(ip.core.nblocks, 356);
+    assert!(mp.read_file(&ip, 0, expect.len()).unwrap() == expect);
+}
+
+#[test]
+fn test_eof_prealloc_enospc() {
+    let mp = mkfs_and_mount("test_eof_prealloc_enospc.bin", small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut f = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let mut g = mp
+        .create(rootino, b"g", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    mp.buffered_write(&mut f, 0, &[1; 256 * BS as usize])
+        .unwrap();
+    mp.flush_delalloc(&mut f).unwrap();
+    assert_eq!(f.core.nblocks, 512);
+
+    // 空间不足时回收其他文件 EOF 之后的块，预留成功
+    let free = mp.fdblocks.load(Ordering::Relaxed) - mp.alloc_set_aside();
+    mp.mod_fdblocks(-(free as i64), false).unwrap();
+    mp.buffered_write(&mut g, 0, &[2; 10 * BS as usize])
+        .unwrap();
+    assert_eq!(mp.delayed_blocks(g.ino), 10);
+    let f = mp.iget(f.ino).unwrap();
+    assert_eq!(f.core.nblocks, 256);
+    assert_eq!(f.bmap(256), None);
+    // 没有可以回收的块时仍然失败
+    assert_eq!(
+        mp.buffered_write(&mut g, 10 * BS, &[2; 250 * BS as usize])
+            .err(),
+        Some(ENOSPC)
+    );
+    mp.mod_fdblocks(free as i64, false).unwrap();
+}
+
+#[test]
+fn test_delalloc_reserved_space() {
+    let mp = mkfs_and_mount("test_delalloc_reserved_space.bin", small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut f = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let mut g = mp
+        .create(rootino, b"g", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+
+    // 缓冲写用完所有可以预留的空间
+    let free = mp.fdblocks.load(Ordering::Relaxed) - mp.alloc_set_aside();
+    mp.mod_fdblocks(-(free as i64 - 20), false).unwrap();
+    let expect: Vec<u8> = (0..20 * BS).map(|i| (i % 241) as u8).collect();
+    mp.buffered_write(&mut f, 0, &expect).unwrap();
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), mp.alloc_set_aside());
+
+    // 立即分配的路径不能用掉已经预留给缓冲写的块
+    assert_eq!(mp.fallocate(&mut g, 0, 0, BS).err(), Some(ENOSPC));
+    assert_eq!(mp.write_file(&mut g, 0, &[1; 10]).err(), Some(ENOSPC));
+    assert_eq!(g.core.nblocks, 0);
+
+    mp.flush_delalloc_all().unwrap();
+    let f = mp.iget(f.ino).unwrap();
+    assert_eq!(mp.delayed_blocks(f.ino), 0);
+    assert!(mp.read_file(&f, 0, expect.len()).unwrap() == expect);
+    // 预留不到 EOF 之后多分配的块时只分配数据
+    assert_eq!(f.core.nblocks, 20);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), mp.alloc_set_aside());
+    mp.mod_fdblocks(free as i64 - 20, false).unwrap();
+}
+
+#[test]
+fn test_delalloc_fragmented() {
+    let mp = mkfs_and_mount("test_delalloc_fragmented.bin", small_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut ip = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let ag_free = || -> u64 {
+        mp.perag
+            .iter()
+            .map(|pag| {
+                let agf = pag.agf.lock().unwrap();
+                (agf.freeblks + agf.flcount) as u64
+            })
+            .sum()
+    };
+
+    // AG 0 中除了末尾留给 AGFL 补充块的一段，只剩下长度为 3 的空闲 extent，每次只能分到一小段
+    let longest = mp.perag[0].agf.lock().unwrap().longest - 512;
+    let (start, len) = mp
+        .with_agf(0, |ctx| ctx.alloc_extent(longest, longest, None))
+        .unwrap();
+    for bno in (start..start + len - 3).step_by(4) {
+        mp.free_blocks(mp.agbno_to_fsbno(0, bno), 3).unwrap();
+    }
+    assert!(mp.perag[0].agf.lock().unwrap().freeblks > 1000);
+
+    let expect: Vec<u8> = (0..200 * BS).map(|i| (i % 239) as u8).collect();
+    mp.buffered_write(&mut ip, 0, &expect).unwrap();
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), ag_free() - 200);
+    mp.flush_delalloc(&mut ip).unwrap();
+    assert_eq!(mp.delayed_blocks(ip.ino), 0);
+    assert!(ip.extents.len() > 60);
+    assert!(mp.read_file(&ip, 0, expect.len()).unwrap() == expect);
+    // 没有延迟分配的块时 fdblocks 与各 AG 的空闲块数一致
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), ag_free());
+}
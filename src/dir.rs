@@ -0,0 +1,543 @@
+//! 目录数据块
+//!
+//! 目录的内容按文件数据的方式存放在 data fork 映射的块中，每个块的布局如下：
+//!
+//! ```text
+//! | DirBlockHeader | entry | entry | ... | entry |
+//! ```
+//!
+//! entry 是变长的，各 entry 首尾相接，最后一个 entry 延伸到块末尾：
+//!
+//! ```text
+//! | inumber u64 | reclen u16 | namelen u8 | filetype u8 | name ... | 填充到 8 字节对齐 |
+//! ```
+//!
+//! reclen 是整个 entry 占用的长度，其中可能包含未使用的空间。inumber 为 0 的 entry 未被使用。
+use libc::{EEXIST, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY};
+
+use crate::{
+    alloc::AllocArgs,
+    block_dev::BlockDevice,
+    dstruct::{timestamp, AbsInoNo, BmbtRecord, DirBlockHeader, ExtentState, DIR_BLOCK_MAGIC},
+    inode::Inode,
+    pound_fs::{errno, FsResult, MountPoint},
+};
+
+// DirBlockHeader 编码后的大小
+pub const DIR_BLOCK_HDR_SIZE: usize = 48;
+// entry 中 name 之前的固定部分
+const DIR_ENTRY_FIXED_SIZE: usize = 12;
+
+// 目录项的文件类型，同 XFS_DIR3_FT_*
+pub const DIR_FT_UNKNOWN: u8 = 0;
+pub const DIR_FT_REG_FILE: u8 = 1;
+pub const DIR_FT_DIR: u8 = 2;
+pub const DIR_FT_CHRDEV: u8 = 3;
+pub const DIR_FT_BLKDEV: u8 = 4;
+pub const DIR_FT_FIFO: u8 = 5;
+pub const DIR_FT_SOCK: u8 = 6;
+pub const DIR_FT_SYMLINK: u8 = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub ino: AbsInoNo,
+    pub ftype: u8,
+    pub name: Vec<u8>,
+}
+
+impl DirEntry {
+    fn unused() -> Self {
+        DirEntry {
+            ino: 0,
+            ftype: DIR_FT_UNKNOWN,
+            name: Vec::new(),
+        }
+    }
+}
+
+/// 根据 inode 的 mode 得到目录项的文件类型
+pub fn mode_to_ftype(mode: u16) -> u8 {
+    match mode as u32 & libc::S_IFMT {
+        libc::S_IFREG => DIR_FT_REG_FILE,
+        libc::S_IFDIR => DIR_FT_DIR,
+        libc::S_IFCHR => DIR_FT_CHRDEV,
+        libc::S_IFBLK => DIR_FT_BLKDEV,
+        libc::S_IFIFO => DIR_FT_FIFO,
+        libc::S_IFSOCK => DIR_FT_SOCK,
+        libc::S_IFLNK => DIR_FT_SYMLINK,
+        _ => DIR_FT_UNKNOWN,
+    }
+}
+
+/// 名字长度为 namelen 的 entry 至少需要的空间
+pub fn entry_size(namelen: usize) -> usize {
+    (DIR_ENTRY_FIXED_SIZE + namelen + 7) & !7
+}
+
+fn read_u16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(buf[off..off + 2].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+/// 在 off 处写入一个 entry，reclen 为其占用的空间
+fn write_entry(buf: &mut [u8], off: usize, reclen: usize, ent: &DirEntry) {
+    buf[off..off + 8].copy_from_slice(&ent.ino.to_le_bytes());
+    buf[off + 8..off + 10].copy_from_slice(&(reclen as u16).to_le_bytes());
+    buf[off + 10] = ent.name.len() as u8;
+    buf[off + 11] = ent.ftype;
+    let name_off = off + DIR_ENTRY_FIXED_SIZE;
+    buf[name_off..name_off + ent.name.len()].copy_from_slice(&ent.name);
+}
+
+/// 将 entries 依次编码为一个目录块。调用者需保证 entries 放得下
+pub fn encode_dir_block(
+    blkno: u64,
+    owner: AbsInoNo,
+    entries: &[DirEntry],
+    blocksize: usize,
+) -> Vec<u8> {
+    let mut buf = vec![0u8; blocksize];
+    let hdr = DirBlockHeader {
+        magic: DIR_BLOCK_MAGIC,
+        crc: 0,
+        blkno,
+        lsn: 0,
+        uuid: [0; 16],
+        owner,
+    };
+    buf[..DIR_BLOCK_HDR_SIZE].copy_from_slice(&bincode::serialize(&hdr).unwrap());
+    let mut off = DIR_BLOCK_HDR_SIZE;
+    for (i, ent) in entries.iter().enumerate() {
+        let reclen = if i + 1 == entries.len() {
+            blocksize - off
+        } else {
+            entry_size(ent.name.len())
+        };
+        write_entry(&mut buf, off, reclen, ent);
+        off += reclen;
+    }
+    if entries.is_empty() {
+        write_entry(&mut buf, off, blocksize - off, &DirEntry::unused());
+    }
+    buf
+}
+
+/// 解码一个目录块，返回 (entry 在块内的偏移, reclen, entry)，包括未使用的 entry
+pub fn decode_dir_block(buf: &[u8]) -> FsResult<Vec<(usize, usize, DirEntry)>> {
+    let hdr: DirBlockHeader = bincode::deserialize(&buf[..DIR_BLOCK_HDR_SIZE]).map_err(|_| EIO)?;
+    if hdr.magic != DIR_BLOCK_MAGIC {
+        return Err(EIO);
+    }
+    let mut entries = Vec::new();
+    let mut off = DIR_BLOCK_HDR_SIZE;
+    while off + DIR_ENTRY_FIXED_SIZE <= buf.len() {
+        let reclen = read_u16(buf, off + 8) as usize;
+        let namelen = buf[off + 10] as usize;
+        if reclen < entry_size(0)
+            || off + reclen > buf.len()
+            || DIR_ENTRY_FIXED_SIZE + namelen > reclen
+        {
+            return Err(EIO);
+        }
+        let name_off = off + DIR_ENTRY_FIXED_SIZE;
+        entries.push((
+            off,
+            reclen,
+            DirEntry {
+                ino: read_u64(buf, off),
+                ftype: buf[off + 11],
+                name: buf[name_off..name_off + namelen].to_vec(),
+            },
+        ));
+        off += reclen;
+    }
+    Ok(entries)
+}
+
+impl<'a> MountPoint<'a> {
+    /// 读取目录的第 idx 个块
+    pub fn read_dir_block(&self, dp: &Inode, idx: u64) -> FsResult<Vec<u8>> {
+        let blocksize = self.superblock.blocksize as usize;
+        let (fsbno, _) = dp.bmap(idx).ok_or(EIO)?;
+        let mut buf = vec![0u8; blocksize];
+        self.dev
+            .read_all_at(fsbno as usize * blocksize, &mut buf)
+            .map_err(errno)?;
+        Ok(buf)
+    }
+
+    /// 目录占用的块数
+    pub fn dir_nblocks(&self, dp: &Inode) -> u64 {
+        dp.core.size / self.superblock.blocksize as u64
+    }
+
+    /// 从目录内的字节位置 pos 开始列出目录项，返回 (下一个目录项的位置, 目录项)
+    pub fn read_dir(&self, dp: &Inode, pos: u64) -> FsResult<Vec<(u64, DirEntry)>> {
+        let blocksize = self.superblock.blocksize as u64;
+        let mut result = Vec::new();
+        for idx in pos / blocksize..self.dir_nblocks(dp) {
+            let buf = self.read_dir_block(dp, idx)?;
+            for (off, reclen, ent) in decode_dir_block(&buf)? {
+                let ent_pos = idx * blocksize + off as u64;
+                if ent.ino == 0 || ent_pos < pos {
+                    continue;
+                }
+                result.push((ent_pos + reclen as u64, ent));
+            }
+        }
+        Ok(result)
+    }
+
+    /// 在目录中查找名为 name 的目录项
+    pub fn dir_lookup(&self, dp: &Inode, name: &[u8]) -> FsResult<Option<DirEntry>> {
+        for idx in 0..self.dir_nblocks(dp) {
+            let buf = self.read_dir_block(dp, idx)?;
+            for (_, _, ent) in decode_dir_block(&buf)? {
+                if ent.ino != 0 && ent.name == name {
+                    return Ok(Some(ent));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn check_name(name: &[u8]) -> FsResult<()> {
+    if name.len() > u8::MAX as usize {
+        return Err(ENAMETOOLONG);
+    }
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        return Err(EINVAL);
+    }
+    Ok(())
+}
+
+impl<'a> MountPoint<'a> {
+    fn write_dir_block(&self, dp: &Inode, idx: u64, buf: &[u8]) -> FsResult<()> {
+        let blocksize = self.superblock.blocksize as usize;
+        let (fsbno, _) = dp.bmap(idx).ok_or(EIO)?;
+        self.dev
+            .write_all_at(fsbno as usize * blocksize, buf)
+            .map_err(errno)
+    }
+
+    /// 为目录追加一个包含 entries 的新块
+    fn dir_append_block(&self, dp: &mut Inode, entries: &[DirEntry]) -> FsResult<()> {
+        let blocksize = self.superblock.blocksize as u64;
+        let idx = self.dir_nblocks(dp);
+        let hint = match idx.checked_sub(1).and_then(|prev| dp.bmap(prev)) {
+            Some((fsbno, _)) => fsbno + 1,
+            None => dp.ino >> self.superblock.inpblock_bits,
+        };
+        let args = AllocArgs {
+            ino: dp.ino,
+            hint: Some(hint),
+            minlen: 1,
+            maxlen: 1,
+        };
+        let (fsbno, _) = self.with_reserved(1, |resv| self.alloc_blocks(&args, resv))?;
+        let rec = BmbtRecord {
+            startoff: idx,
+            startblock: fsbno,
+            blockcount: 1,
+            state: ExtentState::ExtNorm,
+        };
+        let buf = encode_dir_block(fsbno, dp.ino, entries, blocksize as usize);
+        if let Err(e) = self.dev.write_all_at((fsbno * blocksize) as usize, &buf) {
+            let _ = self.free_blocks(fsbno, 1);
+            return Err(errno(e));
+        }
+        if let Err(e) = self.map_extent(dp, rec) {
+            let _ = self.free_blocks(fsbno, 1);
+            return Err(e);
+        }
+        dp.core.size += blocksize;
+        Ok(())
+    }
+
+    /// 初始化一个空目录，只包含 . 和 ..
+    pub fn dir_init(&self, dp: &mut Inode, parent: AbsInoNo) -> FsResult<()> {
+        let entries = [
+            DirEntry {
+                ino: dp.ino,
+                ftype: DIR_FT_DIR,
+                name: b".".to_vec(),
+            },
+            DirEntry {
+                ino: parent,
+                ftype: DIR_FT_DIR,
+                name: b"..".to_vec(),
+            },
+        ];
+        self.dir_append_block(dp, &entries)?;
+        dp.core.nlink = 2;
+        self.write_inode(dp)
+    }
+
+    /// 创建根目录，根目录的 .. 指向自身
+    pub fn make_root_dir(&self) -> FsResult<Inode> {
+        let mut root = self.ialloc(0, libc::S_IFDIR as u16 | 0o755, 0, 0)?;
+        let ino = root.ino;
+        self.dir_init(&mut root, ino)?;
+        Ok(root)
+    }
+
+    /// 添加目录项，优先复用已有块中的空闲空间，不够时追加新块。不会写回 dp
+    pub fn dir_add_entry(&self, dp: &mut Inode, ent: &DirEntry) -> FsResult<()> {
+        let need = entry_size(ent.name.len());
+        for idx in 0..self.dir_nblocks(dp) {
+            let mut buf = self.read_dir_block(dp, idx)?;
+            for (off, reclen, cur) in decode_dir_block(&buf)? {
+                let used = if cur.ino == 0 {
+                    0
+                } else {
+                    entry_size(cur.name.len())
+                };
+                if reclen - used < need {
+                    continue;
+                }
+                if cur.ino == 0 {
+                    write_entry(&mut buf, off, reclen, ent);
+                } else {
+                    // 拆分当前 entry 尾部的空闲空间
+                    write_entry(&mut buf, off, used, &cur);
+                    write_entry(&mut buf, off + used, reclen - used, ent);
+                }
+                return self.write_dir_block(dp, idx, &buf);
+            }
+        }
+        self.dir_append_block(dp, std::slice::from_ref(ent))
+    }
+
+    /// 删除名为 name 的目录项，其空间并入前一个 entry。返回被删除的目录项
+    pub fn dir_remove_entry(&self, dp: &Inode, name: &[u8]) -> FsResult<DirEntry> {
+        for idx in 0..self.dir_nblocks(dp) {
+            let mut buf = self.read_dir_block(dp, idx)?;
+            let mut prev: Option<(usize, usize, DirEntry)> = None;
+            for (off, reclen, cur) in decode_dir_block(&buf)? {
+                if cur.ino == 0 || cur.name != name {
+                    prev = Some((off, reclen, cur));
+                    continue;
+                }
+                match prev {
+                    Some((prev_off, prev_reclen, prev)) => {
+                        write_entry(&mut buf, prev_off, prev_reclen + reclen, &prev)
+                    }
+                    None => write_entry(&mut buf, off, reclen, &DirEntry::unused()),
+                }
+                self.write_dir_block(dp, idx, &buf)?;
+                return Ok(cur);
+            }
+        }
+        Err(ENOENT)
+    }
+
+    /// 将名为 name 的目录项原地指向另一个 inode
+    pub fn dir_replace_entry(
+        &self,
+        dp: &Inode,
+        name: &[u8],
+        ino: AbsInoNo,
+        ftype: u8,
+    ) -> FsResult<()> {
+        for idx in 0..self.dir_nblocks(dp) {
+            let mut buf = self.read_dir_block(dp, idx)?;
+            for (off, reclen, cur) in decode_dir_block(&buf)? {
+                if cur.ino != 0 && cur.name == name {
+                    let ent = DirEntry {
+                        ino,
+                        ftype,
+                        name: cur.name,
+                    };
+                    write_entry(&mut buf, off, reclen, &ent);
+                    return self.write_dir_block(dp, idx, &buf);
+                }
+            }
+        }
+        Err(ENOENT)
+    }
+
+    /// 目录中除 . 和 .. 外是否没有其他目录项
+    pub fn dir_is_empty(&self, dp: &Inode) -> FsResult<bool> {
+        Ok(self
+            .read_dir(dp, 0)?
+            .iter()
+            .all(|(_, ent)| ent.name == b"." || ent.name == b".."))
+    }
+
+    /// 在目录 parent 中创建名为 name 的文件或目录
+    pub fn create(
+        &self,
+        parent: AbsInoNo,
+        name: &[u8],
+        mode: u16,
+        uid: u32,
+        gid: u32,
+    ) -> FsResult<Inode> {
+        check_name(name)?;
+        let mut dp = self.iget(parent)?;
+        if !dp.is_dir() {
+            return Err(ENOTDIR);
+        }
+        if self.dir_lookup(&dp, name)?.is_some() {
+            return Err(EEXIST);
+        }
+        let mut ip = self.ialloc(dp.ino, mode, uid, gid)?;
+        if ip.is_dir() {
+            if let Err(e) = self.dir_init(&mut ip, dp.ino) {
+                self.ifree(&mut ip)?;
+                return Err(e);
+            }
+        }
+        let ent = DirEntry {
+            ino: ip.ino,
+            ftype: mode_to_ftype(mode),
+            name: name.to_vec(),
+        };
+        if let Err(e) = self.dir_add_entry(&mut dp, &ent) {
+            self.ifree(&mut ip)?;
+            return Err(e);
+        }
+        if ip.is_dir() {
+            dp.core.inc_nlink();
+        }
+        dp.touch();
+        self.write_inode(&mut dp)?;
+        Ok(ip)
+    }
+
+    /// 减少 inode 的链接数，降为 0 时释放它
+    fn drop_link(&self, ip: &mut Inode) -> FsResult<()> {
+        if ip.is_dir() {
+            ip.core.nlink = 0;
+        } else {
+            ip.core.drop_nlink();
+        }
+        if ip.core.nlink == 0 {
+            self.ifree(ip)
+        } else {
+            ip.core.ctime = timestamp::now();
+            self.write_inode(ip)
+        }
+    }
+
+    /// 删除目录 parent 中名为 name 的文件（rmdir 为 false）或空目录（rmdir 为 true）
+    pub fn remove(&self, parent: AbsInoNo, name: &[u8], rmdir: bool) -> FsResult<()> {
+        if name == b"." || name == b".." {
+            return Err(if rmdir { ENOTEMPTY } else { EISDIR });
+        }
+        let mut dp = self.iget(parent)?;
+        if !dp.is_dir() {
+            return Err(ENOTDIR);
+        }
+        let ent = self.dir_lookup(&dp, name)?.ok_or(ENOENT)?;
+        let mut ip = self.iget(ent.ino)?;
+        match (rmdir, ip.is_dir()) {
+            (true, false) => return Err(ENOTDIR),
+            (false, true) => return Err(EISDIR),
+            (true, true) if !self.dir_is_empty(&ip)? => return Err(ENOTEMPTY),
+            _ => {}
+        }
+        self.dir_remove_entry(&dp, name)?;
+        if ip.is_dir() {
+            dp.core.drop_nlink();
+        }
+        dp.touch();
+        self.write_inode(&mut dp)?;
+        self.drop_link(&mut ip)
+    }
+
+    /// dir 是否是 ancestor 本身或位于 ancestor 之下
+    fn is_descendant(&self, dir: AbsInoNo, ancestor: AbsInoNo) -> FsResult<bool> {
+        let mut cur = dir;
+        loop {
+            if cur == ancestor {
+                return Ok(true);
+            }
+            if cur == self.superblock.rootino {
+                return Ok(false);
+            }
+            let dp = self.iget(cur)?;
+            cur = self.dir_lookup(&dp, b"..")?.ok_or(EIO)?.ino;
+        }
+    }
+
+    /// 将 parent 中的 name 重命名为 newparent 中的 newname，newname 已存在时将其替换
+    pub fn rename(
+        &self,
+        parent: AbsInoNo,
+        name: &[u8],
+        newparent: AbsInoNo,
+        newname: &[u8],
+        noreplace: bool,
+    ) -> FsResult<()> {
+        check_name(name)?;
+        check_name(newname)?;
+        let dp = self.iget(parent)?;
+        let mut tdp = self.iget(newparent)?;
+        if !dp.is_dir() || !tdp.is_dir() {
+            return Err(ENOTDIR);
+        }
+        let ent = self.dir_lookup(&dp, name)?.ok_or(ENOENT)?;
+        if parent == newparent && name == newname {
+            return Ok(());
+        }
+        let mut ip = self.iget(ent.ino)?;
+        // 不能把目录移动到它自己的子目录中
+        if ip.is_dir() && self.is_descendant(newparent, ip.ino)? {
+            return Err(EINVAL);
+        }
+
+        match self.dir_lookup(&tdp, newname)? {
+            Some(target) => {
+                if noreplace {
+                    return Err(EEXIST);
+                }
+                if target.ino == ip.ino {
+                    return Ok(());
+                }
+                let mut tip = self.iget(target.ino)?;
+                match (ip.is_dir(), tip.is_dir()) {
+                    (true, false) => return Err(ENOTDIR),
+                    (false, true) => return Err(EISDIR),
+                    (true, true) if !self.dir_is_empty(&tip)? => return Err(ENOTEMPTY),
+                    _ => {}
+                }
+                self.dir_replace_entry(&tdp, newname, ip.ino, ent.ftype)?;
+                if tip.is_dir() {
+                    tdp.core.drop_nlink();
+                }
+                self.drop_link(&mut tip)?;
+            }
+            None => {
+                let new_ent = DirEntry {
+                    ino: ip.ino,
+                    ftype: ent.ftype,
+                    name: newname.to_vec(),
+                };
+                self.dir_add_entry(&mut tdp, &new_ent)?;
+            }
+        }
+        if ip.is_dir() && parent != newparent {
+            self.dir_replace_entry(&ip, b"..", newparent, DIR_FT_DIR)?;
+            tdp.core.inc_nlink();
+        }
+        tdp.touch();
+        self.write_inode(&mut tdp)?;
+
+        // tdp 可能就是 dp，需要重新读取
+        let mut dp = self.iget(parent)?;
+        self.dir_remove_entry(&dp, name)?;
+        if ip.is_dir() && parent != newparent {
+            dp.core.drop_nlink();
+        }
+        dp.touch();
+        self.write_inode(&mut dp)?;
+
+        ip.core.ctime = timestamp::now();
+        self.write_inode(&mut ip)
+    }
+}
@@ -0,0 +1,199 @@
+#[cfg(test)]
+use std::sync::atomic::Ordering;
+
+#[cfg(test)]
+use crate::{
+    dir::{encode_dir_block, DirEntry, DIR_FT_DIR, DIR_FT_REG_FILE},
+    block_dev::BlockDevice,
+    btree_test::{make_raw_mp, mkfs_and_mount, remount, test_mkfs_option},
+    dstruct::{BmbtRecord, Dinode, ExtentState, SuperBlock},
+    inode::Inode,
+    pound_fs::MountPoint,
+};
+
+/// 手工构造一个只有根目录和 hello.txt 的镜像
+///
+/// 块 1 存放 inode，块 2 是根目录的目录块，块 3、4 是 hello.txt 的数据
+#[cfg(test)]
+fn make_test_image(path: &str) -> MountPoint<'static> {
+    let blocksize = 4096;
+    let mut sb = SuperBlock::new();
+    sb.blocksize = blocksize as u32;
+    sb.blocksize_bits = 12;
+    sb.dblocks = 64;
+    sb.agblocks = 64;
+    sb.agblocks_bits = 6;
+    sb.agcount = 1;
+    sb.inodesize = 512;
+    sb.inodesize_bits = 9;
+    sb.inopblock = 8;
+    sb.inpblock_bits = 3;
+    sb.rootino = 1 << 3;
+    let mp = make_raw_mp(path, sb);
+
+    let mut root = Inode {
+        ino: 1 << 3,
+        core: Dinode::new(1 << 3, libc::S_IFDIR as u16 | 0o755),
+        extents: vec![BmbtRecord {
+            startoff: 0,
+            startblock: 2,
+            blockcount: 1,
+            state: ExtentState::ExtNorm,
+        }],
+        broot: None,
+    };
+    root.core.nlink = 2;
+    root.core.size = blocksize as u64;
+    root.core.nblocks = 1;
+    mp.write_inode(&mut root).unwrap();
+
+    let mut file = Inode {
+        ino: (1 << 3) + 1,
+        core: Dinode::new((1 << 3) + 1, libc::S_IFREG as u16 | 0o644),
+        extents: vec![BmbtRecord {
+            startoff: 0,
+            startblock: 3,
+            blockcount: 2,
+            state: ExtentState::ExtNorm,
+        }],
+        broot: None,
+    };
+    file.core.size = 5000;
+    file.core.nblocks = 2;
+    mp.write_inode(&mut file).unwrap();
+
+    let entries = [
+        DirEntry {
+            ino: root.ino,
+            ftype: DIR_FT_DIR,
+            name: b".".to_vec(),
+        },
+        DirEntry {
+            ino: root.ino,
+            ftype: DIR_FT_DIR,
+            name: b"..".to_vec(),
+        },
+        DirEntry {
+            ino: file.ino,
+            ftype: DIR_FT_REG_FILE,
+            name: b"hello.txt".to_vec(),
+        },
+    ];
+    let dir_block = encode_dir_block(2, root.ino, &entries, blocksize);
+    mp.dev.write_all_at(2 * blocksize, &dir_block).unwrap();
+
+    let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    mp.dev.write_all_at(3 * blocksize, &data).unwrap();
+    mp
+}
+
+#[test]
+fn test_dir_lookup_and_read() {
+    let mp = make_test_image("test_dir_lookup.bin");
+    let root = mp.iget(mp.superblock.rootino).unwrap();
+    assert!(root.is_dir());
+
+    let names: Vec<Vec<u8>> = mp
+        .read_dir(&root, 0)
+        .unwrap()
+        .into_iter()
+        .map(|(_, ent)| ent.name)
+        .collect();
+    assert_eq!(
+        names,
+        vec![b".".to_vec(), b"..".to_vec(), b"hello.txt".to_vec()]
+    );
+
+    // 从第二个目录项之后继续列出
+    let entries = mp.read_dir(&root, 0).unwrap();
+    let rest = mp.read_dir(&root, entries[1].0).unwrap();
+    assert_eq!(rest.len(), 1);
+    assert_eq!(rest[0].1.name, b"hello.txt".to_vec());
+
+    assert!(mp.dir_lookup(&root, b"missing").unwrap().is_none());
+    let ent = mp.dir_lookup(&root, b"hello.txt").unwrap().unwrap();
+    let file = mp.iget(ent.ino).unwrap();
+    assert_eq!(file.core.size, 5000);
+
+    // 跨块读取，并在 EOF 处截断
+    let data = mp.read_file(&file, 4000, 2000).unwrap();
+    assert_eq!(data.len(), 1000);
+    for (i, b) in data.iter().enumerate() {
+        assert_eq!(*b, ((4000 + i) % 251) as u8);
+    }
+    assert!(mp.read_file(&file, 5000, 10).unwrap().is_empty());
+}
+
+#[test]
+fn test_dir_create_rename_remove() {
+    let path = "test_dir_rw.bin";
+    let mp = mkfs_and_mount(path, test_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let free_before = mp.fdblocks.load(Ordering::Relaxed);
+
+    let mut file = mp
+        .create(rootino, b"a.txt", libc::S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    let data: Vec<u8> = (0..10000).map(|i| (i % 253) as u8).collect();
+    assert_eq!(mp.write_file(&mut file, 0, &data).unwrap(), data.len());
+    // 在 EOF 之后写入，中间留下空洞
+    mp.write_file(&mut file, 20000, b"tail").unwrap();
+    let file = mp.iget(file.ino).unwrap();
+    assert_eq!(file.core.size, 20004);
+    assert_eq!(mp.read_file(&file, 0, 10000).unwrap(), data);
+    assert_eq!(mp.read_file(&file, 10000, 6000).unwrap(), vec![0u8; 6000]);
+    assert_eq!(mp.read_file(&file, 20000, 100).unwrap(), b"tail".to_vec());
+    assert_eq!(
+        mp.create(rootino, b"a.txt", libc::S_IFREG as u16, 0, 0)
+            .err(),
+        Some(libc::EEXIST)
+    );
+
+    let dir = mp
+        .create(rootino, b"d", libc::S_IFDIR as u16 | 0o755, 0, 0)
+        .unwrap();
+    assert_eq!(mp.iget(rootino).unwrap().core.nlink, 3);
+    mp.rename(rootino, b"a.txt", dir.ino, b"b.txt", false)
+        .unwrap();
+    let root = mp.iget(rootino).unwrap();
+    assert!(mp.dir_lookup(&root, b"a.txt").unwrap().is_none());
+    let dp = mp.iget(dir.ino).unwrap();
+    assert_eq!(mp.dir_lookup(&dp, b"b.txt").unwrap().unwrap().ino, file.ino);
+    assert_eq!(mp.remove(rootino, b"d", true).err(), Some(libc::ENOTEMPTY));
+    // 不能把目录移动到自己下面
+    assert_eq!(
+        mp.rename(rootino, b"d", dir.ino, b"d2", false).err(),
+        Some(libc::EINVAL)
+    );
+
+    // 重新挂载后空闲空间应当一致
+    mp.write_superblock().unwrap();
+    let free_mid = mp.fdblocks.load(Ordering::Relaxed);
+    drop(mp);
+    let mp = remount(path);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), free_mid);
+
+    mp.remove(dir.ino, b"b.txt", false).unwrap();
+    mp.remove(rootino, b"d", true).unwrap();
+    assert_eq!(mp.iget(rootino).unwrap().core.nlink, 2);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), free_before);
+}
+
+#[test]
+fn test_truncate() {
+    let mp = mkfs_and_mount("test_truncate.bin", test_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let mut file = mp
+        .create(rootino, b"t", libc::S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap();
+    mp.write_file(&mut file, 0, &vec![0xabu8; 3 * 4096])
+        .unwrap();
+    assert_eq!(file.core.nblocks, 3);
+    mp.truncate(&mut file, 5000).unwrap();
+    assert_eq!(file.core.nblocks, 2);
+    // 扩展后，原先截断部分读出为 0
+    mp.truncate(&mut file, 3 * 4096).unwrap();
+    let data = mp.read_file(&file, 0, 3 * 4096).unwrap();
+    assert!(data[..5000].iter().all(|&b| b == 0xab));
+    assert!(data[5000..].iter().all(|&b| b == 0));
+}
@@ -2,11 +2,16 @@ pub type AbsInoNo = u64;
 pub type UUID = [u8; 16];
 
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde::{Serialize, Deserialize};
+use serde_big_array::BigArray;
 
-use crate::util::uuid;
+use crate::btree::{BtreeKey, BtreeRecord};
 
-const SuperBlockMagicNum: u32 = 0x73666470;
+pub const SuperBlockMagicNum: u32 = 0x73666470;
+// 超级块 flags 中的特性位
+pub const SB_FEAT_SPARSE_INODES: u32 = 1 << 0; // 稀疏 inode chunk，空间不足时可以只分配 chunk 的一部分
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SuperBlock {
     pub magicnum: u32,      // 魔数
@@ -15,7 +20,7 @@ pub struct SuperBlock {
     pub rblocks: u32,       // 实时块数 https://www.cnblogs.com/orange-CC/p/12711078.html
     pub rextents: u32,      // 实时扩展数
     pub uuid: UUID,         // 唯一标识符
-    pub rootino: u32,       // 根目录Inode号
+    pub rootino: AbsInoNo,  // 根目录Inode号
     pub rbmino: u32,        // 实时块位图Inode号
     pub rextsize: u32,      // 实时扩展块大小
     pub agblocks: u32,      // 块组大小 最后一个AG的实际大小可能会不一样
@@ -91,12 +96,15 @@ impl SuperBlock {
 // AG第二个扇区包括两个空闲空间的B+树和AG空闲空间
 // XFS_BTNUM_AGF Btree number 0 is bno, 1 is cnt.  This value gives the size of the arrays below.
 const AgfBtNum: usize = 3;
-const AgfMagicNum: u32 = 0x01231023;
+pub const AgfBtBno: usize = 0;
+pub const AgfBtCnt: usize = 1;
+pub const AgfMagicNum: u32 = 0x01231023;
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Agf {
-    magicnum: u32,   // AG扇区的 Magic Number
-    versionnum: u32, // 版本号
-    seqno: u32,      // 扇区的 AG 序号，from 0
-    length: u32,     // AG 中有多少块，除了最后一块外都相同，等于 agblocks
+    pub magicnum: u32,   // AG扇区的 Magic Number
+    pub versionnum: u32, // 版本号
+    pub seqno: u32,      // 扇区的 AG 序号，from 0
+    pub length: u32,     // AG 中有多少块，除了最后一块外都相同，等于 agblocks
     /**
      * 从下标 0 开始，依次是
      * bnoroot  表示“以块号为索引的 FS B+tree 根节点”的块号
@@ -127,7 +135,8 @@ impl Agf {
     // xfs_agfblock_init
     // * `agno` - AG number from 0
     // * `agblocks` - AG real size in blocks
-    pub fn new(agno: u32, agblocks: u32) -> Self {
+    // * `uuid` - 文件系统的 UUID
+    pub fn new(agno: u32, agblocks: u32, uuid: UUID) -> Self {
         Agf {
             magicnum: AgfMagicNum,
             versionnum: 0,
@@ -143,7 +152,7 @@ impl Agf {
             freeblks: 0,
             longest: 0,
             btreeblks: 0,
-            uuid: uuid(),
+            uuid,
             spare64: [0; 16],
             lsn: 0,
             crc: 0,
@@ -152,60 +161,194 @@ impl Agf {
     }
 }
 
+pub const AgflMagicNum: u32 = 0x5841464c; // "XAFL"
+// AGFL 中未使用的槽位
+pub const NullAgBlock: u32 = u32::MAX;
+// Agfl 头部编码后的大小，之后的整个扇区都是大端序的 AG 内块号
+pub const AGFL_HDR_SIZE: usize = 36;
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Agfl {
-    magicnum: u32, // AGFL 的 Magic Number
-    seqno: u32,    // AGFL 的隶属 AG 序号，from 0
-    uuid: UUID,    // AGFL 的UUID
-    lsn: u64,      // 最后写入 AGFL 的日志 SN（序列号）
-    crc: u32,      // AGFL 的 CRC 校验值
-                   // 剩余的整个扇区空间都是 AGFL 的有效部分
-}
-
-
-pub struct Agi {
-    magicnum: u32,   // AGInode 的 Magic Number = XAGI
-    versionnum: u32, // AGInode 的版本号
-    seqno: u32,      // 所属 AG 的序号，from 0
-    length: u32,     // 当前 AG 的大小，单位是块
+    pub magicnum: u32, // AGFL 的 Magic Number
+    pub seqno: u32,    // AGFL 的隶属 AG 序号，from 0
+    pub uuid: UUID,    // AGFL 的UUID
+    pub lsn: u64,      // 最后写入 AGFL 的日志 SN（序列号）
+    pub crc: u32,      // AGFL 的 CRC 校验值
+    // 剩余的整个扇区空间都是 AGFL 的有效部分，作为环形数组使用，
+    // 有效部分由 AGF 的 flfirst、fllast、flcount 描述
+    #[serde(skip)]
+    pub bno: Vec<u32>,
+}
+
+impl Agfl {
+    // * `sectsize` - 扇区大小，决定槽位数
+    // * `uuid` - 文件系统的 UUID
+    pub fn new(agno: u32, sectsize: usize, uuid: UUID) -> Self {
+        Agfl {
+            magicnum: AgflMagicNum,
+            seqno: agno,
+            uuid,
+            lsn: 0,
+            crc: 0,
+            bno: vec![NullAgBlock; (sectsize - AGFL_HDR_SIZE) / 4],
+        }
+    }
 
-    count: u32,     // 当前 AG 已分配的 inode 数量
-    root: u32,      // inobt 根节点的位置（块号）
-    level: u32,     // inobt 的深度
-    freecount: u32, // 已分配但尚未使用的 inode 数量
+    /// 编码为一个完整的扇区
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = bincode::serialize(self).unwrap();
+        for agbno in self.bno.iter() {
+            buf.extend_from_slice(&agbno.to_be_bytes());
+        }
+        buf
+    }
 
-    newino: u32,         // 最新分配的 inode
-    dirino: u32,         // 空余字段
-    unlinked: [u32; 64], //哈希表，记录已经 unlink 但仍然被引用的 inode。默认为全 -1，代表无占用。
-    uuid: UUID,          // 当前 文件系统的 UUID
-    crc: u32,            // AGI 扇区的 CRC 校验值
-    pad32: u32,          // 填充对齐
-    lsn: u64,            // 最后写入 AGI 的日志 SN（序列号）
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let mut agfl: Agfl = bincode::deserialize(buf).ok()?;
+        agfl.bno = buf
+            .get(AGFL_HDR_SIZE..)?
+            .chunks_exact(4)
+            .map(u32::decode)
+            .collect();
+        Some(agfl)
+    }
+}
 
-    freeRoot: u32,  // finobt （空闲 inode 树）的根节点
-    freeLevel: u32, // finobt 的层级
 
-    iblocks: u32, // inobt 已用块数。需要启用 inobt count 特性
-    fblocks: u32, // finobt 已用块数。需要启用 inobt count 特性
+pub const AgiMagicNum: u32 = 0x58414749; // "XAGI"
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Agi {
+    pub magicnum: u32,   // AGInode 的 Magic Number = XAGI
+    pub versionnum: u32, // AGInode 的版本号
+    pub seqno: u32,      // 所属 AG 的序号，from 0
+    pub length: u32,     // 当前 AG 的大小，单位是块
+
+    pub count: u32,     // 当前 AG 已分配的 inode 数量
+    pub root: u32,      // inobt 根节点的位置（块号）
+    pub level: u32,     // inobt 的深度
+    pub freecount: u32, // 已分配但尚未使用的 inode 数量
+
+    pub newino: u32, // 最新分配的 inode
+    pub dirino: u32, // 空余字段
+    #[serde(with = "BigArray")]
+    pub unlinked: [u32; 64], //哈希表，记录已经 unlink 但仍然被引用的 inode。默认为全 -1，代表无占用。
+    pub uuid: UUID,          // 当前 文件系统的 UUID
+    pub crc: u32,            // AGI 扇区的 CRC 校验值
+    pub pad32: u32,          // 填充对齐
+    pub lsn: u64,            // 最后写入 AGI 的日志 SN（序列号）
+
+    pub freeRoot: u32,  // finobt （空闲 inode 树）的根节点
+    pub freeLevel: u32, // finobt 的层级
+
+    pub iblocks: u32, // inobt 已用块数。需要启用 inobt count 特性
+    pub fblocks: u32, // finobt 已用块数。需要启用 inobt count 特性
+}
+
+impl Agi {
+    // xfs_agiblock_init
+    // * `agno` - AG number from 0
+    // * `agblocks` - AG real size in blocks
+    // * `uuid` - 文件系统的 UUID
+    pub fn new(agno: u32, agblocks: u32, uuid: UUID) -> Self {
+        Agi {
+            magicnum: AgiMagicNum,
+            versionnum: 0,
+            seqno: agno,
+            length: agblocks,
+            count: 0,
+            root: 0,
+            level: 0,
+            freecount: 0,
+            newino: u32::MAX,
+            dirino: u32::MAX,
+            unlinked: [u32::MAX; 64],
+            uuid,
+            crc: 0,
+            pad32: 0,
+            lsn: 0,
+            freeRoot: 0,
+            freeLevel: 0,
+            iblocks: 0,
+            fblocks: 0,
+        }
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct InodeBtreeRecord {
-    startino: u32, // 一个 inode chunk 里 inode num 最小的那 inode，也就是这个 chunk 的起始 inode
-    holemask: u16, // 空洞掩码。稀疏 inode 允许以小于 chunk 的大小分配 inodes，从而 chunk 中有的位置需要跳过。
+    pub startino: u32, // 一个 inode chunk 里 inode num 最小的那 inode，也就是这个 chunk 的起始 inode
+    pub holemask: u16, // 空洞掩码。稀疏 inode 允许以小于 chunk 的大小分配 inodes，从而 chunk 中有的位置需要跳过。
     // 16 位，每位代表 4 个连续 inode 空洞。
-    count: u8, // 表示一共有多少已分配的 inode，当未启用 sparse 时为 64，但若启用了 sparse，则为 64 - 4 * n(holemask)
-    freecount: u8, // 当前记录的空余 inode 数量（已分配但尚未使用的 inode 数量）
-    free: u64, // finode 位图，64 位对应 inode chunk 里的 inode 的空闲情况。1 表示可用。
+    pub count: u8, // 表示一共有多少已分配的 inode，当未启用 sparse 时为 64，但若启用了 sparse，则为 64 - 4 * n(holemask)
+    pub freecount: u8, // 当前记录的空余 inode 数量（已分配但尚未使用的 inode 数量）
+    pub free: u64, // finode 位图，64 位对应 inode chunk 里的 inode 的空闲情况。1 表示可用。
+}
+
+// inobt 以 chunk 的起始 inode 为 key
+impl BtreeRecord for InodeBtreeRecord {
+    type Key = u32;
+    const SIZE: usize = 16;
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.startino.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.holemask.to_be_bytes());
+        buf[6] = self.count;
+        buf[7] = self.freecount;
+        buf[8..16].copy_from_slice(&self.free.to_be_bytes());
+    }
+    fn decode(buf: &[u8]) -> Self {
+        InodeBtreeRecord {
+            startino: u32::decode(&buf[0..4]),
+            holemask: u16::from_be_bytes([buf[4], buf[5]]),
+            count: buf[6],
+            freecount: buf[7],
+            free: u64::decode(&buf[8..16]),
+        }
+    }
+    fn key(&self) -> u32 {
+        self.startino
+    }
 }
 
 // unix 纳秒时间戳
-struct timestamp {
-    sec: u32,
-    nsec: u32,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct timestamp {
+    pub sec: u32,
+    pub nsec: u32,
 }
 
-type InodeFlags = u16;
+impl timestamp {
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    // 编码为 sec(4) nsec(4)，大端序
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.sec.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.nsec.to_be_bytes());
+    }
 
-enum InodeFlag {
+    fn decode(buf: &[u8]) -> Self {
+        timestamp {
+            sec: u32::decode(&buf[0..4]),
+            nsec: u32::decode(&buf[4..8]),
+        }
+    }
+}
+
+impl From<SystemTime> for timestamp {
+    fn from(t: SystemTime) -> Self {
+        let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+        timestamp {
+            sec: d.as_secs() as u32,
+            nsec: d.subsec_nanos(),
+        }
+    }
+}
+
+pub type InodeFlags = u16;
+
+// Dinode::flags 中的各位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeFlag {
     XfsDiflagRealtime = 1 << 0, // 表示当前 inode 的数据位于在 realtime 设备上。
     XfsDiflagPrealloc = 1 << 1, // 表示当前 inode 含有预分配的 extent。
     XfsDiflagNewrtbm = 1 << 2,  // 表示当前 inode 使用 real-time bitmap格式，参照sb_rbmino。
@@ -223,79 +366,427 @@ enum InodeFlag {
     XfsDiflagFilestream = 1 << 14, // 具有这个标记的目录会提前 reserve AG 的可用空间，并把这部分reserve的空间留给自己下面的inode使用，其它不在此目录下的inode在申请空间时则不会获得这部分空间。
 }
 
-enum InodeFlag2 {
+// Dinode::flags2 中的各位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeFlag2 {
     XFS_DIFLAG2_DAX = 1 << 0, // 是和DAX有关的flag，表明当前inode在支持DAX的persistent-memory设备上，并且以DAX的方式访问。如果给目录设置此flag，则表示其下的inode自动继承这个标志。
     XFS_DIFLAG2_REFLINK = 1 << 1, // 表示当前inode有和其它inode共享的数据块，这和reflink特性有关。
     XFS_DIFLAG2_COWEXTSIZE = 1 << 2, // 和XFS_DIFLAG_EXTSZINHERIT类似，只是用于COW(copy-on-write)操作时的“暗示性”extent size。与di_cowextsize配合使用。如果一个目录具有这个标记，则其下所有inode自动继承。
     XFS_DIFLAG2_BIGTIME = 1 << 3, // 这个标记是目前新加的，是XFS为了解决“2038问题”而新增的特性，增加了XFS支持的时间戳长度。具有这个标记的inode表示使用这个特性。
 }
 
+// 磁盘上的 inode 核心 xfs_dinode，编码为大端序，各字段的偏移见 Dinode::encode
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Dinode {
-    magic: u16,        // IN
-    mode: u16,         // rwx 等权限位。
-    version: u8,       // 版本，3
-    format: u8,        // 格式，常见有 FMT_LOCAL, FMT_EXTENTS, FMT_BTREE. FMT_DEV 用于字符或块设备
-    onlink: u16,       // 已过期
-    uid: u32,          // 文件所有人
-    gid: u32,          // 文件所属组
-    nlink: u32,        // 硬链接计数
-    projid_lo: u16,    // project quote id，暂时用不到
-    projid_hi: u16,    // project quote id，暂时用不到
-    pad: [u8; 6],      // 占位，用不到
-    flushiter: u16,    //
-    atime: timestamp,  // 最后访问时间
-    mtime: timestamp,  // 最后修改时间
-    ctime: timestamp,  // 最后 inode 状态修改时间
-    size: u64, // Inode的大小，对于文件inode来说并不是其实际占用空间的大小，而是看EOF的位置。对于目录inode来说就是目录条目所占的空间。
-    nblocks: u64, // 统计此 inode 占用的文件系统块数。
-    extsize: u32, // 用于实时设备，暂时用不到
-    nextents: u32, // 暂时用不到
-    anextents: u16, // 暂时用不到
-    forkoff: u8, // datafork 和 attrfork 的分界线。乘以8等到真正的偏移字节量
-    aformat: i8, // 指明此inode组织扩展属性数据时使用的数据结构，1 表示 LOCAL
-    dmevmask: u32, // 过期，无意义
-    dmstate: u16, // 过期，无意义
-    flags: InodeFlags, // inode 标记
-    gen: u32,  // 一个随机数，每个 inode 不同
+    pub magic: u16,        // IN
+    pub mode: u16,         // rwx 等权限位。
+    pub version: u8,       // 版本，3
+    pub format: u8,        // 格式，常见有 FMT_LOCAL, FMT_EXTENTS, FMT_BTREE. FMT_DEV 用于字符或块设备
+    pub onlink: u16,       // 已过期
+    pub uid: u32,          // 文件所有人
+    pub gid: u32,          // 文件所属组
+    pub nlink: u32,        // 硬链接计数
+    pub projid_lo: u16,    // project quote id，暂时用不到
+    pub projid_hi: u16,    // project quote id，暂时用不到
+    pub pad: [u8; 6],      // 占位，用不到
+    pub flushiter: u16,    //
+    pub atime: timestamp,  // 最后访问时间
+    pub mtime: timestamp,  // 最后修改时间
+    pub ctime: timestamp,  // 最后 inode 状态修改时间
+    pub size: u64, // Inode的大小，对于文件inode来说并不是其实际占用空间的大小，而是看EOF的位置。对于目录inode来说就是目录条目所占的空间。
+    pub nblocks: u64, // 统计此 inode 占用的文件系统块数。
+    pub extsize: u32, // extent size 提示，以块为单位，XfsDiflagExtsize 置位时有效
+    pub nextents: u32, // 暂时用不到
+    pub anextents: u16, // 暂时用不到
+    pub forkoff: u8, // datafork 和 attrfork 的分界线。乘以8等到真正的偏移字节量
+    pub aformat: i8, // 指明此inode组织扩展属性数据时使用的数据结构，1 表示 LOCAL
+    pub dmevmask: u32, // 过期，无意义
+    pub dmstate: u16, // 过期，无意义
+    pub flags: InodeFlags, // inode 标记
+    pub gen: u32,  // 一个随机数，每个 inode 不同
 
     /* di_next_unlinked is the only non-core field in the old dinode */
-    next_unlinked: u32, // 之前提到 unlinked 哈希表，记录已经 unlink 但仍然被引用的 inode。此处是该哈希表的拉链。
+    pub next_unlinked: u32, // 之前提到 unlinked 哈希表，记录已经 unlink 但仍然被引用的 inode。此处是该哈希表的拉链。
 
     /* start of the extended dinode, writable fields */
-    crc: u32,         // 当前inode的内容的CRC校验值
-    changecount: u64, // inode 的 i_version，每次修改 inode 时加 1
-    lsn: u64,         // 最后写入操作的 Log SN
-    flags2: u64,      //     扩展的 flags，上面的 flags 不够用了
-    cowextsize: u32,  //
-    pad2: [u8; 12],   // 占位
+    pub crc: u32,         // 当前inode的内容的CRC校验值
+    pub changecount: u64, // inode 的 i_version，每次修改 inode 时加 1
+    pub lsn: u64,         // 最后写入操作的 Log SN
+    pub flags2: u64,      //     扩展的 flags，上面的 flags 不够用了
+    pub cowextsize: u32,  //
+    pub pad2: [u8; 12],   // 占位
 
     /* fields only written to during inode creation */
-    crtime: timestamp, // 创建时间
-    ino: u64,          // 绝对 inode number
-    uuid: UUID,
+    pub crtime: timestamp, // 创建时间
+    pub ino: u64,          // 绝对 inode number
+    pub uuid: UUID,
     /* structure must be padded to 64 bit alignment */
 }
 
-enum ExtentState {
+pub const DINODE_MAGIC: u16 = 0x494e; // "IN"
+pub const DINODE_VERSION: u8 = 3;
+// Dinode 核心部分编码后的大小，其后是 data fork 和 attr fork
+pub const DINODE_CORE_SIZE: usize = 176;
+// crc 字段在 inode 中的偏移，计算 CRC 时这 4 个字节视为 0
+pub const DINODE_CRC_OFF: usize = 100;
+
+// Dinode::format 的取值
+pub const DINODE_FMT_DEV: u8 = 0;
+pub const DINODE_FMT_LOCAL: u8 = 1;
+pub const DINODE_FMT_EXTENTS: u8 = 2;
+pub const DINODE_FMT_BTREE: u8 = 3;
+
+impl Dinode {
+    pub fn new(ino: AbsInoNo, mode: u16) -> Self {
+        Dinode {
+            magic: DINODE_MAGIC,
+            mode,
+            version: DINODE_VERSION,
+            format: DINODE_FMT_EXTENTS,
+            onlink: 0,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            projid_lo: 0,
+            projid_hi: 0,
+            pad: [0; 6],
+            flushiter: 0,
+            atime: timestamp::default(),
+            mtime: timestamp::default(),
+            ctime: timestamp::default(),
+            size: 0,
+            nblocks: 0,
+            extsize: 0,
+            nextents: 0,
+            anextents: 0,
+            forkoff: 0,
+            aformat: 0,
+            dmevmask: 0,
+            dmstate: 0,
+            flags: 0,
+            gen: 0,
+            next_unlinked: 0,
+            crc: 0,
+            changecount: 0,
+            lsn: 0,
+            flags2: 0,
+            cowextsize: 0,
+            pad2: [0; 12],
+            crtime: timestamp::default(),
+            ino,
+            uuid: [0; 16],
+        }
+    }
+
+    /// 把核心部分编码到 buf 的前 DINODE_CORE_SIZE 字节，布局与 xfs_dinode 相同
+    ///
+    /// ```text
+    ///   0 magic      2 mode       4 version    5 format     6 onlink
+    ///   8 uid       12 gid       16 nlink     20 projid_lo 22 projid_hi
+    ///  24 pad[6]    30 flushiter 32 atime     40 mtime     48 ctime
+    ///  56 size      64 nblocks   72 extsize   76 nextents  80 anextents
+    ///  82 forkoff   83 aformat   84 dmevmask  88 dmstate   90 flags
+    ///  92 gen       96 next_unlinked         100 crc      104 changecount
+    /// 112 lsn      120 flags2   128 cowextsize           132 pad2[12]
+    /// 144 crtime   152 ino      160 uuid     176
+    /// ```
+    ///
+    /// 时间戳为 sec(4) nsec(4)
+    pub fn encode(&self, buf: &mut [u8]) {
+        let buf = &mut buf[..DINODE_CORE_SIZE];
+        buf[0..2].copy_from_slice(&self.magic.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.mode.to_be_bytes());
+        buf[4] = self.version;
+        buf[5] = self.format;
+        buf[6..8].copy_from_slice(&self.onlink.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.uid.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.gid.to_be_bytes());
+        buf[16..20].copy_from_slice(&self.nlink.to_be_bytes());
+        buf[20..22].copy_from_slice(&self.projid_lo.to_be_bytes());
+        buf[22..24].copy_from_slice(&self.projid_hi.to_be_bytes());
+        buf[24..30].copy_from_slice(&self.pad);
+        buf[30..32].copy_from_slice(&self.flushiter.to_be_bytes());
+        self.atime.encode(&mut buf[32..40]);
+        self.mtime.encode(&mut buf[40..48]);
+        self.ctime.encode(&mut buf[48..56]);
+        buf[56..64].copy_from_slice(&self.size.to_be_bytes());
+        buf[64..72].copy_from_slice(&self.nblocks.to_be_bytes());
+        buf[72..76].copy_from_slice(&self.extsize.to_be_bytes());
+        buf[76..80].copy_from_slice(&self.nextents.to_be_bytes());
+        buf[80..82].copy_from_slice(&self.anextents.to_be_bytes());
+        buf[82] = self.forkoff;
+        buf[83] = self.aformat as u8;
+        buf[84..88].copy_from_slice(&self.dmevmask.to_be_bytes());
+        buf[88..90].copy_from_slice(&self.dmstate.to_be_bytes());
+        buf[90..92].copy_from_slice(&self.flags.to_be_bytes());
+        buf[92..96].copy_from_slice(&self.gen.to_be_bytes());
+        buf[96..100].copy_from_slice(&self.next_unlinked.to_be_bytes());
+        buf[100..104].copy_from_slice(&self.crc.to_be_bytes());
+        buf[104..112].copy_from_slice(&self.changecount.to_be_bytes());
+        buf[112..120].copy_from_slice(&self.lsn.to_be_bytes());
+        buf[120..128].copy_from_slice(&self.flags2.to_be_bytes());
+        buf[128..132].copy_from_slice(&self.cowextsize.to_be_bytes());
+        buf[132..144].copy_from_slice(&self.pad2);
+        self.crtime.encode(&mut buf[144..152]);
+        buf[152..160].copy_from_slice(&self.ino.to_be_bytes());
+        buf[160..176].copy_from_slice(&self.uuid);
+    }
+
+    /// 从 buf 的前 DINODE_CORE_SIZE 字节解码，不做校验
+    pub fn decode(buf: &[u8]) -> Self {
+        let u16_at = |off: usize| u16::from_be_bytes([buf[off], buf[off + 1]]);
+        let u32_at = |off: usize| u32::decode(&buf[off..off + 4]);
+        let u64_at = |off: usize| u64::decode(&buf[off..off + 8]);
+        Dinode {
+            magic: u16_at(0),
+            mode: u16_at(2),
+            version: buf[4],
+            format: buf[5],
+            onlink: u16_at(6),
+            uid: u32_at(8),
+            gid: u32_at(12),
+            nlink: u32_at(16),
+            projid_lo: u16_at(20),
+            projid_hi: u16_at(22),
+            pad: buf[24..30].try_into().unwrap(),
+            flushiter: u16_at(30),
+            atime: timestamp::decode(&buf[32..40]),
+            mtime: timestamp::decode(&buf[40..48]),
+            ctime: timestamp::decode(&buf[48..56]),
+            size: u64_at(56),
+            nblocks: u64_at(64),
+            extsize: u32_at(72),
+            nextents: u32_at(76),
+            anextents: u16_at(80),
+            forkoff: buf[82],
+            aformat: buf[83] as i8,
+            dmevmask: u32_at(84),
+            dmstate: u16_at(88),
+            flags: u16_at(90),
+            gen: u32_at(92),
+            next_unlinked: u32_at(96),
+            crc: u32_at(100),
+            changecount: u64_at(104),
+            lsn: u64_at(112),
+            flags2: u64_at(120),
+            cowextsize: u32_at(128),
+            pad2: buf[132..144].try_into().unwrap(),
+            crtime: timestamp::decode(&buf[144..152]),
+            ino: u64_at(152),
+            uuid: buf[160..176].try_into().unwrap(),
+        }
+    }
+
+    /// 文件类型，即 mode 中的 S_IFMT 部分
+    pub fn file_type(&self) -> u32 {
+        self.mode as u32 & libc::S_IFMT
+    }
+
+    /// 权限位，包括 setuid、setgid 和 sticky
+    pub fn perm(&self) -> u16 {
+        self.mode & 0o7777
+    }
+
+    /// 修改权限位，文件类型不变
+    pub fn set_perm(&mut self, perm: u16) {
+        self.mode = (self.mode & libc::S_IFMT as u16) | (perm & 0o7777);
+    }
+
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+    }
+
+    pub fn inc_nlink(&mut self) {
+        self.nlink += 1;
+    }
+
+    /// 硬链接计数减一，已经为 0 时不变
+    pub fn drop_nlink(&mut self) {
+        self.nlink = self.nlink.saturating_sub(1);
+    }
+
+    /// 把 atime、mtime、ctime 和 crtime 都设为 now，用于新建的 inode
+    pub fn set_all_times(&mut self, now: timestamp) {
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
+        self.crtime = now;
+    }
+
+    pub fn has_flag(&self, flag: InodeFlag) -> bool {
+        self.flags & flag as InodeFlags != 0
+    }
+
+    pub fn set_flag(&mut self, flag: InodeFlag, on: bool) {
+        if on {
+            self.flags |= flag as InodeFlags;
+        } else {
+            self.flags &= !(flag as InodeFlags);
+        }
+    }
+
+    pub fn has_flag2(&self, flag: InodeFlag2) -> bool {
+        self.flags2 & flag as u64 != 0
+    }
+
+    /// 分配时使用的 extent size 提示，以块为单位，没有提示时为 0 xfs_get_extsz_hint
+    pub fn extsize_hint(&self) -> u64 {
+        if self.has_flag(InodeFlag::XfsDiflagExtsize) {
+            self.extsize as u64
+        } else {
+            0
+        }
+    }
+
+    /// data fork 的大小。forkoff 为 0 时没有 attr fork，data fork 占满核心之后的空间
+    pub fn data_fork_size(&self, inodesize: u16) -> usize {
+        match self.forkoff {
+            0 => inodesize as usize - DINODE_CORE_SIZE,
+            off => off as usize * 8,
+        }
+    }
+
+    /// attr fork 在 inode 中的偏移和大小，没有 attr fork 时返回 None
+    pub fn attr_fork(&self, inodesize: u16) -> Option<(usize, usize)> {
+        let off = DINODE_CORE_SIZE + self.data_fork_size(inodesize);
+        (self.forkoff != 0).then_some((off, inodesize as usize - off))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentState {
     ExtNorm,         // 正常状态。有数据写入状态
     ExtUnwritten,    // 表示当前extent处于预分配但是还没有实际数据写入的状态
     ExtDmapiOffline, // 暂时用不到
     ExtInvalid,      // 暂时用不到
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BmbtRecord {
-    startoff: u64,      // 文件的逻辑偏移块号，属于文件size内的逻辑偏移
-    startblock: u32,    // 此extent相对于整个文件系统的起始物理块号。
-    blockcount: u64,    // 此extent包含多少个块。
-    state: ExtentState, // 此extent的一个标记位
+    pub startoff: u64,      // 文件的逻辑偏移块号，属于文件size内的逻辑偏移
+    pub startblock: u64,    // 此extent相对于整个文件系统的起始物理块号。
+    pub blockcount: u64,    // 此extent包含多少个块。
+    pub state: ExtentState, // 此extent的一个标记位
+}
+
+// 打包格式中各字段的位数
+pub const BMBT_STARTOFF_BITS: u32 = 54;
+pub const BMBT_STARTBLOCK_BITS: u32 = 52;
+pub const BMBT_BLOCKCOUNT_BITS: u32 = 21;
+// 一个 extent 最多包含的块数
+pub const MAX_BMBT_EXTLEN: u64 = (1 << BMBT_BLOCKCOUNT_BITS) - 1;
+
+// 磁盘上打包的 extent 记录 xfs_bmbt_rec
+//
+// l0: unwritten(1) startoff(54) startblock 高 9 位
+// l1: startblock 低 43 位 blockcount(21)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BmbtRec {
+    pub l0: u64,
+    pub l1: u64,
+}
+
+impl From<&BmbtRecord> for BmbtRec {
+    /// xfs_bmbt_disk_set_all
+    fn from(rec: &BmbtRecord) -> Self {
+        debug_assert!(rec.startoff >> BMBT_STARTOFF_BITS == 0);
+        debug_assert!(rec.startblock >> BMBT_STARTBLOCK_BITS == 0);
+        debug_assert!(rec.blockcount <= MAX_BMBT_EXTLEN);
+        debug_assert!(matches!(
+            rec.state,
+            ExtentState::ExtNorm | ExtentState::ExtUnwritten
+        ));
+        let unwritten = (rec.state == ExtentState::ExtUnwritten) as u64;
+        let startoff = rec.startoff & ((1 << BMBT_STARTOFF_BITS) - 1);
+        let startblock = rec.startblock & ((1 << BMBT_STARTBLOCK_BITS) - 1);
+        BmbtRec {
+            l0: unwritten << 63 | startoff << 9 | startblock >> 43,
+            l1: startblock << 21 | (rec.blockcount & MAX_BMBT_EXTLEN),
+        }
+    }
+}
+
+impl From<BmbtRec> for BmbtRecord {
+    /// xfs_bmbt_disk_get_all
+    fn from(rec: BmbtRec) -> Self {
+        BmbtRecord {
+            startoff: (rec.l0 >> 9) & ((1 << BMBT_STARTOFF_BITS) - 1),
+            startblock: (rec.l0 & 0x1ff) << 43 | rec.l1 >> 21,
+            blockcount: rec.l1 & MAX_BMBT_EXTLEN,
+            state: match rec.l0 >> 63 {
+                0 => ExtentState::ExtNorm,
+                _ => ExtentState::ExtUnwritten,
+            },
+        }
+    }
+}
+
+// bmbt 以文件内的逻辑块号为 key，磁盘上保存打包的 BmbtRec，l0 和 l1 都是大端序
+impl BtreeRecord for BmbtRecord {
+    type Key = u64;
+    const SIZE: usize = 16;
+    fn encode(&self, buf: &mut [u8]) {
+        let rec = BmbtRec::from(self);
+        buf[0..8].copy_from_slice(&rec.l0.to_be_bytes());
+        buf[8..16].copy_from_slice(&rec.l1.to_be_bytes());
+    }
+    fn decode(buf: &[u8]) -> Self {
+        BmbtRec {
+            l0: u64::decode(&buf[0..8]),
+            l1: u64::decode(&buf[8..16]),
+        }
+        .into()
+    }
+    fn key(&self) -> u64 {
+        self.startoff
+    }
 }
 
+// data fork 为 btree 格式时，inode 中保存的 bmbt 根 xfs_bmdr_block
+//
+// 编码格式：level(2) numrecs(2)，之后是 numrecs 个 key(8)，再之后是同样多个子节点的 fsbno(8)
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BmdrBlock {
-    level: u8, // 深度，0 表示叶节点
-    numrecs: u8, // 当前block里有多少records
-               // 后面是 records，键值对
-               // 如果是叶节点：key 是逻辑块号，value 是物理块号
-               // 如果是中间节点：key 是逻辑块起始地址，value 是子节点的指针
+    pub level: u16,     // 深度，子节点是磁盘上 bmbt 的根节点，因此等于 bmbt 的层数
+    pub keys: Vec<u64>, // 每个子树中最小的文件逻辑块号
+    pub ptrs: Vec<u64>, // 子节点的 fsbno
+}
+
+impl BmdrBlock {
+    pub const HDR_SIZE: usize = 4;
+
+    /// 编码后的长度
+    pub fn size(&self) -> usize {
+        Self::HDR_SIZE + self.keys.len() * 16
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        let n = self.keys.len();
+        buf[0..2].copy_from_slice(&self.level.to_be_bytes());
+        buf[2..4].copy_from_slice(&(n as u16).to_be_bytes());
+        for (i, (key, ptr)) in self.keys.iter().zip(self.ptrs.iter()).enumerate() {
+            let off = Self::HDR_SIZE + i * 8;
+            buf[off..off + 8].copy_from_slice(&key.to_be_bytes());
+            let off = off + n * 8;
+            buf[off..off + 8].copy_from_slice(&ptr.to_be_bytes());
+        }
+    }
+
+    /// 从 data fork 解码，记录数超出 buf 时返回 None
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let level = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]);
+        let n = u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?]) as usize;
+        if Self::HDR_SIZE + n * 16 > buf.len() {
+            return None;
+        }
+        let at = |off: usize| u64::decode(&buf[off..off + 8]);
+        Some(BmdrBlock {
+            level,
+            keys: (0..n).map(|i| at(Self::HDR_SIZE + i * 8)).collect(),
+            ptrs: (0..n).map(|i| at(Self::HDR_SIZE + (n + i) * 8)).collect(),
+        })
+    }
 }
 
 // xfs_dir2_sf_hdr
@@ -317,13 +808,16 @@ pub struct DirDataHeader {
     pad: u32, /* 64 bit alignment */
 }
 
+pub const DIR_BLOCK_MAGIC: u32 = 0x58444233; // "XDB3"
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DirBlockHeader {
-    magic: u32,
-    crc: u32,
-    blkno: u64,
-    lsn: u64,
-    uuid: UUID,
-    owner: u64,
+    pub magic: u32,
+    pub crc: u32,
+    pub blkno: u64,
+    pub lsn: u64,
+    pub uuid: UUID,
+    pub owner: u64,
 }
 
 pub struct DirDataFree {
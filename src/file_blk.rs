@@ -1,57 +1,102 @@
 use std::{
     fs::File,
-    io::{Read, Seek, SeekFrom, Write},
+    io::{self, Seek, SeekFrom},
+    ops::Range,
+    os::unix::{fs::FileExt, io::AsRawFd},
 };
 
 use crate::block_dev::BlockDevice;
 
 pub struct FileBlockDevice {
     file: File,
+    size: u64, // 文件或块设备的字节大小，打开时确定
 }
 
 impl FileBlockDevice {
-    pub fn new(path: &str) -> Self {
-        FileBlockDevice {
-            file: File::options().read(true).write(true).open(path).unwrap(),
-        }
+    pub fn new(path: &str) -> io::Result<Self> {
+        let mut file = File::options().read(true).write(true).open(path)?;
+        // 块设备的 metadata 中长度为 0，因此通过 seek 获取大小
+        let size = file.seek(SeekFrom::End(0))?;
+        Ok(FileBlockDevice { file, size })
     }
-    pub fn create(path: &str, size: usize) -> Self {
-        let output = File::create(path);
-        if output.is_err() {
-            panic!("Failed to create file {}", path);
-        }
-        let file = output.unwrap();
-        file.set_len(size as u64).unwrap();
+    pub fn create(path: &str, size: usize) -> io::Result<Self> {
+        let file = File::create(path)?;
+        file.set_len(size as u64)?;
         drop(file);
         FileBlockDevice::new(path)
     }
+}
 
-    // drop
-    pub fn drop(&mut self) {
-        self.file.sync_all().unwrap();
+impl Drop for FileBlockDevice {
+    fn drop(&mut self) {
+        // 析构时无法返回错误，需要确认落盘的调用者应当先调用 flush
+        let _ = self.file.sync_data();
     }
 }
 
-const PHY_BLOCKSIZE: u16 = 512;
+pub const PHY_BLOCKSIZE: u16 = 512;
+
+impl FileBlockDevice {
+    /// 检查读写是否超出文件末尾
+    fn check_range(&self, block_id: usize, len: usize) -> io::Result<u64> {
+        let offset = block_id as u64 * PHY_BLOCKSIZE as u64;
+        if offset + len as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("block {} is out of range", block_id),
+            ));
+        }
+        Ok(offset)
+    }
+}
 
 impl BlockDevice for FileBlockDevice {
-    fn read_block(self: &FileBlockDevice, block_id: usize, buf: &mut [u8]) -> bool {
-        let mut file = self.file.try_clone().unwrap();
-        file.seek(SeekFrom::Start((block_id * PHY_BLOCKSIZE as usize) as u64))
-            .unwrap();
-        file.read_exact(buf).unwrap();
-        return true;
+    fn read_block(self: &FileBlockDevice, block_id: usize, buf: &mut [u8]) -> io::Result<()> {
+        let offset = self.check_range(block_id, buf.len())?;
+        // 读到的字节不足时返回 UnexpectedEof
+        self.file.read_exact_at(buf, offset)
     }
 
-    fn write_block(self: &FileBlockDevice, block_id: usize, buf: &[u8]) -> bool {
-        let mut file = self.file.try_clone().unwrap();
-        file.seek(std::io::SeekFrom::Start((block_id * PHY_BLOCKSIZE as usize) as u64))
-            .unwrap();
-        file.write_all(buf).unwrap();
-        return true;
+    fn write_block(self: &FileBlockDevice, block_id: usize, buf: &[u8]) -> io::Result<()> {
+        let offset = self.check_range(block_id, buf.len())?;
+        self.file.write_all_at(buf, offset)
     }
 
     fn get_phy_block_size(self: &FileBlockDevice) -> u16 {
         PHY_BLOCKSIZE 
     }
+
+    fn num_blocks(self: &FileBlockDevice) -> usize {
+        (self.size / PHY_BLOCKSIZE as u64) as usize
+    }
+
+    fn flush(self: &FileBlockDevice) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// 在镜像文件中打洞，使其保持稀疏
+    fn discard(self: &FileBlockDevice, blocks: Range<usize>) -> io::Result<()> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let offset = self.check_range(blocks.start, 0)?;
+        let len = self.check_range(blocks.end, 0)? - offset;
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        // 文件系统不支持打洞时忽略，discard 只是提示
+        match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) => Ok(()),
+            _ => Err(err),
+        }
+    }
 }
@@ -2,16 +2,18 @@
 use crate::util::pad_zeroes;
 #[cfg(test)]
 use crate::{block_dev::BlockDevice, file_blk::FileBlockDevice};
+#[cfg(test)]
+use std::os::unix::fs::MetadataExt;
 
 #[test]
 fn test_file_blk() {
     let path = "test_file_blk.bin";
     let size = 1024 * 1024 * 50; // 50MB
-    let file_blk = FileBlockDevice::create(path, size);
+    let file_blk = FileBlockDevice::create(path, size).unwrap();
     let mut buf = [0u8; 512];
     let buf_to_write: [u8; 512] = pad_zeroes([1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
-    file_blk.write_block(0, &buf_to_write);
-    file_blk.read_block(0, &mut buf);
+    file_blk.write_block(0, &buf_to_write).unwrap();
+    file_blk.read_block(0, &mut buf).unwrap();
     let mut long_buf_to_write: [u8; 5120] = pad_zeroes([]);
     for i in 0..512 {
         long_buf_to_write[i * 8] = 'A' as u8 + i as u8 % 26;
@@ -20,6 +22,37 @@ fn test_file_blk() {
         long_buf_to_write[i] = '@' as u8;
     }
     long_buf_to_write[5120 - 1] = '#' as u8;
-    file_blk.write_all_at(0x00000100, long_buf_to_write.as_mut());
+    file_blk.write_all_at(0x00000100, long_buf_to_write.as_mut()).unwrap();
     assert_eq!(buf, buf_to_write);
 }
+
+#[test]
+fn test_file_blk_out_of_range() {
+    let path = "test_file_blk_range.bin";
+    let file_blk = FileBlockDevice::create(path, 4 * 512).unwrap();
+    assert_eq!(file_blk.num_blocks(), 4);
+    let mut buf = [0u8; 512];
+    assert!(file_blk.read_block(3, &mut buf).is_ok());
+    // 越界读写返回错误而不是 panic
+    assert!(file_blk.read_block(4, &mut buf).is_err());
+    assert!(file_blk.write_block(4, &buf).is_err());
+    assert!(file_blk.read_all_at(3 * 512 + 100, &mut buf).is_err());
+}
+
+#[test]
+fn test_file_blk_discard() {
+    let path = "test_file_blk_discard.bin";
+    let file_blk = FileBlockDevice::create(path, 64 * 4096).unwrap();
+    file_blk.write_all_at(0, &vec![0xab; 64 * 4096]).unwrap();
+    file_blk.flush().unwrap();
+    let allocated = std::fs::metadata(path).unwrap().blocks();
+
+    // 打洞后读出为 0，且文件占用的空间变小
+    file_blk.discard(8..64 * 8).unwrap();
+    let mut buf = [0u8; 512];
+    file_blk.read_block(8, &mut buf).unwrap();
+    assert_eq!(buf, [0u8; 512]);
+    file_blk.read_block(7, &mut buf).unwrap();
+    assert_eq!(buf, [0xab; 512]);
+    assert!(std::fs::metadata(path).unwrap().blocks() < allocated);
+}
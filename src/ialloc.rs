@@ -0,0 +1,447 @@
+//! inode 的分配
+//!
+//! inode 以 64 个为一组（chunk）从 AG 的空闲空间中分配，每个 chunk 在 inobt 中有一条记录，
+//! 记录的 `free` 位图标记其中哪些 inode 空闲。finobt 只保存还有空闲 inode 的记录，
+//! 分配时从 finobt 中查找，不必遍历整棵 inobt。
+//!
+//! 启用稀疏 inode 后，完整的 chunk 按 chunk 大小对齐。AG 中找不到能放下对齐 chunk 的空间时，
+//! 只分配 chunk 的一部分，未分配的部分记在 `holemask` 中，之后可以继续补上。
+use std::sync::atomic::Ordering;
+
+use libc::{EINVAL, EIO, ENOSPC};
+
+use crate::{
+    block_dev::BlockDevice,
+    btree::{Btree, BtreeAlloc, UndoLog},
+    dstruct::{AbsInoNo, InodeBtreeRecord, SB_FEAT_SPARSE_INODES},
+    mstruct::AgiCtx,
+    pound_fs::{errno, FsResult, MountPoint},
+};
+
+// 每个 chunk 中的 inode 数 XFS_INODES_PER_CHUNK
+pub const INODES_PER_CHUNK: u32 = 64;
+// holemask 中每一位代表的 inode 数 XFS_INODES_PER_HOLEMASK_BIT
+pub const INODES_PER_HOLEMASK_BIT: u32 = 4;
+
+impl InodeBtreeRecord {
+    /// 把 holemask 展开为 inode 位图，1 表示该 inode 在空洞中
+    pub fn holes(&self) -> u64 {
+        (0..16)
+            .filter(|i| self.holemask & (1 << i) != 0)
+            .fold(0, |acc, i| acc | (0xf << (i * INODES_PER_HOLEMASK_BIT)))
+    }
+
+    /// chunk 中第 idx 个 inode 是否已经分配了空间
+    pub fn contains(&self, idx: u32) -> bool {
+        idx < INODES_PER_CHUNK && self.holes() & (1 << idx) == 0
+    }
+}
+
+/// 从 AG 的空闲空间中为 inobt 和 finobt 分配块，释放的块等操作成功后再还给 AG
+struct AgBtreeAlloc<'m> {
+    mp: &'m MountPoint<'m>,
+    agno: u32,
+    blocks: i64,         // 树占用的块数的变化
+    allocated: Vec<u64>, // 新分配的块
+    freed: Vec<u64>,     // 不再使用的块
+}
+
+impl<'m> BtreeAlloc for AgBtreeAlloc<'m> {
+    fn alloc_block(&mut self) -> FsResult<u64> {
+        let near = self.mp.ino_root_block();
+        let (agbno, _) = self.mp.with_reserved(1, |resv| {
+            self.mp
+                .with_agf_resv(self.agno, resv, |ctx| ctx.alloc_extent(1, 1, Some(near)))
+        })?;
+        self.blocks += 1;
+        let fsbno = self.mp.agbno_to_fsbno(self.agno, agbno);
+        self.allocated.push(fsbno);
+        Ok(fsbno)
+    }
+
+    fn free_block(&mut self, fsbno: u64) -> FsResult<()> {
+        self.freed.push(fsbno);
+        self.blocks -= 1;
+        Ok(())
+    }
+}
+
+impl<'a> MountPoint<'a> {
+    /// inode 号转为 (agno, AG 内 inode 号)
+    pub fn ino_to_agino(&self, ino: AbsInoNo) -> (u32, u32) {
+        let sb = &self.superblock;
+        let bits = sb.agblocks_bits + sb.inpblock_bits;
+        ((ino >> bits) as u32, (ino & ((1 << bits) - 1)) as u32)
+    }
+
+    /// AG 内 inode 号转为 inode 号
+    pub fn agino_to_ino(&self, agno: u32, agino: u32) -> AbsInoNo {
+        let sb = &self.superblock;
+        ((agno as u64) << (sb.agblocks_bits + sb.inpblock_bits)) | agino as u64
+    }
+
+    /// 一次分配 inode chunk 占用的块数，一个块中的 inode 多于一个 chunk 时为 1
+    pub fn ialloc_blocks(&self) -> u32 {
+        (INODES_PER_CHUNK >> self.superblock.inpblock_bits).max(1)
+    }
+
+    /// 一次分配的 inode 数，是 INODES_PER_CHUNK 的整数倍
+    pub fn ialloc_inos(&self) -> u32 {
+        self.ialloc_blocks() << self.superblock.inpblock_bits
+    }
+
+    /// 是否启用了稀疏 inode chunk
+    pub fn has_sparse_inodes(&self) -> bool {
+        self.superblock.flags & SB_FEAT_SPARSE_INODES != 0
+    }
+
+    /// 稀疏分配时一次分配的块数，至少包含 holemask 的一位
+    pub fn sparse_alloc_blocks(&self) -> u32 {
+        (INODES_PER_HOLEMASK_BIT >> self.superblock.inpblock_bits).max(1)
+    }
+
+    /// 锁住第 agno 个 AG 的 AGI 执行 f，成功后把 inode 数的变化同步到 icount/ifree，
+    /// 写回 AGI 并释放 inobt/finobt 不再使用的块
+    ///
+    /// f 出错时恢复执行前的 AGI 和 inobt/finobt，释放树新分配的块，不写回 AGI。
+    /// 需要同时锁住 AGF 时，必须先锁 AGI
+    pub fn with_agi<T>(
+        &self,
+        agno: u32,
+        f: impl FnOnce(&mut AgiCtx) -> FsResult<T>,
+    ) -> FsResult<T> {
+        let pag = self.perag.get(agno as usize).ok_or(EINVAL)?;
+        let mut agi = pag.agi.lock().unwrap();
+        let saved = agi.clone();
+        let undo = UndoLog::default();
+        let mut ctx = AgiCtx::new(self, &mut agi, &undo);
+        let ret = f(&mut ctx);
+        let (new_blocks, dead_blocks) = (ctx.new_blocks, ctx.dead_blocks);
+        let ret = match ret {
+            Ok(ret) => ret,
+            Err(e) => {
+                *agi = saved;
+                // 返回 f 的错误，dialloc 靠 ENOSPC 换到下一个 AG
+                let _ = undo.rollback(self);
+                for (fsbno, len) in new_blocks {
+                    let _ = self.free_blocks(fsbno, len);
+                }
+                return Err(e);
+            }
+        };
+        // 负数转为 u64 后相加即为相减
+        let dcount = agi.count as i64 - saved.count as i64;
+        let dfree = agi.freecount as i64 - saved.freecount as i64;
+        self.icount.fetch_add(dcount as u64, Ordering::Relaxed);
+        self.ifree.fetch_add(dfree as u64, Ordering::Relaxed);
+        self.write_agi(&agi)?;
+        for (fsbno, len) in dead_blocks {
+            self.free_blocks(fsbno, len)?;
+        }
+        Ok(ret)
+    }
+
+    /// 为 parent 目录下的新 inode 选择 inode 号 xfs_dialloc
+    ///
+    /// 新目录轮流放到各个 AG 中，其他 inode 放在父目录所在的 AG。该 AG 既没有空闲 inode
+    /// 也放不下新的 chunk 时，依次尝试后面的 AG
+    pub fn dialloc(&self, parent: AbsInoNo, mode: u16) -> FsResult<AbsInoNo> {
+        let agcount = self.superblock.agcount;
+        let (pagno, pagino) = self.ino_to_agino(parent);
+        let start = if mode as u32 & libc::S_IFMT == libc::S_IFDIR {
+            self.rotor_ag()
+        } else {
+            pagno % agcount
+        };
+        for i in 0..agcount {
+            let agno = (start + i) % agcount;
+            let near = (agno == pagno).then_some(pagino);
+            match self.with_agi(agno, |ctx| ctx.dialloc(near)) {
+                Ok(agino) => return Ok(self.agino_to_ino(agno, agino)),
+                Err(ENOSPC) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ENOSPC)
+    }
+
+    /// 释放 inode 号 xfs_difree
+    pub fn difree(&self, ino: AbsInoNo) -> FsResult<()> {
+        let (agno, agino) = self.ino_to_agino(ino);
+        self.with_agi(agno, |ctx| ctx.difree(agino))
+    }
+}
+
+impl<'a> AgiCtx<'a> {
+    fn inobt(&self) -> Btree<'a, InodeBtreeRecord> {
+        Btree::from_agi(self.mp, self.agi).with_undo(self.undo)
+    }
+
+    fn finobt(&self) -> Btree<'a, InodeBtreeRecord> {
+        Btree::from_agi_free(self.mp, self.agi).with_undo(self.undo)
+    }
+
+    /// 对 inobt（free 为 false）或 finobt 执行 f，之后把新的根节点、层数和块数写回 AGI
+    fn modify_tree(
+        &mut self,
+        free: bool,
+        f: impl FnOnce(&mut Btree<'a, InodeBtreeRecord>, &mut AgBtreeAlloc) -> FsResult<()>,
+    ) -> FsResult<()> {
+        let mut tree = if free { self.finobt() } else { self.inobt() };
+        let mut alloc = AgBtreeAlloc {
+            mp: self.mp,
+            agno: self.agi.seqno,
+            blocks: 0,
+            allocated: Vec::new(),
+            freed: Vec::new(),
+        };
+        let ret = f(&mut tree, &mut alloc);
+        self.new_blocks
+            .extend(alloc.allocated.iter().map(|&fsbno| (fsbno, 1)));
+        self.dead_blocks
+            .extend(alloc.freed.iter().map(|&fsbno| (fsbno, 1)));
+        let root = self.mp.fsbno_to_agbno(tree.root).1;
+        let (root_ref, level, blocks) = if free {
+            (
+                &mut self.agi.freeRoot,
+                &mut self.agi.freeLevel,
+                &mut self.agi.fblocks,
+            )
+        } else {
+            (
+                &mut self.agi.root,
+                &mut self.agi.level,
+                &mut self.agi.iblocks,
+            )
+        };
+        *root_ref = root;
+        *level = tree.levels;
+        *blocks = (*blocks as i64 + alloc.blocks) as u32;
+        ret
+    }
+
+    /// 分配一个 inode，优先选择离 near 最近的空闲 inode。返回 AG 内 inode 号
+    pub fn dialloc(&mut self, near: Option<u32>) -> FsResult<u32> {
+        if self.agi.freecount == 0 {
+            self.alloc_chunk(near)?;
+        }
+        let mut rec = self.find_free(near.unwrap_or(0))?;
+        let idx = rec.free.trailing_zeros();
+        rec.free &= !(1 << idx);
+        rec.freecount -= 1;
+        self.modify_tree(false, |inobt, _| inobt.update(&rec))?;
+        self.modify_tree(true, |finobt, alloc| {
+            if rec.freecount == 0 {
+                finobt.delete(&rec.startino, alloc)
+            } else {
+                finobt.update(&rec)
+            }
+        })?;
+        self.agi.freecount -= 1;
+        Ok(rec.startino + idx)
+    }
+
+    /// 从 finobt 中找离 agino 最近的、还有空闲 inode 的 chunk
+    fn find_free(&self, agino: u32) -> FsResult<InodeBtreeRecord> {
+        let finobt = self.finobt();
+        let mut cur = finobt.cursor();
+        let left = cur.seek_le(&agino)?;
+        let right = cur.seek_ge(&agino)?;
+        let found = match (left, right) {
+            (Some(l), Some(r)) => {
+                if agino - l.startino <= r.startino - agino {
+                    l
+                } else {
+                    r
+                }
+            }
+            (Some(rec), None) | (None, Some(rec)) => rec,
+            (None, None) => return Err(EIO),
+        };
+        if found.freecount == 0 || found.free == 0 {
+            return Err(EIO);
+        }
+        Ok(found)
+    }
+
+    /// 分配并清零新的 inode chunk，尽量靠近 near，其次靠近上一次分配的 chunk
+    ///
+    /// 启用稀疏 inode 时 chunk 需要对齐，放不下完整的 chunk 时只分配其中一部分
+    fn alloc_chunk(&mut self, near: Option<u32>) -> FsResult<()> {
+        let mp = self.mp;
+        let agno = self.agi.seqno;
+        let bits = mp.superblock.inpblock_bits;
+        let blocks = mp.ialloc_blocks();
+        let (agbno, len) = if mp.has_sparse_inodes() {
+            let align = mp.superblock.sb_inoalignmt.max(1);
+            let alloc_aligned = |len: u32, align: u32| {
+                mp.with_reserved(len as u64, |resv| {
+                    mp.with_agf_resv(agno, resv, |ctx| ctx.alloc_aligned(len, align))
+                })
+            };
+            match alloc_aligned(blocks, align) {
+                Ok(agbno) => (agbno, blocks),
+                Err(ENOSPC) if blocks > 1 => {
+                    let len = mp.sparse_alloc_blocks();
+                    (alloc_aligned(len, len)?, len)
+                }
+                Err(e) => return Err(e),
+            }
+        } else {
+            let hint = near
+                .or((self.agi.newino != u32::MAX).then_some(self.agi.newino))
+                .map(|agino| agino >> bits);
+            let (agbno, _) = mp.with_reserved(blocks as u64, |resv| {
+                mp.with_agf_resv(agno, resv, |ctx| ctx.alloc_extent(blocks, blocks, hint))
+            })?;
+            (agbno, blocks)
+        };
+        let fsbno = mp.agbno_to_fsbno(agno, agbno);
+        let blocksize = mp.superblock.blocksize as usize;
+        let zero = vec![0u8; len as usize * blocksize];
+        if let Err(e) = mp.dev.write_all_at(fsbno as usize * blocksize, &zero) {
+            let _ = mp.free_blocks(fsbno, len as u64);
+            return Err(errno(e));
+        }
+        // 插入记录或之后的操作失败时由 with_agi 释放
+        self.new_blocks.push((fsbno, len as u64));
+
+        if len < blocks {
+            return self.add_sparse(agbno, len);
+        }
+        self.add_chunk(agbno)
+    }
+
+    /// 为从 agbno 开始的完整 chunk 在 inobt 和 finobt 中插入记录
+    fn add_chunk(&mut self, agbno: u32) -> FsResult<()> {
+        let mp = self.mp;
+        let startino = agbno << mp.superblock.inpblock_bits;
+        let inos = mp.ialloc_inos();
+        for off in (0..inos).step_by(INODES_PER_CHUNK as usize) {
+            let rec = InodeBtreeRecord {
+                startino: startino + off,
+                holemask: 0,
+                count: INODES_PER_CHUNK as u8,
+                freecount: INODES_PER_CHUNK as u8,
+                free: u64::MAX,
+            };
+            self.modify_tree(false, |inobt, alloc| inobt.insert(&rec, alloc))?;
+            self.modify_tree(true, |finobt, alloc| finobt.insert(&rec, alloc))?;
+        }
+        self.add_counts(startino, inos);
+        Ok(())
+    }
+
+    /// 把稀疏分配的 [agbno, agbno + len) 加入它所在的对齐 chunk，chunk 的记录不存在时新建
+    fn add_sparse(&mut self, agbno: u32, len: u32) -> FsResult<()> {
+        let bits = self.mp.superblock.inpblock_bits;
+        let blocks = self.mp.ialloc_blocks();
+        let startino = (agbno & !(blocks - 1)) << bits;
+        let off = (agbno << bits) - startino;
+        let inos = len << bits;
+        let mask = (u64::MAX >> (64 - inos)) << off;
+        let filled = (0..16)
+            .filter(|i| mask & (0xf << (i * INODES_PER_HOLEMASK_BIT)) != 0)
+            .fold(0u16, |acc, i| acc | (1 << i));
+
+        let old = self.inobt().get(&startino)?;
+        let mut rec = old.unwrap_or(InodeBtreeRecord {
+            startino,
+            holemask: u16::MAX,
+            count: 0,
+            freecount: 0,
+            free: 0,
+        });
+        if rec.holemask & filled != filled {
+            return Err(EIO);
+        }
+        rec.holemask &= !filled;
+        rec.count += inos as u8;
+        rec.freecount += inos as u8;
+        rec.free |= mask;
+        match old {
+            Some(old) => {
+                self.modify_tree(false, |inobt, _| inobt.update(&rec))?;
+                self.modify_tree(true, |finobt, alloc| {
+                    if old.freecount == 0 {
+                        finobt.insert(&rec, alloc)
+                    } else {
+                        finobt.update(&rec)
+                    }
+                })?;
+            }
+            None => {
+                self.modify_tree(false, |inobt, alloc| inobt.insert(&rec, alloc))?;
+                self.modify_tree(true, |finobt, alloc| finobt.insert(&rec, alloc))?;
+            }
+        }
+        self.add_counts(startino, inos);
+        Ok(())
+    }
+
+    fn add_counts(&mut self, startino: u32, inos: u32) {
+        self.agi.count += inos;
+        self.agi.freecount += inos;
+        self.agi.newino = startino;
+    }
+
+    /// 释放 AG 内 inode 号为 agino 的 inode，chunk 全部空闲时释放它占用的块
+    pub fn difree(&mut self, agino: u32) -> FsResult<()> {
+        let old = self
+            .inobt()
+            .cursor()
+            .seek_le(&agino)?
+            .filter(|rec| rec.contains(agino - rec.startino))
+            .ok_or(EINVAL)?;
+        let bit = 1 << (agino - old.startino);
+        if old.free & bit != 0 {
+            return Err(EIO);
+        }
+        let mut rec = old;
+        rec.free |= bit;
+        rec.freecount += 1;
+        self.agi.freecount += 1;
+
+        // 一个块中有多个 chunk 时，chunk 不会被释放
+        if rec.freecount == rec.count && self.mp.ialloc_inos() == INODES_PER_CHUNK {
+            self.modify_tree(false, |inobt, alloc| inobt.delete(&rec.startino, alloc))?;
+            if old.freecount > 0 {
+                self.modify_tree(true, |finobt, alloc| finobt.delete(&rec.startino, alloc))?;
+            }
+            self.free_chunk(&rec);
+            let inos = rec.count as u32;
+            self.agi.count -= inos;
+            self.agi.freecount -= inos;
+            return Ok(());
+        }
+        self.modify_tree(false, |inobt, _| inobt.update(&rec))?;
+        self.modify_tree(true, |finobt, alloc| {
+            if old.freecount == 0 {
+                finobt.insert(&rec, alloc)
+            } else {
+                finobt.update(&rec)
+            }
+        })
+    }
+
+    /// 释放 chunk 中已分配的块，跳过空洞。块在操作成功后才还给 AG
+    fn free_chunk(&mut self, rec: &InodeBtreeRecord) {
+        let bits = self.mp.superblock.inpblock_bits;
+        let holes = rec.holes();
+        let mut idx = 0;
+        while idx < INODES_PER_CHUNK {
+            if holes & (1 << idx) != 0 {
+                idx += 1;
+                continue;
+            }
+            let start = idx;
+            while idx < INODES_PER_CHUNK && holes & (1 << idx) == 0 {
+                idx += 1;
+            }
+            let agbno = (rec.startino + start) >> bits;
+            let len = (idx - start) >> bits;
+            let fsbno = self.mp.agbno_to_fsbno(self.agi.seqno, agbno);
+            self.dead_blocks.push((fsbno, len as u64));
+        }
+    }
+}
@@ -0,0 +1,247 @@
+#[cfg(test)]
+use std::sync::atomic::Ordering;
+
+#[cfg(test)]
+use libc::{EINVAL, EIO, S_IFREG};
+
+#[cfg(test)]
+use crate::{
+    btree::Btree,
+    btree_test::{mkfs_and_mount, remount, small_mkfs_option, test_mkfs_option},
+    dstruct::{Agi, InodeBtreeRecord},
+    pound_fs::{MkfsOption, MountPoint},
+};
+
+/// inobt 与 AGI 的计数一致，finobt 恰好包含 inobt 中有空闲 inode 的记录
+#[cfg(test)]
+fn check_agi(mp: &MountPoint, agi: &Agi) {
+    let inobt: Vec<InodeBtreeRecord> = Btree::from_agi(mp, agi)
+        .range(..)
+        .map(|r| r.unwrap())
+        .collect();
+    for rec in inobt.iter() {
+        assert_eq!(rec.free.count_ones(), rec.freecount as u32);
+        // 空洞中的 inode 不计入 count，也不会标记为空闲
+        assert_eq!(64 - rec.holes().count_ones(), rec.count as u32);
+        assert_eq!(rec.free & rec.holes(), 0);
+    }
+    let finobt: Vec<InodeBtreeRecord> = Btree::from_agi_free(mp, agi)
+        .range(..)
+        .map(|r| r.unwrap())
+        .collect();
+    let with_free: Vec<InodeBtreeRecord> = inobt
+        .iter()
+        .copied()
+        .filter(|rec| rec.freecount > 0)
+        .collect();
+    assert_eq!(finobt, with_free);
+    assert_eq!(
+        inobt.iter().map(|rec| rec.count as u32).sum::<u32>(),
+        agi.count
+    );
+    assert_eq!(
+        inobt.iter().map(|rec| rec.freecount as u32).sum::<u32>(),
+        agi.freecount
+    );
+}
+
+#[test]
+fn test_ialloc() {
+    let path = "test_ialloc.bin";
+    let mp = mkfs_and_mount(path, test_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    // 根目录所在的 chunk
+    assert_eq!(mp.icount.load(Ordering::Relaxed), 64);
+    assert_eq!(mp.ifree.load(Ordering::Relaxed), 63);
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+
+    let inos: Vec<u64> = (0..100)
+        .map(|_| mp.dialloc(rootino, S_IFREG as u16).unwrap())
+        .collect();
+    let mut sorted = inos.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(sorted.len(), 100);
+    assert!(inos.iter().all(|&ino| mp.ino_to_agino(ino).0 == 0));
+    assert_eq!(mp.icount.load(Ordering::Relaxed), 128);
+    assert_eq!(mp.ifree.load(Ordering::Relaxed), 128 - 101);
+    // 新的 chunk 从 AG 的空闲空间中分配
+    assert_eq!(
+        mp.fdblocks.load(Ordering::Relaxed),
+        fdblocks - mp.ialloc_blocks() as u64
+    );
+    check_agi(&mp, &mp.perag[0].agi.lock().unwrap());
+
+    // 刚释放的 inode 离父目录最近，会被再次分配
+    mp.difree(inos[10]).unwrap();
+    assert_eq!(mp.difree(inos[10]).err(), Some(EIO));
+    assert_eq!(mp.dialloc(rootino, S_IFREG as u16).unwrap(), inos[10]);
+    assert_eq!(mp.difree(mp.agino_to_ino(1, 0)).err(), Some(EINVAL));
+
+    // 全部释放后，新分配的 chunk 归还给空闲空间
+    for &ino in inos.iter() {
+        mp.difree(ino).unwrap();
+    }
+    assert_eq!(mp.icount.load(Ordering::Relaxed), 64);
+    assert_eq!(mp.ifree.load(Ordering::Relaxed), 63);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks);
+    let agi = mp.perag[0].agi.lock().unwrap().clone();
+    assert_eq!((agi.count, agi.freecount), (64, 63));
+    check_agi(&mp, &agi);
+
+    // 出错时撤销 AGI、inobt/finobt 和计数器的修改，新分配的 chunk 归还给空闲空间
+    let agi = mp.perag[1].agi.lock().unwrap().clone();
+    let ret = mp.with_agi(1, |ctx| {
+        ctx.dialloc(None)?;
+        Err::<(), _>(EIO)
+    });
+    assert_eq!(ret.err(), Some(EIO));
+    let restored = mp.perag[1].agi.lock().unwrap().clone();
+    assert_eq!(
+        (restored.count, restored.freecount),
+        (agi.count, agi.freecount)
+    );
+    assert_eq!((restored.root, restored.freeRoot), (agi.root, agi.freeRoot));
+    assert_eq!(mp.icount.load(Ordering::Relaxed), 64);
+    assert_eq!(mp.ifree.load(Ordering::Relaxed), 63);
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks);
+    check_agi(&mp, &restored);
+
+    // 重新挂载后计数器由 AGI 汇总
+    let ino = mp.dialloc(rootino, S_IFREG as u16).unwrap();
+    drop(mp);
+    let mp = remount(path);
+    assert_eq!(mp.ifree.load(Ordering::Relaxed), 62);
+    assert_eq!(mp.difree(ino), Ok(()));
+}
+
+#[test]
+fn test_ialloc_many() {
+    // 块大小为 512 时每个叶子节点只能放 28 条记录，inobt 和 finobt 都会分裂
+    let path = "test_ialloc_many.bin";
+    let mp = mkfs_and_mount(path, small_mkfs_option());
+    assert_eq!(mp.ialloc_blocks(), 32);
+    let rootino = mp.superblock.rootino;
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+
+    let inos: Vec<u64> = (0..3000)
+        .map(|_| mp.dialloc(rootino, S_IFREG as u16).unwrap())
+        .collect();
+    for pag in mp.perag.iter() {
+        check_agi(&mp, &pag.agi.lock().unwrap());
+    }
+    assert!(mp.perag[0].agi.lock().unwrap().level > 1);
+    assert_eq!(mp.icount.load(Ordering::Relaxed) % 64, 0);
+
+    // 隔一个释放一个，finobt 中又有了所有的 chunk
+    for &ino in inos.iter().step_by(2) {
+        mp.difree(ino).unwrap();
+    }
+    {
+        let agi = mp.perag[0].agi.lock().unwrap();
+        check_agi(&mp, &agi);
+        assert_eq!(agi.freeLevel, agi.level);
+    }
+    for &ino in inos.iter().skip(1).step_by(2) {
+        mp.difree(ino).unwrap();
+    }
+    assert_eq!(mp.icount.load(Ordering::Relaxed), 64);
+    assert_eq!(mp.ifree.load(Ordering::Relaxed), 63);
+    let agi = mp.perag[0].agi.lock().unwrap().clone();
+    check_agi(&mp, &agi);
+    assert_eq!((agi.level, agi.freeLevel), (1, 1));
+    assert_eq!((agi.iblocks, agi.fblocks), (0, 0));
+    // 除了留在 AGFL 中的块，其余都已归还
+    let agf = mp.perag[0].agf.lock().unwrap().clone();
+    assert_eq!(
+        mp.fdblocks.load(Ordering::Relaxed),
+        fdblocks - agf.btreeblks as u64
+    );
+}
+
+#[test]
+fn test_ialloc_sparse() {
+    // 每个块 2 个 inode，完整的 chunk 需要 32 个对齐的块，稀疏分配一次 2 个块
+    let path = "test_ialloc_sparse.bin";
+    let mp = mkfs_and_mount(
+        path,
+        MkfsOption {
+            sparse_inodes: true,
+            ..small_mkfs_option()
+        },
+    );
+    assert!(mp.has_sparse_inodes());
+    assert_eq!(mp.superblock.sb_inoalignmt, 32);
+    assert_eq!(mp.sparse_alloc_blocks(), 2);
+    let bits = mp.superblock.inpblock_bits;
+    // 空间充足时仍然分配对齐的完整 chunk
+    let agino = mp.with_agi(2, |ctx| ctx.dialloc(None)).unwrap();
+    assert_eq!((agino >> bits) % 32, 0);
+    check_agi(&mp, &mp.perag[2].agi.lock().unwrap());
+
+    // 占满 AG 1，再在每 32 个块中释放 8 个，剩下的空间放不下对齐的 chunk
+    let prealloc = mp.ag_prealloc_blocks() as u32;
+    mp.with_agf(1, |ctx| {
+        while ctx.alloc_extent(1, u32::MAX, None).is_ok() {}
+        for base in (prealloc.next_multiple_of(32)..ctx.agf.length)
+            .step_by(32)
+            .take(8)
+        {
+            ctx.free_extent(base + 8, 8)?;
+        }
+        Ok(())
+    })
+    .unwrap();
+    let fdblocks = mp.fdblocks.load(Ordering::Relaxed);
+    let icount = mp.icount.load(Ordering::Relaxed);
+
+    let inos: Vec<u32> = (0..20)
+        .map(|_| mp.with_agi(1, |ctx| ctx.dialloc(None)).unwrap())
+        .collect();
+    let agi = mp.perag[1].agi.lock().unwrap().clone();
+    check_agi(&mp, &agi);
+    assert_eq!(agi.count, 20);
+    assert_eq!(agi.freecount, 0);
+    assert_eq!(mp.icount.load(Ordering::Relaxed), icount + 20);
+    let recs: Vec<InodeBtreeRecord> = Btree::from_agi(&mp, &agi)
+        .range(..)
+        .map(|r| r.unwrap())
+        .collect();
+    // 同一个对齐 chunk 中后分配的部分合并到已有的记录中
+    assert!(recs.len() < 5);
+    for rec in recs.iter() {
+        assert_eq!((rec.startino >> bits) % 32, 0);
+        assert_ne!(rec.holemask, 0);
+    }
+    for &agino in inos.iter() {
+        let rec = recs.iter().rfind(|rec| rec.startino <= agino).unwrap();
+        assert!(rec.contains(agino - rec.startino));
+    }
+    // 空洞中的 inode 不能释放
+    let hole = (0..64).find(|&i| !recs[0].contains(i)).unwrap();
+    assert_eq!(
+        mp.with_agi(1, |ctx| ctx.difree(recs[0].startino + hole))
+            .err(),
+        Some(EINVAL)
+    );
+
+    // 全部释放后只归还已分配的块
+    for &agino in inos.iter() {
+        mp.with_agi(1, |ctx| ctx.difree(agino)).unwrap();
+    }
+    let agi = mp.perag[1].agi.lock().unwrap().clone();
+    assert_eq!((agi.count, agi.freecount), (0, 0));
+    assert_eq!(
+        Btree::<InodeBtreeRecord>::from_agi(&mp, &agi)
+            .range(..)
+            .count(),
+        0
+    );
+    assert_eq!(mp.fdblocks.load(Ordering::Relaxed), fdblocks);
+    assert_eq!(mp.icount.load(Ordering::Relaxed), icount);
+
+    // 特性位保存在超级块中
+    drop(mp);
+    let mp = remount(path);
+    assert!(mp.has_sparse_inodes());
+}
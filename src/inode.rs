@@ -0,0 +1,443 @@
+use std::collections::{HashMap, HashSet};
+
+use libc::{EFBIG, EIO, ENOENT};
+use rand::RngCore;
+
+use crate::{
+    alloc::AllocArgs,
+    block_dev::BlockDevice,
+    bmap::BMBT_REC_SIZE,
+    btree::BtreeRecord,
+    dstruct::{
+        timestamp, AbsInoNo, BmbtRecord, BmdrBlock, Dinode, ExtentState, DINODE_CORE_SIZE,
+        DINODE_CRC_OFF, DINODE_FMT_BTREE, DINODE_FMT_EXTENTS, DINODE_MAGIC, DINODE_VERSION,
+        MAX_BMBT_EXTLEN,
+    },
+    pound_fs::{errno, FsResult, MountPoint},
+};
+
+// inode 缓存的容量，超过时写回脏 inode 并清空缓存
+pub const ICACHE_INODES: usize = 4096;
+
+/// 内存中的 inode（xfs_inode），包含 inode 核心以及解码后的 data fork
+#[derive(Clone)]
+pub struct Inode {
+    pub ino: AbsInoNo,
+    pub core: Dinode,
+    pub extents: Vec<BmbtRecord>, // 按 startoff 升序排列
+    pub broot: Option<BmdrBlock>, // data fork 为 btree 格式时 bmbt 的根
+}
+
+impl Inode {
+    pub fn is_dir(&self) -> bool {
+        self.core.file_type() == libc::S_IFDIR
+    }
+
+    /// 更新 mtime 和 ctime
+    pub fn touch(&mut self) {
+        let now = timestamp::now();
+        self.core.mtime = now;
+        self.core.ctime = now;
+    }
+}
+
+/// 已读入内存的 inode xfs_icache
+///
+/// write_inode 只修改缓存中的副本并记为脏，sync 或缓存满时才写回设备
+#[derive(Default)]
+pub struct InodeCache {
+    inodes: HashMap<AbsInoNo, Inode>,
+    dirty: HashSet<AbsInoNo>,
+}
+
+impl<'a> MountPoint<'a> {
+    /// inode 号所在的 AG XFS_INO_TO_AGNO
+    pub fn ino_to_agno(&self, ino: AbsInoNo) -> u32 {
+        self.ino_to_agino(ino).0
+    }
+
+    /// inode 所在的 AG 内块号 XFS_INO_TO_AGBNO
+    pub fn ino_to_agbno(&self, ino: AbsInoNo) -> u32 {
+        self.ino_to_agino(ino).1 >> self.superblock.inpblock_bits
+    }
+
+    /// inode 在块内的槽位 XFS_INO_TO_OFFSET
+    pub fn ino_to_slot(&self, ino: AbsInoNo) -> u32 {
+        (ino & ((1 << self.superblock.inpblock_bits) - 1)) as u32
+    }
+
+    /// 由 AG 号、AG 内块号和槽位组成 inode 号 XFS_AGB_TO_INO
+    pub fn make_ino(&self, agno: u32, agbno: u32, slot: u32) -> AbsInoNo {
+        self.agino_to_ino(agno, (agbno << self.superblock.inpblock_bits) | slot)
+    }
+
+    /// inode 在设备上的字节偏移，inode 号不在任何 AG 的数据区内时返回 None
+    pub fn ino_to_offset(&self, ino: AbsInoNo) -> Option<usize> {
+        let sb = &self.superblock;
+        let agno = self.ino_to_agno(ino);
+        let agbno = self.ino_to_agbno(ino);
+        if agno >= sb.agcount || agbno >= self.ag_block_count(agno) {
+            return None;
+        }
+        let fsbno = self.agbno_to_fsbno(agno, agbno);
+        if fsbno == 0 {
+            return None;
+        }
+        let slot = self.ino_to_slot(ino) as usize;
+        Some(fsbno as usize * sb.blocksize as usize + slot * sb.inodesize as usize)
+    }
+
+    /// 获取 inode，不在缓存中时从设备读入 xfs_iget
+    ///
+    /// 返回的是缓存中的副本，修改后需要用 write_inode 放回
+    pub fn iget(&self, ino: AbsInoNo) -> FsResult<Inode> {
+        let mut cache = self.icache.lock().unwrap();
+        if let Some(ip) = cache.inodes.get(&ino) {
+            return Ok(ip.clone());
+        }
+        let ip = self.read_inode(ino)?;
+        self.icache_insert(&mut cache, ip.clone())?;
+        Ok(ip)
+    }
+
+    /// 从设备读取并校验 inode xfs_iread
+    ///
+    /// 已释放的 inode 全部为 0，返回 ENOENT。魔数、版本、CRC 或 inode 号不符时返回 EIO
+    pub fn read_inode(&self, ino: AbsInoNo) -> FsResult<Inode> {
+        let offset = self.ino_to_offset(ino).ok_or(ENOENT)?;
+        let mut buf = vec![0u8; self.superblock.inodesize as usize];
+        self.dev.read_all_at(offset, &mut buf).map_err(errno)?;
+        if buf.iter().all(|&b| b == 0) {
+            return Err(ENOENT);
+        }
+        let core = Dinode::decode(&buf);
+        if core.magic != DINODE_MAGIC || core.version != DINODE_VERSION || core.ino != ino {
+            return Err(EIO);
+        }
+        if dinode_crc(&buf) != core.crc {
+            return Err(EIO);
+        }
+        // forkoff 超出 inode 时无法确定 data fork 的范围
+        if DINODE_CORE_SIZE + core.data_fork_size(self.superblock.inodesize) > buf.len() {
+            return Err(EIO);
+        }
+        let mut extents = Vec::with_capacity(core.nextents as usize);
+        let mut broot = None;
+        match core.format {
+            DINODE_FMT_EXTENTS => {
+                if core.nextents as usize > self.max_inline_extents(&core) {
+                    return Err(EIO);
+                }
+                for i in 0..core.nextents as usize {
+                    let off = DINODE_CORE_SIZE + i * BMBT_REC_SIZE;
+                    extents.push(BmbtRecord::decode(&buf[off..off + BMBT_REC_SIZE]));
+                }
+            }
+            DINODE_FMT_BTREE => {
+                let fork =
+                    &buf[DINODE_CORE_SIZE..][..core.data_fork_size(self.superblock.inodesize)];
+                let (root, recs) = self.bmap_read_btree(&core, fork)?;
+                broot = Some(root);
+                extents = recs;
+            }
+            _ => {}
+        }
+        Ok(Inode {
+            ino,
+            core,
+            extents,
+            broot,
+        })
+    }
+
+    /// 把修改后的 inode 放回缓存并记为脏 xfs_trans_log_inode
+    pub fn write_inode(&self, ip: &mut Inode) -> FsResult<()> {
+        self.ino_to_offset(ip.ino).ok_or(ENOENT)?;
+        if ip.broot.is_some() {
+            ip.core.format = DINODE_FMT_BTREE;
+        } else if ip.extents.len() > self.max_inline_extents(&ip.core) {
+            return Err(EFBIG);
+        } else {
+            ip.core.format = DINODE_FMT_EXTENTS;
+        }
+        ip.core.nextents = ip.extents.len() as u32;
+        let mut cache = self.icache.lock().unwrap();
+        match cache.inodes.get_mut(&ip.ino) {
+            Some(cached) => *cached = ip.clone(),
+            None => self.icache_insert(&mut cache, ip.clone())?,
+        }
+        cache.dirty.insert(ip.ino);
+        Ok(())
+    }
+
+    /// 把 inode 编码后写到设备上 xfs_iflush
+    ///
+    /// 目前不支持扩展属性，attr fork 写为 0
+    fn iflush(&self, ip: &Inode) -> FsResult<()> {
+        let offset = self.ino_to_offset(ip.ino).ok_or(ENOENT)?;
+        let mut buf = vec![0u8; self.superblock.inodesize as usize];
+        ip.core.encode(&mut buf);
+        match &ip.broot {
+            Some(broot) => broot.encode(&mut buf[DINODE_CORE_SIZE..]),
+            None => {
+                for (i, rec) in ip.extents.iter().enumerate() {
+                    let off = DINODE_CORE_SIZE + i * BMBT_REC_SIZE;
+                    rec.encode(&mut buf[off..off + BMBT_REC_SIZE]);
+                }
+            }
+        }
+        let crc = dinode_crc(&buf);
+        buf[DINODE_CRC_OFF..DINODE_CRC_OFF + 4].copy_from_slice(&crc.to_be_bytes());
+        self.dev.write_all_at(offset, &buf).map_err(errno)
+    }
+
+    /// 写回缓存中所有的脏 inode
+    pub fn flush_inodes(&self) -> FsResult<()> {
+        let mut cache = self.icache.lock().unwrap();
+        self.flush_dirty(&mut cache)
+    }
+
+    fn flush_dirty(&self, cache: &mut InodeCache) -> FsResult<()> {
+        let mut dirty: Vec<AbsInoNo> = cache.dirty.iter().copied().collect();
+        dirty.sort();
+        for ino in dirty {
+            self.iflush(&cache.inodes[&ino])?;
+            cache.dirty.remove(&ino);
+        }
+        Ok(())
+    }
+
+    /// 放入缓存，缓存已满时先写回脏 inode 再清空
+    fn icache_insert(&self, cache: &mut InodeCache, ip: Inode) -> FsResult<()> {
+        if cache.inodes.len() >= ICACHE_INODES {
+            self.flush_dirty(cache)?;
+            cache.inodes.clear();
+        }
+        cache.inodes.insert(ip.ino, ip);
+        Ok(())
+    }
+
+    /// 从缓存中丢弃 inode，不写回
+    fn icache_remove(&self, ino: AbsInoNo) {
+        let mut cache = self.icache.lock().unwrap();
+        cache.inodes.remove(&ino);
+        cache.dirty.remove(&ino);
+    }
+    /// 读取文件 [offset, offset + size) 范围内的数据，空洞读出为 0，读到 EOF 为止
+    pub fn read_file(&self, ip: &Inode, offset: u64, size: usize) -> FsResult<Vec<u8>> {
+        if offset >= ip.core.size {
+            return Ok(Vec::new());
+        }
+        let end = ip.core.size.min(offset + size as u64);
+        let blocksize = self.superblock.blocksize as u64;
+        let mut data = vec![0u8; (end - offset) as usize];
+        let mut pos = offset;
+        while pos < end {
+            let lblk = pos / blocksize;
+            let blk_off = pos % blocksize;
+            let len = (blocksize - blk_off).min(end - pos);
+            let dst = (pos - offset) as usize;
+            if let Some((fsbno, ExtentState::ExtNorm)) = ip.bmap(lblk) {
+                let dev_off = fsbno * blocksize + blk_off;
+                self.dev
+                    .read_all_at(dev_off as usize, &mut data[dst..dst + len as usize])
+                    .map_err(errno)?;
+            }
+            pos += len;
+        }
+        self.delalloc_read(ip.ino, offset, &mut data);
+        Ok(data)
+    }
+
+    /// 将文件 [offset, offset + len) 中已写入数据的部分清零，空洞和预分配的块本来就读出为 0
+    pub fn zero_range(&self, ip: &Inode, offset: u64, len: u64) -> FsResult<()> {
+        let blocksize = self.superblock.blocksize as u64;
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let blk_off = pos % blocksize;
+            let len = (blocksize - blk_off).min(end - pos);
+            if let Some((fsbno, ExtentState::ExtNorm)) = ip.bmap(pos / blocksize) {
+                let zero = vec![0u8; len as usize];
+                self.dev
+                    .write_all_at((fsbno * blocksize + blk_off) as usize, &zero)
+                    .map_err(errno)?;
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> MountPoint<'a> {
+    /// 在 parent 目录下分配并初始化一个新的 inode
+    pub fn ialloc(&self, parent: AbsInoNo, mode: u16, uid: u32, gid: u32) -> FsResult<Inode> {
+        let ino = self.dialloc(parent, mode)?;
+        let mut core = Dinode::new(ino, mode);
+        core.set_owner(uid, gid);
+        core.set_all_times(timestamp::now());
+        core.gen = rand::thread_rng().next_u32();
+        let mut ip = Inode {
+            ino,
+            core,
+            extents: Vec::new(),
+            broot: None,
+        };
+        if let Err(e) = self.write_inode(&mut ip) {
+            let _ = self.difree(ino);
+            return Err(e);
+        }
+        Ok(ip)
+    }
+
+    /// 释放 inode 及其占用的所有块
+    pub fn ifree(&self, ip: &mut Inode) -> FsResult<()> {
+        self.truncate(ip, 0)?;
+        self.icache_remove(ip.ino);
+        let offset = self.ino_to_offset(ip.ino).ok_or(ENOENT)?;
+        let zero = vec![0u8; self.superblock.inodesize as usize];
+        self.dev.write_all_at(offset, &zero).map_err(errno)?;
+        self.difree(ip.ino)
+    }
+
+    /// 为 [lblk, lblk + len) 中的空洞分配状态为 state 的块，返回新分配的逻辑范围。失败时回滚已分配的块
+    pub fn alloc_range(
+        &self,
+        ip: &mut Inode,
+        lblk: u64,
+        len: u64,
+        state: ExtentState,
+    ) -> FsResult<Vec<(u64, u64)>> {
+        let mut new_ranges = Vec::new();
+        if let Err(e) = self.alloc_range_inner(ip, lblk, len, state, &mut new_ranges) {
+            for (start, len) in new_ranges {
+                let _ = self.bunmap(ip, start, len);
+            }
+            return Err(e);
+        }
+        Ok(new_ranges)
+    }
+
+    fn alloc_range_inner(
+        &self,
+        ip: &mut Inode,
+        lblk: u64,
+        len: u64,
+        state: ExtentState,
+        new_ranges: &mut Vec<(u64, u64)>,
+    ) -> FsResult<()> {
+        let end = lblk + len;
+        let mut holes = Vec::new();
+        let mut cur = lblk;
+        while cur < end {
+            if ip.bmap(cur).is_some() {
+                cur += 1;
+                continue;
+            }
+            let (lo, hi) = ip.hole_around(cur);
+            let hole_end = hi.min(end);
+            // 有 extsize 提示时多分配的部分映射为 unwritten
+            let (astart, aend) = self.extsize_align(ip, cur, hole_end, lo, hi);
+            holes.push((cur, hole_end, astart, aend));
+            cur = aend;
+        }
+        // 一次预留所有空洞需要的块，空间不足时什么都不分配
+        let total = holes
+            .iter()
+            .map(|&(_, _, astart, aend)| aend - astart)
+            .sum();
+        self.with_reserved(total, |resv| {
+            for (cur, hole_end, astart, aend) in holes {
+                let mut pos = astart;
+                while pos < aend {
+                    // 尽量紧接着前一个块分配，使文件在物理上连续
+                    let hint = match pos.checked_sub(1).and_then(|prev| ip.bmap(prev)) {
+                        Some((fsbno, _)) => fsbno + 1,
+                        None => ip.ino >> self.superblock.inpblock_bits,
+                    };
+                    let args = AllocArgs {
+                        ino: ip.ino,
+                        hint: Some(hint),
+                        minlen: 1,
+                        maxlen: (aend - pos).min(MAX_BMBT_EXTLEN),
+                    };
+                    let (fsbno, got) = self.alloc_blocks(&args, resv)?;
+                    self.map_alloc(ip, pos, fsbno, got, cur..hole_end, state)?;
+                    new_ranges.push((pos, got));
+                    pos += got;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// 将 data 直接写入文件的 offset 处，必要时立即分配块并扩展文件大小
+    ///
+    /// 先写回延迟分配的数据，以免同一个块有两份
+    pub fn write_file(&self, ip: &mut Inode, offset: u64, data: &[u8]) -> FsResult<usize> {
+        self.flush_delalloc(ip)?;
+        self.write_direct(ip, offset, data)
+    }
+
+    /// 不经过延迟分配写入 xfs_file_dio_write
+    pub fn write_direct(&self, ip: &mut Inode, offset: u64, data: &[u8]) -> FsResult<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let blocksize = self.superblock.blocksize as u64;
+        let end = offset + data.len() as u64;
+        let first = offset / blocksize;
+        let last = (end - 1) / blocksize;
+        let new_ranges = self.alloc_range(ip, first, last - first + 1, ExtentState::ExtNorm)?;
+        let is_new = |lblk: u64| new_ranges.iter().any(|&(s, l)| s <= lblk && lblk < s + l);
+
+        let mut pos = offset;
+        while pos < end {
+            let lblk = pos / blocksize;
+            let blk_off = pos % blocksize;
+            let len = (blocksize - blk_off).min(end - pos);
+            let src = &data[(pos - offset) as usize..(pos - offset + len) as usize];
+            let (fsbno, state) = ip.bmap(lblk).ok_or(EIO)?;
+            if (is_new(lblk) || state == ExtentState::ExtUnwritten) && len < blocksize {
+                // 新分配的块和预分配的块中未写入的部分需要清零
+                let mut buf = vec![0u8; blocksize as usize];
+                buf[blk_off as usize..(blk_off + len) as usize].copy_from_slice(src);
+                self.dev.write_all_at((fsbno * blocksize) as usize, &buf)
+            } else {
+                self.dev
+                    .write_all_at((fsbno * blocksize + blk_off) as usize, src)
+            }
+            .map_err(errno)?;
+            pos += len;
+        }
+        // 数据写入之后，预分配的块才转换为正常状态
+        self.bmap_convert(ip, first, last - first + 1, ExtentState::ExtNorm)?;
+        if end > ip.core.size {
+            ip.core.size = end;
+        }
+        ip.touch();
+        self.write_inode(ip)?;
+        Ok(data.len())
+    }
+
+    /// 将文件截断或扩展到 size，释放 EOF 之后的块
+    pub fn truncate(&self, ip: &mut Inode, size: u64) -> FsResult<()> {
+        let blocksize = self.superblock.blocksize as u64;
+        // 缩小文件时，最后一个块中 EOF 之后的部分需要清零，以免之后扩展时读出旧数据
+        if size < ip.core.size && !size.is_multiple_of(blocksize) {
+            self.zero_range(ip, size, blocksize - size % blocksize)?;
+        }
+        self.delalloc_truncate(ip.ino, size);
+        let first_free = size.div_ceil(blocksize);
+        self.bunmap(ip, first_free, u64::MAX)?;
+        ip.core.size = size;
+        Ok(())
+    }
+}
+
+/// 整个 inode 的 CRC，计算时 crc 字段视为 0 xfs_dinode_calc_crc
+fn dinode_crc(buf: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(&buf[..DINODE_CRC_OFF]);
+    let crc = crc32c::crc32c_append(crc, &[0; 4]);
+    crc32c::crc32c_append(crc, &buf[DINODE_CRC_OFF + 4..])
+}
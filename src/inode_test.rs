@@ -0,0 +1,139 @@
+#[cfg(test)]
+use libc::{EIO, ENOENT, S_IFREG};
+
+#[cfg(test)]
+use crate::{
+    block_dev::BlockDevice,
+    btree_test::{mkfs_and_mount, remount, test_mkfs_option},
+    dstruct::{timestamp, Dinode, InodeFlag, DINODE_CORE_SIZE, DINODE_CRC_OFF},
+};
+
+#[test]
+fn test_ino_location() {
+    let mp = mkfs_and_mount("test_ino_location.bin", test_mkfs_option());
+    let ino = mp.make_ino(1, 100, 3);
+    assert_eq!(mp.ino_to_agno(ino), 1);
+    assert_eq!(mp.ino_to_agbno(ino), 100);
+    assert_eq!(mp.ino_to_slot(ino), 3);
+    assert_eq!(mp.ino_to_offset(ino), Some((8192 + 100) * 4096 + 3 * 512));
+    // 最后一个 AG 只有 4608 块
+    assert_eq!(mp.ino_to_offset(mp.make_ino(1, 4608, 0)), None);
+    assert_eq!(mp.ino_to_offset(mp.make_ino(2, 0, 0)), None);
+    assert_eq!(mp.ino_to_offset(0), None);
+
+    let rootino = mp.superblock.rootino;
+    let root = mp.iget(rootino).unwrap();
+    assert!(root.is_dir());
+    assert_eq!(
+        mp.make_ino(
+            mp.ino_to_agno(rootino),
+            mp.ino_to_agbno(rootino),
+            mp.ino_to_slot(rootino)
+        ),
+        rootino
+    );
+}
+
+#[test]
+fn test_iget() {
+    let path = "test_iget.bin";
+    let mp = mkfs_and_mount(path, test_mkfs_option());
+    let rootino = mp.superblock.rootino;
+    let ino = mp
+        .create(rootino, b"f", S_IFREG as u16 | 0o644, 0, 0)
+        .unwrap()
+        .ino;
+    mp.flush_inodes().unwrap();
+
+    // 修改只进入缓存，写回后设备上才能读到
+    let mut ip = mp.iget(ino).unwrap();
+    ip.core.size = 1234;
+    mp.write_inode(&mut ip).unwrap();
+    assert_eq!(mp.iget(ino).unwrap().core.size, 1234);
+    assert_eq!(mp.read_inode(ino).unwrap().core.size, 0);
+    mp.flush_inodes().unwrap();
+    assert_eq!(mp.read_inode(ino).unwrap().core.size, 1234);
+
+    // 损坏的 inode 无法通过校验，缓存中的副本不受影响
+    let offset = mp.ino_to_offset(ino).unwrap();
+    mp.dev
+        .write_all_at(offset + DINODE_CORE_SIZE + 100, &[1])
+        .unwrap();
+    assert_eq!(mp.read_inode(ino).err(), Some(EIO));
+    assert_eq!(mp.iget(ino).unwrap().core.size, 1234);
+    mp.dev
+        .write_all_at(offset + DINODE_CORE_SIZE + 100, &[0])
+        .unwrap();
+    mp.dev.write_all_at(offset, &[0, 0]).unwrap();
+    assert_eq!(mp.read_inode(ino).err(), Some(EIO));
+    // 不属于该位置的 inode 也视为损坏
+    let mut buf = vec![0u8; 512];
+    mp.dev
+        .read_all_at(mp.ino_to_offset(rootino).unwrap(), &mut buf)
+        .unwrap();
+    mp.dev.write_all_at(offset, &buf).unwrap();
+    assert_eq!(mp.read_inode(ino).err(), Some(EIO));
+
+    // 卸载时写回脏 inode
+    ip.core.size = 5678;
+    mp.write_inode(&mut ip).unwrap();
+    drop(mp);
+    let mp = remount(path);
+    let mut ip = mp.iget(ino).unwrap();
+    assert_eq!(ip.core.size, 5678);
+
+    // 释放后缓存和设备上都不再有这个 inode
+    mp.ifree(&mut ip).unwrap();
+    assert_eq!(mp.iget(ino).err(), Some(ENOENT));
+    drop(mp);
+    let mp = remount(path);
+    assert_eq!(mp.iget(ino).err(), Some(ENOENT));
+}
+
+#[test]
+fn test_dinode_codec() {
+    let mut core = Dinode::new(0x1234, S_IFREG as u16 | 0o644);
+    core.set_owner(1000, 100);
+    core.set_all_times(timestamp { sec: 7, nsec: 9 });
+    core.size = 1 << 40;
+    core.nblocks = 3;
+    core.crc = 0xdead_beef;
+    core.set_flag(InodeFlag::XfsDiflagNoatime, true);
+    core.set_flag(InodeFlag::XfsDiflagAppend, true);
+    core.set_flag(InodeFlag::XfsDiflagAppend, false);
+    assert!(core.has_flag(InodeFlag::XfsDiflagNoatime));
+    assert!(!core.has_flag(InodeFlag::XfsDiflagAppend));
+    core.set_perm(0o4755);
+    assert_eq!(core.file_type(), S_IFREG);
+    assert_eq!(core.perm(), 0o4755);
+
+    // 大端序，字段偏移与 xfs_dinode 相同
+    let mut buf = [0xffu8; 512];
+    core.encode(&mut buf);
+    assert_eq!(&buf[0..2], b"IN");
+    assert_eq!(&buf[8..16], &[0, 0, 0x03, 0xe8, 0, 0, 0, 100]);
+    assert_eq!(&buf[32..40], &[0, 0, 0, 7, 0, 0, 0, 9]);
+    assert_eq!(&buf[56..64], &[0, 0, 1, 0, 0, 0, 0, 0]);
+    assert_eq!(&buf[90..92], &[0, 1 << 6]);
+    assert_eq!(
+        &buf[DINODE_CRC_OFF..DINODE_CRC_OFF + 4],
+        &[0xde, 0xad, 0xbe, 0xef]
+    );
+    assert_eq!(&buf[152..160], &[0, 0, 0, 0, 0, 0, 0x12, 0x34]);
+    // 核心之后的 fork 区域不受影响
+    assert!(buf[DINODE_CORE_SIZE..].iter().all(|&b| b == 0xff));
+    assert_eq!(Dinode::decode(&buf), core);
+
+    core.inc_nlink();
+    assert_eq!(core.nlink, 2);
+    core.nlink = 0;
+    core.drop_nlink();
+    assert_eq!(core.nlink, 0);
+
+    // forkoff 以 8 字节为单位划分 data fork 和 attr fork
+    assert_eq!(core.data_fork_size(512), 512 - DINODE_CORE_SIZE);
+    assert_eq!(core.attr_fork(512), None);
+    core.forkoff = 15;
+    assert_eq!(core.data_fork_size(512), 120);
+    assert_eq!(core.attr_fork(512), Some((DINODE_CORE_SIZE + 120, 216)));
+}
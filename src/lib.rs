@@ -0,0 +1,28 @@
+pub mod block_dev;
+pub mod block_cache;
+mod block_cache_test;
+pub mod file_blk;
+mod file_blk_test;
+pub mod pound_fs;
+mod pound_fs_test;
+pub mod util;
+mod util_test;
+pub mod dstruct;
+pub mod mstruct;
+pub mod btree;
+mod btree_test;
+pub mod inode;
+mod inode_test;
+pub mod dir;
+mod dir_test;
+pub mod pound_fuse;
+pub mod alloc;
+mod alloc_test;
+pub mod ialloc;
+mod ialloc_test;
+pub mod bmap;
+mod bmap_test;
+pub mod bmap_util;
+mod bmap_util_test;
+pub mod delalloc;
+mod delalloc_test;
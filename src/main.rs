@@ -1,143 +1,30 @@
 use clap::{crate_version, Arg, Command};
-use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    Request,
-};
-use libc::ENOENT;
-use std::ffi::OsStr;
-use std::time::{Duration, UNIX_EPOCH};
+use fuser::MountOption;
 
-mod block_dev;
-mod file_blk;
-mod file_blk_test;
-mod pound_fs;
-mod pound_fs_test;
-mod util;
-mod dstruct;
-mod mstruct;
-mod btree;
-mod btree_test;
-
-const TTL: Duration = Duration::from_secs(1); // 1 second
-
-const HELLO_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
-};
-
-const HELLO_TXT_CONTENT: &str = "Hello World!\n";
-
-const HELLO_TXT_ATTR: FileAttr = FileAttr {
-    ino: 2,
-    size: 13,
-    blocks: 1,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::RegularFile,
-    perm: 0o644,
-    nlink: 1,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
-};
-
-struct HelloFS;
-
-impl Filesystem for HelloFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if parent == 1 && name.to_str() == Some("hello.txt") {
-            reply.entry(&TTL, &HELLO_TXT_ATTR, 0);
-        } else {
-            // ENOENT 表示 Error No Entry 无此条目
-            reply.error(ENOENT);
-        }
-    }
-    // 获取文件目录属性
-    // ino: 文件的 inode
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match ino {
-            // 1 号是根目录
-            1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
-            // 2 号是 hello.txt
-            2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
-            _ => reply.error(ENOENT),
-        }
-    }
-
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        _size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        if ino == 2 {
-            reply.data(&HELLO_TXT_CONTENT.as_bytes()[offset as usize..]);
-        } else {
-            reply.error(ENOENT);
-        }
-    }
-    // 列出目录
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        if ino != 1 {
-            reply.error(ENOENT);
-            return;
-        }
-
-        let entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-            (2, FileType::RegularFile, "hello.txt"),
-        ];
-
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                break;
-            }
-        }
-        reply.ok();
-    }
-}
+use pound_fs::{file_blk::FileBlockDevice, pound_fs::mount, pound_fuse::PoundFuse};
 
 fn main() {
-    let matches = Command::new("hello")
+    let matches = Command::new("poundfs")
         .version(crate_version!())
         .author("Zhang Zijing")
         .arg(
-            Arg::new("MOUNT_POINT")
+            Arg::new("DEVICE")
                 .required(true)
                 .index(1)
+                .help("PoundFS image or block device to mount"),
+        )
+        .arg(
+            Arg::new("MOUNT_POINT")
+                .required(true)
+                .index(2)
                 .help("Act as a client, and mount FUSE at given path"),
         )
+        .arg(
+            Arg::new("rw")
+                .short('w')
+                .long("rw")
+                .help("Mount read-write instead of read-only"),
+        )
         .arg(
             Arg::new("auto-unmount")
                 .short('u')
@@ -152,13 +39,34 @@ fn main() {
         )
         .get_matches();
     env_logger::init();
+    let device = matches.value_of("DEVICE").unwrap();
     let mountpoint = matches.value_of("MOUNT_POINT").unwrap();
-    let mut options = vec![MountOption::RO, MountOption::FSName("poundfs".to_string())];
+    let dev = match FileBlockDevice::new(device) {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("{}: {}", device, e);
+            std::process::exit(1);
+        }
+    };
+    let mp = match mount(Box::new(dev)) {
+        Ok(mp) => mp,
+        Err(e) => {
+            eprintln!("{}: {}", device, e);
+            std::process::exit(1);
+        }
+    };
+    let mut options = vec![MountOption::FSName("poundfs".to_string())];
+    if matches.is_present("rw") {
+        options.push(MountOption::RW);
+    } else {
+        options.push(MountOption::RO);
+    }
+    let fs = PoundFuse::new(mp);
     if matches.is_present("auto-unmount") {
         options.push(MountOption::AutoUnmount);
     }
     if matches.is_present("allow-root") {
         options.push(MountOption::AllowRoot);
     }
-    fuser::mount2(HelloFS, mountpoint, &options).unwrap();
+    fuser::mount2(fs, mountpoint, &options).unwrap();
 }
@@ -1,8 +1,31 @@
+use std::sync::Mutex;
+
 use crate::{
-    dstruct::{Agf, Agi},
+    btree::UndoLog,
+    dstruct::{Agf, Agfl, Agi},
     pound_fs::MountPoint,
 };
 
+// 每个 AG 在内存中的头部，挂载时从磁盘载入 xfs_perag
+pub struct PerAg {
+    pub agno: u32,
+    pub agf: Mutex<Agf>,
+    pub agi: Mutex<Agi>,
+    // 预留给 bno/cnt 树分裂使用的块，与 AGF 一起加锁
+    pub agfl: Mutex<Agfl>,
+}
+
+impl PerAg {
+    pub fn new(agno: u32, agf: Agf, agi: Agi, agfl: Agfl) -> Self {
+        PerAg {
+            agno,
+            agf: Mutex::new(agf),
+            agi: Mutex::new(agi),
+            agfl: Mutex::new(agfl),
+        }
+    }
+}
+
 pub struct AgCtx<'a> {
     pub mp: &'a MountPoint<'a>,
     pub ag_no: u32,
@@ -19,11 +42,25 @@ impl<'a> AgCtx<'a> {
 pub struct AgfCtx<'a> {
     pub mp: &'a MountPoint<'a>,
     pub agf: &'a mut Agf,
+    pub agfl: &'a mut Agfl,
+    pub undo: &'a UndoLog, // bno/cnt 树被修改的块，出错时撤销
+    pub allocated: u64,    // 分配出去的 extent 的块数，不含树分裂用掉的块
 }
 
 impl<'a> AgfCtx<'a> {
-    pub fn new(mp: &'a MountPoint<'a>, agf: &'a mut Agf) -> Self {
-        AgfCtx { mp, agf }
+    pub fn new(
+        mp: &'a MountPoint<'a>,
+        agf: &'a mut Agf,
+        agfl: &'a mut Agfl,
+        undo: &'a UndoLog,
+    ) -> Self {
+        AgfCtx {
+            mp,
+            agf,
+            agfl,
+            undo,
+            allocated: 0,
+        }
     }
 }
 
@@ -31,22 +68,32 @@ impl<'a> AgfCtx<'a> {
 pub struct AgiCtx<'a> {
     pub mp: &'a MountPoint<'a>,
     pub agi: &'a mut Agi,
+    pub undo: &'a UndoLog,            // inobt/finobt 被修改的块，出错时撤销
+    pub new_blocks: Vec<(u64, u64)>,  // 新分配的 chunk 和树的块 (fsbno, 块数)，出错时释放
+    pub dead_blocks: Vec<(u64, u64)>, // 不再使用的 chunk 和树的块，成功后才释放
 }
 
 impl<'a> AgiCtx<'a> {
-    pub fn new(mp: &'a MountPoint<'a>, agi: &'a mut Agi) -> Self {
-        AgiCtx { mp, agi }
+    pub fn new(mp: &'a MountPoint<'a>, agi: &'a mut Agi, undo: &'a UndoLog) -> Self {
+        AgiCtx {
+            mp,
+            agi,
+            undo,
+            new_blocks: Vec::new(),
+            dead_blocks: Vec::new(),
+        }
     }
 }
 
-// Ag Free list
+// Ag Free list，环形数组的位置保存在 AGF 中
 pub struct AgflCtx<'a> {
     pub mp: &'a MountPoint<'a>,
-    pub agfl: &'a mut Agf,
+    pub agf: &'a mut Agf,
+    pub agfl: &'a mut Agfl,
 }
 
 impl<'a> AgflCtx<'a> {
-    pub fn new(mp: &'a MountPoint<'a>, agfl: &'a mut Agf) -> Self {
-        AgflCtx { mp, agfl }
+    pub fn new(mp: &'a MountPoint<'a>, agf: &'a mut Agf, agfl: &'a mut Agfl) -> Self {
+        AgflCtx { mp, agf, agfl }
     }
 }
@@ -1,25 +1,532 @@
-use crate::{block_dev::BlockDevice, dstruct::{SuperBlock, Agf}, util::{human_readable_size, hex_str, ffs}, mstruct::{AgCtx, AgfCtx}};
+use std::{
+    fmt, io,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use libc::EIO;
+
+use crate::{block_cache::{BlockCache, DEFAULT_CACHE_BLOCKS}, block_dev::BlockDevice, btree::{AllocRec, BtreeBlock, BtreeRecord}, dstruct::{SuperBlock, UUID, InodeBtreeRecord, Agf, Agfl, Agi, SuperBlockMagicNum, SB_FEAT_SPARSE_INODES, AgfMagicNum, AgflMagicNum, AgiMagicNum, AgfBtBno, AgfBtCnt}, util::{human_readable_size, ffs, load_from_bytes, uuid}, mstruct::PerAg, inode::InodeCache, delalloc::DelallocCache};
+
+// 默认的 inode 大小
+pub const DEFAULT_INODESIZE: u16 = 512;
 
 pub struct MkfsOption {
-    pub size: usize,    // 总大小，单位为字节
-    pub blocksize: u32, // 逻辑块大小 通常是4096字节（4KB）
-    pub agblocks: u32,  // 每个 AG 的逻辑块数
+    pub size: usize,        // 总大小，单位为字节
+    pub blocksize: u32,     // 逻辑块大小 通常是4096字节（4KB）
+    pub agblocks: u32,      // 每个 AG 的逻辑块数
+    pub inodesize: u16,     // inode 大小 bytes
+    pub label: String,      // 文件系统名称，写入 fsname
+    pub uuid: Option<UUID>, // 为空时随机生成
+    pub logblocks: u32,     // 日志块数，0 表示没有日志
+    pub sparse_inodes: bool, // 启用稀疏 inode chunk
+}
+
+impl Default for MkfsOption {
+    fn default() -> Self {
+        MkfsOption {
+            size: 0,
+            blocksize: 4096,
+            agblocks: 0,
+            inodesize: DEFAULT_INODESIZE,
+            label: String::new(),
+            uuid: None,
+            logblocks: 0,
+            sparse_inodes: false,
+        }
+    }
+}
+
+// 文件系统操作的结果，错误为 errno
+pub type FsResult<T> = Result<T, libc::c_int>;
+
+/// 将设备的 I/O 错误转换为 errno，越界等没有 errno 的错误视为 EIO
+pub fn errno(e: io::Error) -> libc::c_int {
+    e.raw_os_error().unwrap_or(EIO)
 }
 
 // xfs_mount
 pub struct MountPoint<'a> {
-    pub dev: Box<dyn BlockDevice + 'a>,
-    superblock: SuperBlock,
+    pub dev: BlockCache<'a>,
+    pub superblock: SuperBlock,
+    pub perag: Vec<PerAg>,
+    // 超级块中的计数器在运行时单独维护，写回超级块时再同步
+    pub fdblocks: AtomicU64,
+    pub icount: AtomicU64,
+    pub ifree: AtomicU64,
+    // 新目录轮流放到各个 AG 中 m_agirotor
+    pub agrotor: AtomicU32,
+    pub icache: Mutex<InodeCache>,
+    pub delalloc: Mutex<DelallocCache>,
 }
 
 impl<'a> MountPoint<'a> {
     pub fn new(dev: Box<dyn BlockDevice + 'a>, superblock: SuperBlock) -> Self {
-        MountPoint { dev, superblock }
+        MountPoint {
+            dev: BlockCache::new(dev, DEFAULT_CACHE_BLOCKS),
+            fdblocks: AtomicU64::new(superblock.fdblocks),
+            icount: AtomicU64::new(superblock.icount),
+            ifree: AtomicU64::new(superblock.ifree),
+            superblock,
+            perag: Vec::new(),
+            agrotor: AtomicU32::new(0),
+            icache: Mutex::new(InodeCache::default()),
+            delalloc: Mutex::new(DelallocCache::default()),
+        }
+    }
+
+    /// 第 agno 个 AG 的实际块数，最后一个 AG 可能比 agblocks 小
+    pub fn ag_block_count(&self, agno: u32) -> u32 {
+        let sb = &self.superblock;
+        let start = agno as u64 * sb.agblocks as u64;
+        (sb.dblocks as u64 - start).min(sb.agblocks as u64) as u32
+    }
+
+    /// AG 内块号转为文件系统块号
+    pub fn agbno_to_fsbno(&self, agno: u32, agbno: u32) -> u64 {
+        agno as u64 * self.superblock.agblocks as u64 + agbno as u64
+    }
+
+    /// 文件系统块号转为 (agno, agbno)
+    pub fn fsbno_to_agbno(&self, fsbno: u64) -> (u32, u32) {
+        let agblocks = self.superblock.agblocks as u64;
+        ((fsbno / agblocks) as u32, (fsbno % agblocks) as u32)
+    }
+
+    /// 第 agno 个 AG 中第 sector 个扇区的字节偏移。0 为 SB，1 为 AGF，2 为 AGI，3 为 AGFL
+    pub fn ag_sector_offset(&self, agno: u32, sector: u32) -> usize {
+        let sb = &self.superblock;
+        agno as usize * sb.agblocks as usize * sb.blocksize as usize
+            + sector as usize * sb.sectsize as usize
+    }
+
+    /// 由各 AG 的头部汇总空闲块和 inode 计数器 xfs_initialize_perag_data
+    pub fn init_counters(&self) {
+        let (mut fdblocks, mut icount, mut ifree) = (0, 0, 0);
+        for pag in self.perag.iter() {
+            let agf = pag.agf.lock().unwrap();
+            fdblocks += agf.freeblks as u64 + agf.flcount as u64;
+            let agi = pag.agi.lock().unwrap();
+            icount += agi.count as u64;
+            ifree += agi.freecount as u64;
+        }
+        self.fdblocks.store(fdblocks, Ordering::Relaxed);
+        self.icount.store(icount, Ordering::Relaxed);
+        self.ifree.store(ifree, Ordering::Relaxed);
+    }
+
+    /// 带有最新计数器的超级块
+    pub fn current_superblock(&self) -> SuperBlock {
+        let mut sb = self.superblock.clone();
+        sb.fdblocks = self.fdblocks.load(Ordering::Relaxed);
+        sb.icount = self.icount.load(Ordering::Relaxed);
+        sb.ifree = self.ifree.load(Ordering::Relaxed);
+        sb
+    }
+
+    /// 将超级块写回第 0 块
+    pub fn write_superblock(&self) -> FsResult<()> {
+        let sb_encoded = bincode::serialize(&self.current_superblock()).map_err(|_| EIO)?;
+        self.dev.write_all_at(0, &sb_encoded).map_err(errno)
+    }
+
+    /// 将 AGF 写回所在 AG 的第 1 个扇区
+    pub fn write_agf(&self, agf: &Agf) -> FsResult<()> {
+        let agf_encoded = bincode::serialize(agf).map_err(|_| EIO)?;
+        self.dev
+            .write_all_at(self.ag_sector_offset(agf.seqno, 1), &agf_encoded)
+            .map_err(errno)
+    }
+
+    /// 将 AGI 写回所在 AG 的第 2 个扇区
+    pub fn write_agi(&self, agi: &Agi) -> FsResult<()> {
+        let agi_encoded = bincode::serialize(agi).map_err(|_| EIO)?;
+        self.dev
+            .write_all_at(self.ag_sector_offset(agi.seqno, 2), &agi_encoded)
+            .map_err(errno)
+    }
+
+    /// 将 AGFL 写回所在 AG 的第 3 个扇区
+    pub fn write_agfl(&self, agfl: &Agfl) -> FsResult<()> {
+        self.dev
+            .write_all_at(self.ag_sector_offset(agfl.seqno, 3), &agfl.encode())
+            .map_err(errno)
+    }
+
+    /// 写回延迟分配的数据、脏 inode 和超级块并将设备上的数据落盘，用于卸载
+    pub fn sync(&self) -> FsResult<()> {
+        self.flush_delalloc_all()?;
+        self.flush_inodes()?;
+        self.write_superblock()?;
+        self.dev.flush().map_err(errno)
+    }
+
+    /// 通知设备 [fsbno, fsbno + len) 不再使用。失败不影响文件系统的一致性，因此忽略错误
+    pub fn discard_blocks(&self, fsbno: u64, len: u64) {
+        let phy_per_block = (self.superblock.blocksize / self.superblock.sectsize as u32) as u64;
+        let start = (fsbno * phy_per_block) as usize;
+        let end = ((fsbno + len) * phy_per_block) as usize;
+        let _ = self.dev.discard(start..end);
+    }
+}
+
+impl<'a> Drop for MountPoint<'a> {
+    fn drop(&mut self) {
+        // 设备的缓存随后在 BlockCache 的 drop 中写回
+        if let Err(e) = self.flush_delalloc_all() {
+            eprintln!("failed to write back delayed allocations: errno {}", e);
+        }
+        if let Err(e) = self.flush_inodes() {
+            eprintln!("failed to write back inodes: errno {}", e);
+        }
+    }
+}
+
+/// 挂载失败的原因
+#[derive(Debug, PartialEq, Eq)]
+pub enum MountError {
+    Io(io::ErrorKind),  // 读取设备失败
+    BadSuperBlock,      // 超级块无法解析
+    BadMagic(u32),      // 魔数不匹配
+    BadBlockSize(u32),  // 块大小不是 2 的幂或超出范围
+    BadSectSize(u16),   // 扇区大小与设备不符
+    BadInodeSize(u16),  // inode 大小不合法
+    BadAgBlocks(u32),   // AG 太小，放不下头部
+    BadAgCount { agcount: u32, expected: u32 },
+    DeviceTooSmall { dblocks: u32, dev_blocks: u64 },
+    BadRootIno(u64),    // 根目录 inode 号超出范围
+    BadAgf(u32),        // 第 agno 个 AG 的 AGF 损坏
+    BadAgi(u32),        // 第 agno 个 AG 的 AGI 损坏
+    BadAgfl(u32),       // 第 agno 个 AG 的 AGFL 损坏，或与 AGF 不一致
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MountError::Io(kind) => write!(f, "failed to read from device: {}", kind),
+            MountError::BadSuperBlock => write!(f, "superblock is unreadable"),
+            MountError::BadMagic(magic) => write!(f, "bad superblock magic {:#010x}", magic),
+            MountError::BadBlockSize(size) => write!(f, "bad block size {}", size),
+            MountError::BadSectSize(size) => write!(f, "bad sector size {}", size),
+            MountError::BadInodeSize(size) => write!(f, "bad inode size {}", size),
+            MountError::BadAgBlocks(n) => write!(f, "AG of {} blocks is too small", n),
+            MountError::BadAgCount { agcount, expected } => {
+                write!(f, "agcount is {}, expected {}", agcount, expected)
+            }
+            MountError::DeviceTooSmall { dblocks, dev_blocks } => write!(
+                f,
+                "filesystem has {} blocks but device only has {}",
+                dblocks, dev_blocks
+            ),
+            MountError::BadRootIno(ino) => write!(f, "bad root inode {}", ino),
+            MountError::BadAgf(agno) => write!(f, "AGF of AG {} is corrupted", agno),
+            MountError::BadAgi(agno) => write!(f, "AGI of AG {} is corrupted", agno),
+            MountError::BadAgfl(agno) => write!(f, "AGFL of AG {} is corrupted", agno),
+        }
+    }
+}
+
+impl std::error::Error for MountError {}
+
+impl From<io::Error> for MountError {
+    fn from(e: io::Error) -> Self {
+        MountError::Io(e.kind())
+    }
+}
+
+/// 检查超级块中的几何信息是否自洽，且不超出设备大小
+fn check_superblock(sb: &SuperBlock, dev: &dyn BlockDevice) -> Result<(), MountError> {
+    if sb.magicnum != SuperBlockMagicNum {
+        return Err(MountError::BadMagic(sb.magicnum));
+    }
+    if !sb.blocksize.is_power_of_two()
+        || !(512..=65536).contains(&sb.blocksize)
+        || sb.blocksize_bits as u32 != sb.blocksize.trailing_zeros()
+    {
+        return Err(MountError::BadBlockSize(sb.blocksize));
+    }
+    if sb.sectsize != dev.get_phy_block_size() || sb.sectsize as u32 > sb.blocksize {
+        return Err(MountError::BadSectSize(sb.sectsize));
+    }
+    if !sb.inodesize.is_power_of_two()
+        || sb.inodesize as u32 > sb.blocksize
+        || sb.inodesize_bits as u32 != sb.inodesize.trailing_zeros()
+        || sb.inopblock as u32 != sb.blocksize / sb.inodesize as u32
+        || sb.inpblock_bits as u32 != sb.inopblock.trailing_zeros()
+    {
+        return Err(MountError::BadInodeSize(sb.inodesize));
+    }
+    // AG 头部和 B+树根节点之后至少还要有一个块
+    if sb.agblocks <= prealloc_blocks(sb.sectsize, sb.blocksize) {
+        return Err(MountError::BadAgBlocks(sb.agblocks));
+    }
+    let expected = sb.dblocks.div_ceil(sb.agblocks);
+    if sb.agcount == 0 || sb.agcount != expected {
+        return Err(MountError::BadAgCount {
+            agcount: sb.agcount,
+            expected,
+        });
+    }
+    let dev_blocks =
+        dev.num_blocks() as u64 * dev.get_phy_block_size() as u64 / sb.blocksize as u64;
+    if sb.dblocks as u64 > dev_blocks {
+        return Err(MountError::DeviceTooSmall {
+            dblocks: sb.dblocks,
+            dev_blocks,
+        });
     }
+    let rootblk = sb.rootino >> sb.inpblock_bits;
+    if rootblk == 0 || rootblk >= sb.dblocks as u64 {
+        return Err(MountError::BadRootIno(sb.rootino));
+    }
+    Ok(())
+}
+
+/// 读取已有的文件系统：校验超级块，并载入每个 AG 的 AGF 和 AGI
+/// refs: xfs_mountfs
+pub fn mount<'a>(dev: Box<dyn BlockDevice + 'a>) -> Result<MountPoint<'a>, MountError> {
+    // 主超级块位于第 0 块
+    let mut buf = vec![0u8; dev.get_phy_block_size() as usize];
+    dev.read_all_at(0, &mut buf)?;
+    let sb: SuperBlock = load_from_bytes(&buf).ok_or(MountError::BadSuperBlock)?;
+    check_superblock(&sb, dev.as_ref())?;
+
+    let mut mp = MountPoint::new(dev, sb);
+    for agno in 0..mp.superblock.agcount {
+        let length = mp.ag_block_count(agno);
+
+        mp.dev.read_all_at(mp.ag_sector_offset(agno, 1), &mut buf)?;
+        let agf: Agf = load_from_bytes(&buf).ok_or(MountError::BadAgf(agno))?;
+        if agf.magicnum != AgfMagicNum || agf.seqno != agno || agf.length != length {
+            return Err(MountError::BadAgf(agno));
+        }
+
+        mp.dev.read_all_at(mp.ag_sector_offset(agno, 2), &mut buf)?;
+        let agi: Agi = load_from_bytes(&buf).ok_or(MountError::BadAgi(agno))?;
+        if agi.magicnum != AgiMagicNum || agi.seqno != agno || agi.length != length {
+            return Err(MountError::BadAgi(agno));
+        }
+
+        mp.dev.read_all_at(mp.ag_sector_offset(agno, 3), &mut buf)?;
+        let agfl = Agfl::decode(&buf).ok_or(MountError::BadAgfl(agno))?;
+        let size = agfl.bno.len() as u32;
+        if agfl.magicnum != AgflMagicNum
+            || agfl.seqno != agno
+            || agf.flcount > size
+            || agf.flfirst >= size
+            || agf.fllast >= size
+        {
+            return Err(MountError::BadAgfl(agno));
+        }
+
+        mp.perag.push(PerAg::new(agno, agf, agi, agfl));
+    }
+    mp.init_counters();
+    Ok(mp)
+}
+
+/// 每个 AG 开头被头部（4 个扇区）和 4 个 B+树根节点占用的块数
+pub fn prealloc_blocks(sectsize: u16, blocksize: u32) -> u32 {
+    (4 * sectsize as u32).div_ceil(blocksize) + 4
+}
+
+/// mkfs 参数不合法的原因
+#[derive(Debug, PartialEq, Eq)]
+pub enum MkfsError {
+    BadBlockSize(u32),
+    BadInodeSize(u16),
+    BadAgBlocks(u32),                                  // 不是 2 的幂
+    AgTooSmall { agblocks: u32, min: u32 },            // 放不下 AG 头部
+    AgTooLarge { agblocks: u32, max: u32 },            // AG 内块号为 u32
+    TooLarge(usize),                                   // 块数超出 u32
+    BadAgCount { agcount: u64, min: u32, max: u32 },
+    LastAgTooSmall { blocks: u32, min: u32 },          // 最后一个 AG 放不下头部
+    BadLogSize { logblocks: u32, min: u32, max: u32 }, // 日志必须放在 AG 0 中
+    LabelTooLong(usize),
+    Io(io::ErrorKind), // 写入设备失败
+}
+
+impl fmt::Display for MkfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MkfsError::BadBlockSize(size) => write!(
+                f,
+                "block size {} must be a power of two between {} and {}",
+                size, MIN_BLOCKSIZE, MAX_BLOCKSIZE
+            ),
+            MkfsError::BadInodeSize(size) => write!(
+                f,
+                "inode size {} must be a power of two between {} and {}, and fit in a block",
+                size, MIN_INODESIZE, MAX_INODESIZE
+            ),
+            MkfsError::BadAgBlocks(n) => write!(f, "agblocks {} is not a power of two", n),
+            MkfsError::AgTooSmall { agblocks, min } => write!(
+                f,
+                "AG too small for headers: {} blocks, need at least {}",
+                agblocks, min
+            ),
+            MkfsError::AgTooLarge { agblocks, max } => {
+                write!(f, "AG of {} blocks is larger than {}", agblocks, max)
+            }
+            MkfsError::TooLarge(size) => write!(
+                f,
+                "size {} has more than {} blocks",
+                human_readable_size(*size),
+                u32::MAX
+            ),
+            MkfsError::BadAgCount { agcount, min, max } => write!(
+                f,
+                "{} AGs is out of range, must be between {} and {}",
+                agcount, min, max
+            ),
+            MkfsError::LastAgTooSmall { blocks, min } => write!(
+                f,
+                "last AG of {} blocks would be dropped, need at least {}",
+                blocks, min
+            ),
+            MkfsError::BadLogSize { logblocks, min, max } => write!(
+                f,
+                "log of {} blocks is out of range, must be 0 or between {} and {}",
+                logblocks, min, max
+            ),
+            MkfsError::LabelTooLong(len) => {
+                write!(f, "label of {} bytes is longer than {}", len, FSNAME_LEN)
+            }
+            MkfsError::Io(kind) => write!(f, "failed to write to device: {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for MkfsError {}
+
+impl From<io::Error> for MkfsError {
+    fn from(e: io::Error) -> Self {
+        MkfsError::Io(e.kind())
+    }
+}
+
+// mkfs 参数的范围
+pub const MIN_BLOCKSIZE: u32 = 512;
+pub const MAX_BLOCKSIZE: u32 = 65536;
+pub const MIN_INODESIZE: u16 = 256;
+pub const MAX_INODESIZE: u16 = 2048;
+pub const MIN_AG_BLOCKS: u32 = 64;
+pub const MAX_AG_BLOCKS: u32 = 1 << 31;
+pub const MIN_AGCOUNT: u32 = 1;
+pub const MAX_AGCOUNT: u32 = 1 << 16;
+pub const MIN_LOG_BLOCKS: u32 = 512;
+pub const FSNAME_LEN: usize = 16;
+
+/// mkfs 计算出的 AG 划分
+#[derive(Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub dblocks: u32,
+    pub agcount: u32,
+    pub last_agblocks: u32, // 最后一个 AG 的块数
+}
+
+/// 块大小必须是 2 的幂，在 [MIN_BLOCKSIZE, MAX_BLOCKSIZE] 之间且不小于扇区大小
+pub fn check_blocksize(blocksize: u32, sectsize: u16) -> Result<(), MkfsError> {
+    if !blocksize.is_power_of_two()
+        || !(MIN_BLOCKSIZE..=MAX_BLOCKSIZE).contains(&blocksize)
+        || blocksize < sectsize as u32
+    {
+        return Err(MkfsError::BadBlockSize(blocksize));
+    }
+    Ok(())
+}
+
+impl MkfsOption {
+    /// 检查参数并计算 AG 划分，sectsize 为设备的扇区大小
+    pub fn validate(&self, sectsize: u16) -> Result<Geometry, MkfsError> {
+        check_blocksize(self.blocksize, sectsize)?;
+        if !self.inodesize.is_power_of_two()
+            || !(MIN_INODESIZE..=MAX_INODESIZE).contains(&self.inodesize)
+            || self.inodesize as u32 > self.blocksize
+        {
+            return Err(MkfsError::BadInodeSize(self.inodesize));
+        }
+        if self.label.len() > FSNAME_LEN {
+            return Err(MkfsError::LabelTooLong(self.label.len()));
+        }
+        if !self.agblocks.is_power_of_two() {
+            return Err(MkfsError::BadAgBlocks(self.agblocks));
+        }
+        let min_agblocks = MIN_AG_BLOCKS.max(prealloc_blocks(sectsize, self.blocksize) + 1);
+        if self.agblocks < min_agblocks {
+            return Err(MkfsError::AgTooSmall {
+                agblocks: self.agblocks,
+                min: min_agblocks,
+            });
+        }
+        // AG 内 inode 号为 u32，其中低位是块内的 inode 序号
+        let inopblock = self.blocksize / self.inodesize as u32;
+        let max_agblocks = (MAX_AG_BLOCKS as u64).min((1 << 32) / inopblock as u64) as u32;
+        if self.agblocks > max_agblocks {
+            return Err(MkfsError::AgTooLarge {
+                agblocks: self.agblocks,
+                max: max_agblocks,
+            });
+        }
+        let dblocks = (self.size / self.blocksize as usize) as u64;
+        if dblocks > u32::MAX as u64 {
+            return Err(MkfsError::TooLarge(self.size));
+        }
+        let agcount = dblocks.div_ceil(self.agblocks as u64);
+        if agcount < MIN_AGCOUNT as u64 || agcount > MAX_AGCOUNT as u64 {
+            return Err(MkfsError::BadAgCount {
+                agcount,
+                min: MIN_AGCOUNT,
+                max: MAX_AGCOUNT,
+            });
+        }
+        let last_agblocks = (dblocks - (agcount - 1) * self.agblocks as u64) as u32;
+        if last_agblocks < min_agblocks {
+            return Err(MkfsError::LastAgTooSmall {
+                blocks: last_agblocks,
+                min: min_agblocks,
+            });
+        }
+        // 日志放在 AG 0 的根节点之后，AG 0 中还要留出存放根目录的空间
+        let ag0_blocks = (self.agblocks as u64).min(dblocks) as u32;
+        let max_log = ag0_blocks - min_agblocks;
+        if self.logblocks != 0 && !(MIN_LOG_BLOCKS..=max_log).contains(&self.logblocks) {
+            return Err(MkfsError::BadLogSize {
+                logblocks: self.logblocks,
+                min: MIN_LOG_BLOCKS,
+                max: max_log,
+            });
+        }
+        Ok(Geometry {
+            dblocks: dblocks as u32,
+            agcount: agcount as u32,
+            last_agblocks,
+        })
+    }
+}
+
+/// 输出 AG 的划分
+pub fn print_geometry(opt: &MkfsOption, geo: &Geometry) {
+    let ag_size = opt.blocksize as usize * opt.agblocks as usize;
+    let last_ag_size = opt.blocksize as usize * geo.last_agblocks as usize;
+    println!(
+        "size={}, (each)ag_size={}, (total)ag_count={}, last_ag_size={}\n",
+        human_readable_size(geo.dblocks as usize * opt.blocksize as usize),
+        human_readable_size(ag_size),
+        geo.agcount,
+        human_readable_size(last_ag_size)
+    );
 }
+
 /// 基于块设备创建文件系统（格式化）
 /// refs: xfs_readsb
-pub fn make_fs(dev: Box<dyn BlockDevice>, opt: MkfsOption) {
+pub fn make_fs(dev: Box<dyn BlockDevice>, opt: MkfsOption) -> Result<(), MkfsError> {
+    let geo = opt.validate(dev.get_phy_block_size())?;
+    // 先丢弃设备上的旧数据，镜像文件因此保持稀疏
+    dev.discard(0..dev.num_blocks())?;
     // init MountPoint
     let mut mp =MountPoint::new(
         dev,
@@ -29,74 +536,142 @@ pub fn make_fs(dev: Box<dyn BlockDevice>, opt: MkfsOption) {
     mp.superblock.blocksize_bits = ffs(opt.blocksize) - 1;
     mp.superblock.agblocks = opt.agblocks;
     mp.superblock.agblocks_bits = ffs(opt.agblocks) - 1;
-    mp.superblock.dblocks = (opt.size / opt.blocksize as usize) as u32;
+    mp.superblock.dblocks = geo.dblocks;
+    mp.superblock.logblocks = opt.logblocks;
+    mp.superblock.sectsize = mp.dev.get_phy_block_size();
+    mp.superblock.sectsize_bits = ffs(mp.superblock.sectsize as u32) - 1;
+    mp.superblock.inodesize = opt.inodesize;
+    mp.superblock.inodesize_bits = ffs(opt.inodesize as u32) - 1;
+    mp.superblock.inopblock = (opt.blocksize / opt.inodesize as u32) as u16;
+    mp.superblock.inpblock_bits = ffs(mp.superblock.inopblock as u32) - 1;
+    mp.superblock.uuid = opt.uuid.unwrap_or_else(uuid);
+    if opt.sparse_inodes {
+        // 完整的 chunk 按 chunk 大小对齐，稀疏分配的部分总能落在某个对齐的 chunk 中
+        mp.superblock.flags |= SB_FEAT_SPARSE_INODES;
+        mp.superblock.sb_inoalignmt = mp.ialloc_blocks();
+    }
+    let label = opt.label.as_bytes();
+    mp.superblock.fsname[..label.len()].copy_from_slice(label);
     // sector_size = xfs_getsize_buftarg(mp->m_ddev_targp);
     // #define xfs_getsize_buftarg(buftarg)	block_size((buftarg)->bt_bdev)
     // 因此推断 blocksize 就是扇区大小
 
     // 将设备划分为多个 AG
-
-    // - 每个 AG 的字节大小
-    let ag_size = opt.blocksize * opt.agblocks;
-    // - 总 AG 数
-    let ag_count = (opt.size as f64 / ag_size as f64).ceil() as usize;
-    // - 最后一个 AG 的实际大小
-    let last_ag_size = (opt.size as u32) % (ag_size as u32);
-
-    mp.superblock.agcount = ag_count as u32;
-    println!(
-        "size={}, (each)ag_size={}, (total)ag_count={}, last_ag_size={}\n",
-        human_readable_size(opt.size),
-        human_readable_size(ag_size as usize),
-        ag_count,
-        human_readable_size(last_ag_size as usize)
-    );
-
-    for ag_no in 0..(ag_count) {
-        let cur_ag_size = if (ag_no + 1) == ag_count {
-            last_ag_size
-        } else {
-            ag_size
-        };
-        init_ag(
+    mp.superblock.agcount = geo.agcount;
+    for ag_no in 0..geo.agcount {
+        let (agf, agi, agfl) = init_ag(
             &mp,
             &InitAgOption {
-                ag_size: cur_ag_size,
-                start_block: ag_no * opt.agblocks as usize,
-                ag_no: ag_no as u32,
+                ag_size: mp.ag_block_count(ag_no) as u64 * opt.blocksize as u64,
+                start_block: ag_no as usize * opt.agblocks as usize,
+                ag_no,
             },
-        );
+        )?;
+        mp.perag.push(PerAg::new(ag_no, agf, agi, agfl));
+    }
+    mp.init_counters();
+
+    // 根目录从 AG 的空闲空间中分配，之后把带有根 inode 号的超级块写到每个 AG
+    let root = mp
+        .make_root_dir()
+        .map_err(|e| MkfsError::Io(io::Error::from_raw_os_error(e).kind()))?;
+    mp.superblock.rootino = root.ino;
+    mp.flush_inodes()
+        .map_err(|e| MkfsError::Io(io::Error::from_raw_os_error(e).kind()))?;
+    mp.superblock = mp.current_superblock();
+    let sb_encoded = bincode::serialize(&mp.superblock).unwrap();
+    for ag_no in 0..geo.agcount {
+        mp.dev
+            .write_all_at(mp.ag_sector_offset(ag_no, 0), sb_encoded.as_slice())?;
     }
+    mp.dev.flush()?;
+    Ok(())
 }
 
 pub struct InitAgOption {
     pub ag_no: u32,
-    pub ag_size: u32,
+    pub ag_size: u64,
     pub start_block: usize, // 起始物理块
 }
-// xfs_ag_init_headers
-pub fn init_ag(mp: &MountPoint, opt: &InitAgOption) {
-    println!(
-        "init_ag: ag_no={}, ag_size={}, start_block={}",
-        opt.ag_no,        
-        human_readable_size(opt.ag_size as usize),
-        opt.start_block
-    );    
-    println!("will init ag at block {}", opt.start_block);
-    
-
+// xfs_ag_init_headers，返回写入的 AGF、AGI 和 AGFL
+pub fn init_ag(mp: &MountPoint, opt: &InitAgOption) -> io::Result<(Agf, Agi, Agfl)> {
     // SB - sec 0
     let sb_sector_off = opt.start_block * mp.superblock.blocksize as usize;
-    println!("write superblock to addr {}", hex_str(sb_sector_off));
     let sb_encoded = bincode::serialize(&mp.superblock).unwrap();
-    mp.dev.as_ref().write_all_at(sb_sector_off, sb_encoded.as_slice());
+    mp.dev.write_all_at(sb_sector_off, sb_encoded.as_slice())?;
     
+    let agno = opt.ag_no;
+    let length = mp.ag_block_count(agno);
+
+    // 除头部和根节点外全部空闲，AG 0 中紧跟在根节点之后的日志除外
+    let mut free_start = mp.ag_prealloc_blocks() as u32;
+    if agno == 0 {
+        free_start += mp.superblock.logblocks;
+    }
+    let bno_recs: Vec<AllocRec> = (free_start < length)
+        .then_some(AllocRec {
+            startblock: free_start,
+            blockcount: length - free_start,
+        })
+        .into_iter()
+        .collect();
+
     // AGF - sec 1
-    let agf_sector_off = (opt.start_block + 1) * mp.superblock.blocksize as usize;
-    println!("write agf to addr {}", hex_str(agf_sector_off));
-    // let mut agf = Agf::new();
-    // let agfCtx = AgfCtx::new(mp, &mut agf);
+    let agf_sector_off = mp.ag_sector_offset(agno, 1);
+    let mut agf = Agf::new(agno, length, mp.superblock.uuid);
+    agf.roots[AgfBtBno] = mp.bno_root_block();
+    agf.roots[AgfBtCnt] = mp.cnt_root_block();
+    agf.levels[AgfBtBno] = 1;
+    agf.levels[AgfBtCnt] = 1;
+    agf.freeblks = bno_recs.iter().map(|rec| rec.blockcount).sum();
+    agf.longest = bno_recs.iter().map(|rec| rec.blockcount).max().unwrap_or(0);
+    let agf_encoded = bincode::serialize(&agf).unwrap();
+    mp.dev.write_all_at(agf_sector_off, agf_encoded.as_slice())?;
 
+    // AGI - sec 2
+    let agi_sector_off = mp.ag_sector_offset(agno, 2);
+    let mut agi = Agi::new(agno, length, mp.superblock.uuid);
+    agi.root = mp.ino_root_block();
+    agi.level = 1;
+    agi.freeRoot = mp.fino_root_block();
+    agi.freeLevel = 1;
+    let agi_encoded = bincode::serialize(&agi).unwrap();
+    mp.dev.write_all_at(agi_sector_off, agi_encoded.as_slice())?;
 
+    // AGFL - sec 3，头部之后的槽位全部置为空
+    let agfl_sector_off = mp.ag_sector_offset(agno, 3);
+    let agfl = Agfl::new(agno, mp.superblock.sectsize as usize, mp.superblock.uuid);
+    mp.dev.write_all_at(agfl_sector_off, agfl.encode().as_slice())?;
 
+    // 空闲空间 B+树、inobt 和 finobt 的根节点，各占一个块
+    init_btree_root(mp, agno, mp.bno_root_block(), &bno_recs)?;
+    init_btree_root(mp, agno, mp.cnt_root_block(), &bno_recs)?;
+    init_btree_root::<InodeBtreeRecord>(mp, agno, mp.ino_root_block(), &[])?;
+    init_btree_root::<InodeBtreeRecord>(mp, agno, mp.fino_root_block(), &[])?;
+    Ok((agf, agi, agfl))
+}
+
+/// 写入只有一个叶子节点的 B+树根节点
+fn init_btree_root<T: BtreeRecord>(
+    mp: &MountPoint,
+    agno: u32,
+    agbno: u32,
+    recs: &[T],
+) -> io::Result<()> {
+    let blocksize = mp.superblock.blocksize as usize;
+    let fsbno = agno as u64 * mp.superblock.agblocks as u64 + agbno as u64;
+    let mut node = BtreeBlock::new(fsbno);
+    node.owner = agno;
+    node.uuid = mp.superblock.uuid;
+    node.numrecs = recs.len() as u16;
+    let mut buf = vec![0u8; blocksize];
+    let hdr = bincode::serialize(&node).unwrap();
+    buf[..hdr.len()].copy_from_slice(&hdr);
+    let mut off = size_of::<BtreeBlock>();
+    for rec in recs {
+        assert!(off + T::SIZE <= blocksize, "too many records for one btree block");
+        rec.encode(&mut buf[off..off + T::SIZE]);
+        off += T::SIZE;
+    }
+    mp.dev.write_all_at(fsbno as usize * blocksize, buf.as_slice())
 }
@@ -8,12 +8,135 @@ use crate::pound_fs::make_fs;
 
 fn test_make_fs(){
     let fsize =  1024 * 1024 * 50; // 50MB
-    let dev = FileBlockDevice::create("test_make_fs.bin", fsize);
+    let dev = FileBlockDevice::create("test_make_fs.bin", fsize).unwrap();
     let mkfs_ptions = MkfsOption{
         size: fsize,
-        agblocks: 10240,
+        agblocks: 8192,
         blocksize: 4096,
+        ..Default::default()
     };
-    make_fs(Box::new(dev), mkfs_ptions);
+    make_fs(Box::new(dev), mkfs_ptions).unwrap();
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+use crate::block_dev::BlockDevice;
+#[cfg(test)]
+use crate::pound_fs::{mount, MkfsError, MountError};
+#[cfg(test)]
+use crate::{
+    btree::{AllocRec, BtreeBlock, BtreeRecord},
+    dstruct::AgfBtBno,
+    util::load_from_bytes,
+};
+#[cfg(test)]
+use std::mem::size_of;
+
+#[test]
+fn test_mount() {
+    let path = "test_mount.bin";
+    let fsize = 1024 * 1024 * 50; // 50MB
+    make_fs(
+        Box::new(FileBlockDevice::create(path, fsize).unwrap()),
+        MkfsOption {
+            size: fsize,
+            agblocks: 8192,
+            blocksize: 4096,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let mp = mount(Box::new(FileBlockDevice::new(path).unwrap())).unwrap();
+    assert_eq!(mp.superblock.agcount, 2);
+    assert_eq!(mp.perag.len(), 2);
+    // 最后一个 AG 只有 12800 - 8192 块
+    assert_eq!(mp.perag[1].agf.lock().unwrap().length, 4608);
+    assert_eq!(mp.perag[1].agi.lock().unwrap().seqno, 1);
+    {
+        // AG 1 除头部和根节点外全部空闲
+        let agf = mp.perag[1].agf.lock().unwrap();
+        let prealloc = mp.ag_prealloc_blocks() as u32;
+        assert_eq!(agf.roots[AgfBtBno], mp.bno_root_block());
+        assert_eq!(agf.freeblks, 4608 - prealloc);
+        assert_eq!(agf.longest, 4608 - prealloc);
+
+        // bno 根节点中只有一条记录
+        let mut buf = vec![0u8; 4096];
+        let fsbno = 8192 + agf.roots[AgfBtBno] as usize;
+        mp.dev.read_all_at(fsbno * 4096, &mut buf).unwrap();
+        let node: BtreeBlock = load_from_bytes(&buf).unwrap();
+        assert_eq!(node.numrecs, 1);
+        // AG 头部和根节点都带有文件系统的 UUID
+        let uuid = mp.superblock.uuid;
+        assert_eq!(node.uuid, uuid);
+        assert_eq!(agf.uuid, uuid);
+        assert_eq!(mp.perag[1].agi.lock().unwrap().uuid, uuid);
+        assert_eq!(mp.perag[1].agfl.lock().unwrap().uuid, uuid);
+        let rec = AllocRec::decode(&buf[size_of::<BtreeBlock>()..]);
+        assert_eq!(rec.startblock, prealloc);
+        assert_eq!(rec.blockcount, 4608 - prealloc);
+    }
+    // AG 0 中根目录占用了空间
+    let agf = mp.perag[0].agf.lock().unwrap();
+    assert!(agf.freeblks < 8192 - mp.ag_prealloc_blocks() as u32);
+    assert_eq!(mp.perag[0].agi.lock().unwrap().root, mp.ino_root_block());
+    drop(agf);
+    drop(mp);
+
+    // 设备比超级块记录的小
+    std::fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_len(fsize as u64 / 2)
+        .unwrap();
+    assert!(matches!(
+        mount(Box::new(FileBlockDevice::new(path).unwrap())),
+        Err(MountError::DeviceTooSmall { .. })
+    ));
+
+    // 魔数被破坏
+    let dev = FileBlockDevice::new(path).unwrap();
+    dev.write_all_at(0, &[0u8; 4]).unwrap();
+    assert_eq!(mount(Box::new(dev)).err(), Some(MountError::BadMagic(0)));
+}
+
+#[test]
+fn test_mkfs_validate() {
+    let opt = |size: usize, agblocks: u32| MkfsOption {
+        size,
+        agblocks,
+        ..Default::default()
+    };
+    let geo = opt(50 << 20, 8192).validate(512).unwrap();
+    assert_eq!(geo.dblocks, 12800);
+    assert_eq!(geo.agcount, 2);
+    assert_eq!(geo.last_agblocks, 4608);
+
+    assert_eq!(
+        opt(50 << 20, 10240).validate(512),
+        Err(MkfsError::BadAgBlocks(10240))
+    );
+    assert!(matches!(
+        opt(50 << 20, 32).validate(512),
+        Err(MkfsError::AgTooSmall { agblocks: 32, .. })
+    ));
+    // 最后一个 AG 只有 16 块
+    assert!(matches!(
+        opt((3 * 4096 + 16) * 4096, 4096).validate(512),
+        Err(MkfsError::LastAgTooSmall { blocks: 16, .. })
+    ));
+    assert!(matches!(
+        opt(32 << 30, 64).validate(512),
+        Err(MkfsError::BadAgCount { agcount: 131072, .. })
+    ));
+    let mut bad_block = opt(50 << 20, 8192);
+    bad_block.blocksize = 3000;
+    assert_eq!(bad_block.validate(512), Err(MkfsError::BadBlockSize(3000)));
+    let mut bad_log = opt(50 << 20, 8192);
+    bad_log.logblocks = 100;
+    assert!(matches!(
+        bad_log.validate(512),
+        Err(MkfsError::BadLogSize { logblocks: 100, .. })
+    ));
+}
@@ -0,0 +1,443 @@
+//! 将 PoundFS 通过 FUSE 挂载
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyStatfs, ReplyWrite, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+use libc::{EINVAL, EISDIR, ENOENT, ENOTDIR, O_DIRECT};
+
+use crate::{
+    block_dev::BlockDevice,
+    dir::{DIR_FT_BLKDEV, DIR_FT_CHRDEV, DIR_FT_DIR, DIR_FT_FIFO, DIR_FT_SOCK, DIR_FT_SYMLINK},
+    dstruct::{timestamp, AbsInoNo},
+    inode::Inode,
+    pound_fs::{errno, FsResult, MountPoint},
+};
+
+const TTL: Duration = Duration::from_secs(1); // 1 second
+
+pub struct PoundFuse {
+    mp: MountPoint<'static>,
+}
+
+fn to_system_time(ts: &timestamp) -> SystemTime {
+    UNIX_EPOCH + Duration::new(ts.sec as u64, ts.nsec)
+}
+
+fn to_timestamp(t: TimeOrNow) -> timestamp {
+    match t {
+        TimeOrNow::SpecificTime(t) => t.into(),
+        TimeOrNow::Now => timestamp::now(),
+    }
+}
+
+fn mode_to_kind(mode: u16) -> FileType {
+    match mode as u32 & libc::S_IFMT {
+        libc::S_IFDIR => FileType::Directory,
+        libc::S_IFLNK => FileType::Symlink,
+        libc::S_IFCHR => FileType::CharDevice,
+        libc::S_IFBLK => FileType::BlockDevice,
+        libc::S_IFIFO => FileType::NamedPipe,
+        libc::S_IFSOCK => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+fn ftype_to_kind(ftype: u8) -> FileType {
+    match ftype {
+        DIR_FT_DIR => FileType::Directory,
+        DIR_FT_SYMLINK => FileType::Symlink,
+        DIR_FT_CHRDEV => FileType::CharDevice,
+        DIR_FT_BLKDEV => FileType::BlockDevice,
+        DIR_FT_FIFO => FileType::NamedPipe,
+        DIR_FT_SOCK => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+impl PoundFuse {
+    pub fn new(mp: MountPoint<'static>) -> Self {
+        PoundFuse { mp }
+    }
+
+    /// FUSE 的根目录固定为 1 号，需要与超级块中的 rootino 互相转换
+    fn to_ino(&self, fuse_ino: u64) -> AbsInoNo {
+        if fuse_ino == FUSE_ROOT_ID {
+            self.mp.superblock.rootino
+        } else {
+            fuse_ino
+        }
+    }
+
+    fn to_fuse_ino(&self, ino: AbsInoNo) -> u64 {
+        if ino == self.mp.superblock.rootino {
+            FUSE_ROOT_ID
+        } else {
+            ino
+        }
+    }
+
+    pub fn attr(&self, ip: &Inode) -> FileAttr {
+        let core = &ip.core;
+        FileAttr {
+            ino: self.to_fuse_ino(ip.ino),
+            size: core.size,
+            blocks: (core.nblocks + self.mp.delayed_blocks(ip.ino))
+                * (self.mp.superblock.blocksize as u64 / 512),
+            atime: to_system_time(&core.atime),
+            mtime: to_system_time(&core.mtime),
+            ctime: to_system_time(&core.ctime),
+            crtime: to_system_time(&core.crtime),
+            kind: mode_to_kind(core.mode),
+            perm: core.perm(),
+            nlink: core.nlink,
+            uid: core.uid,
+            gid: core.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: self.mp.superblock.blocksize,
+        }
+    }
+
+    fn create(&self, req: &Request, parent: u64, name: &OsStr, mode: u32) -> FsResult<Inode> {
+        self.mp.create(
+            self.to_ino(parent),
+            name.as_bytes(),
+            mode as u16,
+            req.uid(),
+            req.gid(),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn setattr(
+        &self,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> FsResult<FileAttr> {
+        let mut ip = self.mp.iget(self.to_ino(ino))?;
+        if let Some(mode) = mode {
+            ip.core.set_perm(mode as u16);
+        }
+        if let Some(uid) = uid {
+            ip.core.uid = uid;
+        }
+        if let Some(gid) = gid {
+            ip.core.gid = gid;
+        }
+        if let Some(size) = size {
+            if ip.is_dir() {
+                return Err(EISDIR);
+            }
+            self.mp.truncate(&mut ip, size)?;
+            ip.core.mtime = timestamp::now();
+        }
+        if let Some(atime) = atime {
+            ip.core.atime = to_timestamp(atime);
+        }
+        if let Some(mtime) = mtime {
+            ip.core.mtime = to_timestamp(mtime);
+        }
+        ip.core.ctime = timestamp::now();
+        self.mp.write_inode(&mut ip)?;
+        Ok(self.attr(&ip))
+    }
+}
+
+impl Filesystem for PoundFuse {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let dp = match self.mp.iget(self.to_ino(parent)) {
+            Ok(dp) => dp,
+            Err(e) => return reply.error(e),
+        };
+        if !dp.is_dir() {
+            return reply.error(ENOTDIR);
+        }
+        let ent = match self.mp.dir_lookup(&dp, name.as_bytes()) {
+            Ok(Some(ent)) => ent,
+            Ok(None) => return reply.error(ENOENT),
+            Err(e) => return reply.error(e),
+        };
+        match self.mp.iget(ent.ino) {
+            Ok(ip) => reply.entry(&TTL, &self.attr(&ip), ip.core.gen as u64),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.mp.iget(self.to_ino(ino)) {
+            Ok(ip) => reply.attr(&TTL, &self.attr(&ip)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let ip = match self.mp.iget(self.to_ino(ino)) {
+            Ok(ip) => ip,
+            Err(e) => return reply.error(e),
+        };
+        match self.mp.read_file(&ip, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let mut ip = match self.mp.iget(self.to_ino(ino)) {
+            Ok(ip) => ip,
+            Err(e) => return reply.error(e),
+        };
+        // 以 O_DIRECT 打开时立即分配块，否则延迟分配
+        let ret = if flags & O_DIRECT != 0 {
+            self.mp.write_file(&mut ip, offset as u64, data)
+        } else {
+            self.mp.buffered_write(&mut ip, offset as u64, data)
+        };
+        match ret {
+            Ok(written) => reply.written(written as u32),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        if offset < 0 || length <= 0 {
+            return reply.error(EINVAL);
+        }
+        let mut ip = match self.mp.iget(self.to_ino(ino)) {
+            Ok(ip) => ip,
+            Err(e) => return reply.error(e),
+        };
+        match self
+            .mp
+            .fallocate(&mut ip, mode, offset as u64, length as u64)
+        {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let mut ip = match self.mp.iget(self.to_ino(ino)) {
+            Ok(ip) => ip,
+            Err(e) => return reply.error(e),
+        };
+        match self.mp.release(&mut ip) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        match PoundFuse::setattr(self, ino, mode, uid, gid, size, atime, mtime) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let mode = libc::S_IFREG | (mode & !umask & 0o7777);
+        match PoundFuse::create(self, req, parent, name, mode) {
+            Ok(ip) => reply.created(&TTL, &self.attr(&ip), ip.core.gen as u64, 0, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let mode = libc::S_IFDIR | (mode & !umask & 0o7777);
+        match PoundFuse::create(self, req, parent, name, mode) {
+            Ok(ip) => reply.entry(&TTL, &self.attr(&ip), ip.core.gen as u64),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.mp.remove(self.to_ino(parent), name.as_bytes(), false) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.mp.remove(self.to_ino(parent), name.as_bytes(), true) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return reply.error(EINVAL);
+        }
+        match self.mp.rename(
+            self.to_ino(parent),
+            name.as_bytes(),
+            self.to_ino(newparent),
+            newname.as_bytes(),
+            flags & libc::RENAME_NOREPLACE != 0,
+        ) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        // 延迟分配的数据先分配块写入设备缓存，inode 需要先从 inode 缓存写回
+        let ret = self
+            .mp
+            .flush_delalloc_all()
+            .and_then(|()| self.mp.flush_inodes())
+            .and_then(|()| self.mp.dev.flush().map_err(errno));
+        match ret {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let sb = self.mp.current_superblock();
+        // 空闲块都可以用来存放 inode
+        let ifree = sb.ifree + sb.fdblocks * sb.inopblock as u64;
+        reply.statfs(
+            sb.dblocks as u64,
+            sb.fdblocks,
+            sb.fdblocks,
+            sb.icount - sb.ifree + ifree,
+            ifree,
+            sb.blocksize,
+            u8::MAX as u32,
+            sb.blocksize,
+        );
+    }
+
+    fn destroy(&mut self) {
+        if let Err(e) = self.mp.sync() {
+            eprintln!("failed to sync filesystem: errno {}", e);
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let dp = match self.mp.iget(self.to_ino(ino)) {
+            Ok(dp) => dp,
+            Err(e) => return reply.error(e),
+        };
+        if !dp.is_dir() {
+            return reply.error(ENOTDIR);
+        }
+        let entries = match self.mp.read_dir(&dp, offset as u64) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e),
+        };
+        for (next, ent) in entries {
+            let name = OsStr::from_bytes(&ent.name);
+            // 返回 true 表示缓冲区已满
+            if reply.add(
+                self.to_fuse_ino(ent.ino),
+                next as i64,
+                ftype_to_kind(ent.ftype),
+                name,
+            ) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
@@ -71,5 +71,46 @@ pub fn load_from_bytes<T>(bytes: &[u8]) -> Option<T>
 where
     T: for<'de> serde::Deserialize<'de>,
 {    
-    Some(bincode::deserialize(bytes).ok()?)
+    bincode::deserialize(bytes).ok()
+}
+
+/// 解析带单位的大小，如 `4096`、`64K`、`50M`、`1G`，单位为 1024 的幂
+pub fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let (num, shift) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[..s.len() - 1], 10),
+        'M' => (&s[..s.len() - 1], 20),
+        'G' => (&s[..s.len() - 1], 30),
+        'T' => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let num: usize = num.parse().ok()?;
+    num.checked_mul(1usize.checked_shl(shift)?)
+}
+
+/// 解析 UUID 字符串，忽略其中的 `-`
+pub fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let hex: Vec<u8> = s.bytes().filter(|&c| c != b'-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut uuid = [0u8; 16];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = std::str::from_utf8(pair).ok()?;
+        uuid[i] = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+/// 按 8-4-4-4-12 的格式输出 UUID
+pub fn uuid_str(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
 }
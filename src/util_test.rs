@@ -0,0 +1,23 @@
+#[cfg(test)]
+use crate::util::{parse_size, parse_uuid, uuid_str};
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("64k"), Some(64 * 1024));
+    assert_eq!(parse_size("50M"), Some(50 * 1024 * 1024));
+    assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+    assert_eq!(parse_size("M"), None);
+    assert_eq!(parse_size("12X"), None);
+}
+
+#[test]
+fn test_parse_uuid() {
+    let s = "0123abcd-4567-89ef-0123-456789abcdef";
+    let uuid = parse_uuid(s).unwrap();
+    assert_eq!(uuid[0], 0x01);
+    assert_eq!(uuid[15], 0xef);
+    assert_eq!(uuid_str(&uuid), s);
+    assert!(parse_uuid("0123").is_none());
+    assert!(parse_uuid("0123abcd-4567-89ef-0123-456789abcdeg").is_none());
+}